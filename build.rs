@@ -2,6 +2,20 @@
 extern crate winres;
 
 fn main() {
+    // The key is only compiled in when asked for with --features embedded-key
+    if std::env::var_os("CARGO_FEATURE_EMBEDDED_KEY").is_some() {
+        embed_api_key();
+    }
+
+    #[cfg(windows)]
+    {
+        let mut res = winres::WindowsResource::new();
+        res.set_icon("heart_inlineBG.ico");
+        res.compile().unwrap();
+    }
+}
+
+fn embed_api_key() {
     // Load .env file at build time
     dotenv::dotenv().ok();
 
@@ -28,11 +42,4 @@ fn main() {
         println!("cargo:warning=GEMINI_API_KEY not found in .env file. Set it before building.");
         println!("cargo:rustc-env=GEMINI_API_KEY=");
     }
-
-    #[cfg(windows)]
-    {
-        let mut res = winres::WindowsResource::new();
-        res.set_icon("heart_inlineBG.ico");
-        res.compile().unwrap();
-    }
 }
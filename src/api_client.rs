@@ -1,13 +1,425 @@
-use crate::ui_components::ChatMessage;
+use crate::conversation::ChatMessage;
+use crate::diagnostics::{self, Recorder};
+use crate::documents::{ContextCache, SharedContext};
+use crate::img_utils::GeneratedImage;
+use crate::storage;
 use base64::{engine::general_purpose, Engine as _};
 use egui::ColorImage;
 use image::{DynamicImage, ImageBuffer, ImageFormat, RgbaImage};
-use reqwest::Client;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
 
+pub const API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta";
+const MODELS_CACHE_FILE: &str = "models_cache.json";
+/// Model selected on startup and used by the command line when none is given
+pub const DEFAULT_MODEL: &str = "gemini-3.1-pro-preview";
+
+/// How much the model is allowed to think before answering
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ThinkingControl {
+    /// Leave `thinkingConfig` unset and let the model decide
+    ModelDefault,
+    /// Token budget for 2.5 models: -1 is dynamic, 0 disables thinking where allowed
+    Budget(i64),
+    /// Thinking level for 3.x models: "minimal", "low", "medium" or "high"
+    Level(String),
+}
+
+pub const THINKING_LEVELS: [&str; 4] = ["minimal", "low", "medium", "high"];
+
+/// Harm categories accepted in `safetySettings`, with the label shown in the UI
+pub const HARM_CATEGORIES: [(&str, &str); 5] = [
+    ("HARM_CATEGORY_HARASSMENT", "Harassment"),
+    ("HARM_CATEGORY_HATE_SPEECH", "Hate speech"),
+    ("HARM_CATEGORY_SEXUALLY_EXPLICIT", "Sexually explicit"),
+    ("HARM_CATEGORY_DANGEROUS_CONTENT", "Dangerous content"),
+    ("HARM_CATEGORY_CIVIC_INTEGRITY", "Civic integrity"),
+];
+
+pub const DEFAULT_THRESHOLD: &str = "HARM_BLOCK_THRESHOLD_UNSPECIFIED";
+
+/// Block thresholds accepted in `safetySettings`, with the label shown in the UI
+pub const BLOCK_THRESHOLDS: [(&str, &str); 6] = [
+    (DEFAULT_THRESHOLD, "Model default"),
+    ("BLOCK_LOW_AND_ABOVE", "Block low and above"),
+    ("BLOCK_MEDIUM_AND_ABOVE", "Block medium and above"),
+    ("BLOCK_ONLY_HIGH", "Block only high"),
+    ("BLOCK_NONE", "Block none"),
+    ("OFF", "Off"),
+];
+
+/// Per-session request options: `generationConfig` plus `safetySettings`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GenerationSettings {
+    pub thinking: ThinkingControl,
+    pub include_thoughts: bool,
+    /// Harm category -> block threshold; categories left out use the model default
+    #[serde(default)]
+    pub safety_thresholds: BTreeMap<String, String>,
+    /// Number of alternative answers to request (`candidateCount`)
+    #[serde(default = "default_candidate_count")]
+    pub candidate_count: u32,
+    /// Set for image-output models, which also need `responseModalities` and no tools
+    #[serde(skip)]
+    pub image_output: bool,
+}
+
+pub const MAX_CANDIDATES: u32 = 4;
+
+fn default_candidate_count() -> u32 {
+    1
+}
+
+impl Default for GenerationSettings {
+    fn default() -> Self {
+        Self {
+            thinking: ThinkingControl::ModelDefault,
+            include_thoughts: true,
+            safety_thresholds: BTreeMap::new(),
+            candidate_count: default_candidate_count(),
+            image_output: false,
+        }
+    }
+}
+
+impl GenerationSettings {
+    /// Drops the thinking options for models that reject `thinkingConfig`
+    pub fn for_model(&self, model: Option<&ModelInfo>) -> Self {
+        let mut settings = self.clone();
+        if model.is_some_and(|m| !m.thinking) {
+            settings.thinking = ThinkingControl::ModelDefault;
+            settings.include_thoughts = false;
+        }
+        settings.image_output = model.is_some_and(|m| m.outputs_images());
+        settings
+    }
+
+    fn generation_config(&self) -> Value {
+        let mut config = json!({});
+        if self.candidate_count > 1 {
+            config["candidateCount"] = json!(self.candidate_count);
+        }
+        if self.image_output {
+            config["responseModalities"] = json!(["TEXT", "IMAGE"]);
+        }
+
+        if self.thinking == ThinkingControl::ModelDefault && !self.include_thoughts {
+            return config;
+        }
+
+        let mut thinking_config = json!({ "includeThoughts": self.include_thoughts });
+        match &self.thinking {
+            ThinkingControl::ModelDefault => {}
+            ThinkingControl::Budget(budget) => {
+                thinking_config["thinkingBudget"] = json!(budget);
+            }
+            ThinkingControl::Level(level) => {
+                thinking_config["thinkingLevel"] = json!(level);
+            }
+        }
+        config["thinkingConfig"] = thinking_config;
+
+        config
+    }
+
+    fn safety_settings(&self) -> Vec<Value> {
+        self.safety_thresholds
+            .iter()
+            .filter(|(_, threshold)| threshold.as_str() != DEFAULT_THRESHOLD)
+            .map(|(category, threshold)| json!({ "category": category, "threshold": threshold }))
+            .collect()
+    }
+}
+
+/// Answer text and thought summaries of a single model turn
+#[derive(Clone, Debug, Default)]
+pub struct ModelResponse {
+    pub text: String,
+    pub thoughts: Option<String>,
+    /// Short reason when the prompt or the answer was blocked; `text` then holds the explanation
+    pub blocked: Option<String>,
+    /// `finishReason` of the candidate, e.g. "STOP" or "MAX_TOKENS"
+    pub finish_reason: Option<String>,
+    /// Images returned as `inlineData` parts by image-output models
+    pub images: Vec<GeneratedImage>,
+    /// Token counts of the whole request, shared by all of its candidates
+    pub usage: Option<TokenUsage>,
+    /// Milliseconds from sending the request until the answer was complete
+    pub latency_ms: Option<u64>,
+}
+
+/// Token counts reported with a response (`usageMetadata`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub prompt: u64,
+    /// Answer tokens of all candidates, without thoughts
+    pub output: u64,
+    pub thoughts: u64,
+    pub total: u64,
+}
+
+impl TokenUsage {
+    fn from_metadata(metadata: &Value) -> Option<Self> {
+        let count = |key: &str| metadata.get(key).and_then(|v| v.as_u64()).unwrap_or(0);
+        metadata.is_object().then(|| Self {
+            prompt: count("promptTokenCount"),
+            output: count("candidatesTokenCount"),
+            thoughts: count("thoughtsTokenCount"),
+            total: count("totalTokenCount"),
+        })
+    }
+}
+
+/// A model returned by the `models` endpoint that supports `generateContent`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub name: String,
+    pub display_name: String,
+    pub input_token_limit: u64,
+    pub output_token_limit: u64,
+    #[serde(default = "default_true")]
+    pub thinking: bool,
+    /// Id of the provider serving the model; models cached before providers existed are Gemini's
+    #[serde(default = "default_provider")]
+    pub provider: String,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_provider() -> String {
+    GEMINI_PROVIDER.to_string()
+}
+
+pub const GEMINI_PROVIDER: &str = "gemini";
+
+impl ModelInfo {
+    /// Label shown in the model picker, e.g. "Gemini 2.5 Flash (1M in / 65K out)"
+    pub fn label(&self) -> String {
+        // OpenAI-compatible servers don't report token limits
+        if self.input_token_limit == 0 {
+            return self.display_name.clone();
+        }
+        format!(
+            "{}{} ({} in / {} out)",
+            self.display_name,
+            if self.outputs_images() { " 🖼" } else { "" },
+            format_token_count(self.input_token_limit),
+            format_token_count(self.output_token_limit)
+        )
+    }
+
+    /// Image-generation variants such as `gemini-2.5-flash-image` answer with image parts
+    pub fn outputs_images(&self) -> bool {
+        self.provider == GEMINI_PROVIDER && self.name.contains("-image")
+    }
+}
+
+fn format_token_count(tokens: u64) -> String {
+    if tokens >= 1_000_000 {
+        format!("{:.1}M", tokens as f64 / 1_000_000.0)
+    } else if tokens >= 1_000 {
+        format!("{}K", tokens / 1_000)
+    } else {
+        tokens.to_string()
+    }
+}
+
+/// Models offered when neither the cache nor the `models` endpoint is available
+pub fn fallback_models() -> Vec<ModelInfo> {
+    [
+        (DEFAULT_MODEL, "Gemini 3.1 Pro Preview"),
+        ("gemini-2.5-pro", "Gemini 2.5 Pro"),
+        ("gemini-2.5-flash", "Gemini 2.5 Flash"),
+        ("gemini-2.5-flash-lite", "Gemini 2.5 Flash-Lite"),
+    ]
+    .iter()
+    .map(|(name, display_name)| ModelInfo {
+        name: name.to_string(),
+        display_name: display_name.to_string(),
+        input_token_limit: 1_048_576,
+        output_token_limit: 65_536,
+        thinking: true,
+        provider: default_provider(),
+    })
+    .collect()
+}
+
+pub fn load_cached_models() -> Option<Vec<ModelInfo>> {
+    storage::read_json::<Vec<ModelInfo>>(MODELS_CACHE_FILE)
+        .ok()
+        .flatten()
+        .filter(|models| !models.is_empty())
+}
+
+pub fn save_cached_models(models: &[ModelInfo]) -> Result<(), String> {
+    storage::write_json(MODELS_CACHE_FILE, &models)
+}
+
+/// Base URL and key configured for the Gemini provider
+struct GeminiEndpoint {
+    base_url: String,
+    api_key: String,
+}
+
+/// Sent instead of a `key` query parameter, so the key never appears in a URL
+const API_KEY_HEADER: &str = "x-goog-api-key";
+
+static GEMINI_ENDPOINT: RwLock<Option<GeminiEndpoint>> = RwLock::new(None);
+
+/// Sends Gemini requests to `base_url` with `api_key`; an empty key falls back as in `api_key`
+pub fn set_gemini_endpoint(base_url: &str, api_key: &str) {
+    diagnostics::register_secret(api_key);
+    if let Ok(mut endpoint) = GEMINI_ENDPOINT.write() {
+        *endpoint = Some(GeminiEndpoint {
+            base_url: base_url.trim().trim_end_matches('/').to_string(),
+            api_key: api_key.trim().to_string(),
+        });
+    }
+}
+
+fn api_base() -> String {
+    GEMINI_ENDPOINT
+        .read()
+        .ok()
+        .and_then(|endpoint| endpoint.as_ref().map(|e| e.base_url.clone()))
+        .filter(|base| !base.is_empty())
+        .unwrap_or_else(|| API_BASE.to_string())
+}
+
+/// The key configured for the Gemini provider, else the GEMINI_API_KEY environment variable,
+/// else the key embedded from .env when built with `--features embedded-key`
+fn api_key() -> Result<String, Box<dyn std::error::Error>> {
+    let configured = GEMINI_ENDPOINT
+        .read()
+        .ok()
+        .and_then(|endpoint| endpoint.as_ref().map(|e| e.api_key.clone()))
+        .unwrap_or_default();
+    if !configured.is_empty() {
+        return Ok(configured);
+    }
+
+    let from_env = std::env::var("GEMINI_API_KEY").unwrap_or_default();
+    #[cfg(feature = "embedded-key")]
+    let from_env = if from_env.trim().is_empty() {
+        env!("GEMINI_API_KEY").to_string()
+    } else {
+        from_env
+    };
+    let api_key = from_env.trim();
+
+    // Debug check for common issues
+    if api_key.is_empty() {
+        return Err(
+            "No Gemini API key. Add one in Settings → Providers... or set GEMINI_API_KEY.".into(),
+        );
+    }
+    if api_key.contains("your-actual-api-key-here") || api_key.contains("placeholder") {
+        return Err("GEMINI_API_KEY is still a placeholder. Set a real key.".into());
+    }
+
+    diagnostics::register_secret(api_key);
+    Ok(api_key.to_string())
+}
+
+fn parse_model_info(model: &Value) -> Option<ModelInfo> {
+    let supports_generate = model
+        .get("supportedGenerationMethods")
+        .and_then(|v| v.as_array())
+        .is_some_and(|methods| methods.iter().any(|m| m == "generateContent"));
+    if !supports_generate {
+        return None;
+    }
+
+    let name = model.get("name")?.as_str()?;
+    let name = name.strip_prefix("models/").unwrap_or(name).to_string();
+    let display_name = model
+        .get("displayName")
+        .and_then(|v| v.as_str())
+        .unwrap_or(&name)
+        .to_string();
+
+    Some(ModelInfo {
+        input_token_limit: model
+            .get("inputTokenLimit")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0),
+        output_token_limit: model
+            .get("outputTokenLimit")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0),
+        thinking: model
+            .get("thinking")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+        name,
+        display_name,
+        provider: default_provider(),
+    })
+}
+
+/// Fetches every model available to the API key that supports `generateContent`
+pub async fn list_models() -> Result<Vec<ModelInfo>, Box<dyn std::error::Error>> {
+    let api_key = api_key()?;
+    list_models_at(&api_base(), &api_key).await
+}
+
+/// `list_models` against another endpoint or with another key, e.g. to check a new key
+pub async fn list_models_at(
+    base_url: &str,
+    api_key: &str,
+) -> Result<Vec<ModelInfo>, Box<dyn std::error::Error>> {
+    let base_url = match base_url.trim().trim_end_matches('/') {
+        "" => API_BASE,
+        base_url => base_url,
+    };
+    let client = Client::new();
+
+    let mut models = Vec::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        // Page tokens may contain '+', '/' and '=', so they must be encoded
+        let mut url = Url::parse(&format!("{}/models", base_url))?;
+        url.query_pairs_mut().append_pair("pageSize", "1000");
+        if let Some(ref token) = page_token {
+            url.query_pairs_mut().append_pair("pageToken", token);
+        }
+
+        let request = client.get(url).header(API_KEY_HEADER, api_key);
+        let (res, recorder) = diagnostics::send(request, None).await?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let error_text = recorder
+                .text(res)
+                .await
+                .unwrap_or_else(|_| "Failed to read error response".to_string());
+            return Err(format!("HTTP Error {}: {}", status, error_text).into());
+        }
+
+        let res_json: Value = serde_json::from_str(&recorder.text(res).await?)?;
+
+        if let Some(list) = res_json.get("models").and_then(|v| v.as_array()) {
+            models.extend(list.iter().filter_map(parse_model_info));
+        }
+
+        match res_json.get("nextPageToken").and_then(|v| v.as_str()) {
+            Some(token) if !token.is_empty() => page_token = Some(token.to_string()),
+            _ => break,
+        }
+    }
+
+    Ok(models)
+}
 
 fn rgba_to_png(
     rgba_data: &[u8],
@@ -26,7 +438,7 @@ fn rgba_to_png(
 }
 
 /// Converts ColorImage to base64-encoded PNG data for API submission
-fn encode_image_to_base64(img: &ColorImage) -> Result<String, Box<dyn std::error::Error>> {
+pub fn encode_image_to_base64(img: &ColorImage) -> Result<String, Box<dyn std::error::Error>> {
     let [width, height] = img.size;
 
     let rgba_bytes: Vec<u8> = img
@@ -39,38 +451,215 @@ fn encode_image_to_base64(img: &ColorImage) -> Result<String, Box<dyn std::error
     Ok(general_purpose::STANDARD.encode(&png_bytes))
 }
 
-/// Extracts text response from Gemini API JSON response
-fn extract_response_text(res_json: &Value) -> &str {
-    res_json
-        .pointer("/candidates/0/content/parts/0/text")
+fn harm_category_label(category: &str) -> &str {
+    HARM_CATEGORIES
+        .iter()
+        .find(|(name, _)| *name == category)
+        .map(|(_, label)| *label)
+        .unwrap_or(category)
+}
+
+fn format_safety_ratings(ratings: Option<&Value>) -> String {
+    let ratings: Vec<String> = ratings
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .map(|rating| {
+            let category = rating
+                .get("category")
+                .and_then(|v| v.as_str())
+                .unwrap_or("Unknown category");
+            let probability = rating
+                .get("probability")
+                .and_then(|v| v.as_str())
+                .unwrap_or("UNKNOWN");
+            let blocked = rating.get("blocked").and_then(|v| v.as_bool()) == Some(true);
+            format!(
+                "- {}: {}{}",
+                harm_category_label(category),
+                probability,
+                if blocked { " **(blocked)**" } else { "" }
+            )
+        })
+        .collect();
+
+    if ratings.is_empty() {
+        "No safety ratings were returned.".to_string()
+    } else {
+        format!("Safety ratings:\n\n{}", ratings.join("\n"))
+    }
+}
+
+/// Builds an explanation when the whole prompt was blocked
+fn explain_prompt_block(res_json: &Value) -> Option<ModelResponse> {
+    let reason = res_json
+        .pointer("/promptFeedback/blockReason")
+        .and_then(|v| v.as_str())?;
+
+    let mut text = format!("**The prompt was blocked** (block reason: `{}`).", reason);
+    if let Some(message) = res_json
+        .pointer("/promptFeedback/blockReasonMessage")
         .and_then(|v| v.as_str())
-        .unwrap_or("No response text found")
+    {
+        text.push_str(&format!("\n\n{}", message));
+    }
+    text.push_str("\n\n");
+    text.push_str(&format_safety_ratings(
+        res_json.pointer("/promptFeedback/safetyRatings"),
+    ));
+
+    Some(ModelResponse {
+        text,
+        blocked: Some(format!("Prompt blocked: {}", reason)),
+        ..Default::default()
+    })
 }
 
-pub async fn send_request(
-    prompt: String,
-    ai_model: String,
-    image_data: Option<ColorImage>,
-    history: Vec<ChatMessage>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    // API key is embedded at compile time from GEMINI_API_KEY environment variable
-    // Set GEMINI_API_KEY before building: cargo build --release
-    const API_KEY: &str = env!("GEMINI_API_KEY");
+/// Builds an explanation when a candidate was stopped without producing any text
+fn explain_candidate_block(candidate: &Value, has_text: bool) -> Option<ModelResponse> {
+    let finish_reason = candidate.get("finishReason").and_then(|v| v.as_str())?;
+    let blocking_reasons = [
+        "SAFETY",
+        "RECITATION",
+        "BLOCKLIST",
+        "PROHIBITED_CONTENT",
+        "SPII",
+        "IMAGE_SAFETY",
+    ];
+    if has_text || !blocking_reasons.contains(&finish_reason) {
+        return None;
+    }
 
-    let api_key = API_KEY.trim();
+    let text = format!(
+        "**The response was blocked** (finish reason: `{}`).\n\n{}",
+        finish_reason,
+        format_safety_ratings(candidate.get("safetyRatings"))
+    );
 
-    // Debug check for common issues
-    if api_key.is_empty() {
-        return Err("API key is empty. Check your .env file and rebuild.".into());
+    Some(ModelResponse {
+        text,
+        blocked: Some(format!("Response blocked: {}", finish_reason)),
+        finish_reason: Some(finish_reason.to_string()),
+        ..Default::default()
+    })
+}
+
+fn decode_inline_image(inline_data: &Value) -> Result<GeneratedImage, String> {
+    let mime_type = inline_data
+        .get("mimeType")
+        .or_else(|| inline_data.get("mime_type"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("image/png");
+    let data = inline_data
+        .get("data")
+        .and_then(|v| v.as_str())
+        .ok_or("missing data")?;
+
+    GeneratedImage::from_base64(mime_type, data)
+}
+
+/// Extracts the answer text, thought summaries and images of a single candidate
+fn extract_candidate(candidate: &Value) -> ModelResponse {
+    let mut text = String::new();
+    let mut thoughts = String::new();
+    let mut images = Vec::new();
+
+    let parts = candidate
+        .pointer("/content/parts")
+        .and_then(|v| v.as_array());
+
+    for part in parts.into_iter().flatten() {
+        let is_thought = part.get("thought").and_then(|v| v.as_bool()) == Some(true);
+
+        if let Some(inline_data) = part.get("inlineData").or_else(|| part.get("inline_data")) {
+            if !is_thought {
+                match decode_inline_image(inline_data) {
+                    Ok(image) => images.push(image),
+                    Err(e) => {
+                        text.push_str(&format!("\n\n*Could not decode returned image: {}*", e))
+                    }
+                }
+            }
+            continue;
+        }
+
+        let Some(part_text) = part.get("text").and_then(|v| v.as_str()) else {
+            continue;
+        };
+
+        if is_thought {
+            thoughts.push_str(part_text);
+        } else {
+            text.push_str(part_text);
+        }
     }
-    if api_key.contains("your-actual-api-key-here") || api_key.contains("placeholder") {
-        return Err("API key is still a placeholder. Set a real key in .env and rebuild.".into());
+
+    let has_output = !text.is_empty() || !images.is_empty();
+    if let Some(blocked) = explain_candidate_block(candidate, has_output) {
+        return blocked;
     }
 
-    let client = Client::new();
+    if text.is_empty() && images.is_empty() {
+        text = "No response text found".to_string();
+    }
+
+    ModelResponse {
+        text,
+        thoughts: (!thoughts.is_empty()).then_some(thoughts),
+        images,
+        finish_reason: candidate
+            .get("finishReason")
+            .and_then(|v| v.as_str())
+            .map(str::to_string),
+        ..Default::default()
+    }
+}
+
+/// Extracts every candidate from a Gemini API JSON response; always returns at least one
+fn extract_candidates(res_json: &Value) -> Vec<ModelResponse> {
+    if let Some(blocked) = explain_prompt_block(res_json) {
+        return vec![blocked];
+    }
 
+    let mut candidates: Vec<ModelResponse> = res_json
+        .get("candidates")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .map(extract_candidate)
+        .collect();
+
+    if candidates.is_empty() {
+        candidates.push(extract_candidate(&Value::Null));
+    }
+    let usage = res_json
+        .get("usageMetadata")
+        .and_then(TokenUsage::from_metadata);
+    for candidate in &mut candidates {
+        candidate.usage = usage;
+    }
+    candidates
+}
+
+/// Request body for `generateContent` and `streamGenerateContent`
+fn build_request_body(
+    prompt: String,
+    image_data: Option<ColorImage>,
+    history: Vec<ChatMessage>,
+    settings: &GenerationSettings,
+    shared: &SharedContext,
+) -> Result<Value, Box<dyn std::error::Error>> {
     let mut contents = Vec::new();
 
+    // Documents lead the conversation unless a cache already holds them
+    if shared.cached_content.is_none() && !shared.attachments.is_empty() {
+        let parts: Vec<Value> = shared.attachments.iter().map(|a| a.to_part()).collect();
+        contents.push(json!({
+            "parts": parts,
+            "role": "user"
+        }));
+    }
+
     // Add history
     for msg in history {
         contents.push(json!({
@@ -103,50 +692,311 @@ pub async fn send_request(
         "role": "user",
     }));
 
-    let res = client
+    let mut body = json!({
+        "contents": contents,
+        "generationConfig": settings.generation_config(),
+    });
+    match shared.cached_content {
+        Some(ref cache) => body["cachedContent"] = json!(cache),
+        None if !shared.system_instruction.trim().is_empty() => {
+            body["systemInstruction"] = json!({
+                "parts": [{ "text": shared.system_instruction }]
+            });
+        }
+        None => {}
+    }
+    // Image-output models don't support search grounding or URL context, and requests
+    // using a cache can't set tools
+    if !settings.image_output && shared.cached_content.is_none() {
+        body["tools"] = json!([
+            {"googleSearch": {}},
+            {"urlContext": {}}
+        ]);
+    }
+    let safety_settings = settings.safety_settings();
+    if !safety_settings.is_empty() {
+        body["safetySettings"] = json!(safety_settings);
+    }
+
+    Ok(body)
+}
+
+/// Reads the `data:` payloads of a server-sent events response one event at a time
+pub struct SseReader {
+    response: reqwest::Response,
+    pending: Vec<u8>,
+    recorder: Recorder,
+    /// Every payload so far, for the diagnostics record
+    transcript: String,
+}
+
+impl SseReader {
+    pub fn new(response: reqwest::Response, recorder: Recorder) -> Self {
+        Self {
+            response,
+            pending: Vec::new(),
+            recorder,
+            transcript: String::new(),
+        }
+    }
+
+    /// The next `data:` payload, or `None` once the response ends
+    pub async fn next_data(&mut self) -> Result<Option<String>, reqwest::Error> {
+        loop {
+            while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.pending.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                if let Some(data) = line.trim_end().strip_prefix("data:") {
+                    let data = data.trim().to_string();
+                    self.transcript.push_str(&data);
+                    self.transcript.push('\n');
+                    return Ok(Some(data));
+                }
+            }
+            match self.response.chunk().await {
+                Ok(Some(chunk)) => self.pending.extend_from_slice(&chunk),
+                Ok(None) => {
+                    self.recorder.finish(&self.transcript);
+                    return Ok(None);
+                }
+                Err(e) => {
+                    self.recorder.fail(&e.to_string());
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Notes a payload that isn't valid JSON in the diagnostics record; the stream goes on
+    pub fn skip(&mut self, error: &serde_json::Error) {
+        self.transcript
+            .push_str(&format!("[skipped, not valid JSON: {}]\n", error));
+    }
+}
+
+pub async fn send_request(
+    prompt: String,
+    ai_model: String,
+    image_data: Option<ColorImage>,
+    history: Vec<ChatMessage>,
+    settings: GenerationSettings,
+    shared: SharedContext,
+) -> Result<Vec<ModelResponse>, Box<dyn std::error::Error>> {
+    let api_key = api_key()?;
+
+    let client = Client::new();
+
+    let body = build_request_body(prompt, image_data, history, &settings, &shared)?;
+
+    let request = client
         .post(format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-            ai_model, api_key
+            "{}/models/{}:generateContent",
+            api_base(),
+            ai_model
         ))
+        .header(API_KEY_HEADER, api_key)
         .header("Content-Type", "application/json")
-        .json(&json!({
-            "contents": contents,
-            "tools": [
-                {"googleSearch": {}},
-                {"urlContext": {}}
-            ]
-        }))
-        .send()
-        .await?;
+        .json(&body);
+    let (res, recorder) = diagnostics::send(request, Some(&ai_model)).await?;
 
     if !res.status().is_success() {
         let status = res.status();
-        let error_text = res
-            .text()
+        let error_text = recorder
+            .text(res)
             .await
             .unwrap_or_else(|_| "Failed to read error response".to_string());
         return Err(format!("HTTP Error {}: {}", status, error_text).into());
     }
 
-    let res_json: Value = res.json().await?;
+    let res_json: Value = serde_json::from_str(&recorder.text(res).await?)?;
 
-    let response_text = extract_response_text(&res_json);
-    Ok(response_text.to_string())
+    Ok(extract_candidates(&res_json))
 }
 
-pub fn spawn_async_request(
+/// Like `send_request` for the first candidate, but hands each piece of answer text to
+/// `on_text` as it arrives over `streamGenerateContent`
+pub async fn stream_request(
     prompt: String,
     ai_model: String,
     image_data: Option<ColorImage>,
-    history: Vec<ChatMessage>, // accepts Vec<ChatMessage>
-) -> JoinHandle<Result<String, ()>> {
+    history: Vec<ChatMessage>,
+    settings: GenerationSettings,
+    shared: SharedContext,
+    mut on_text: impl FnMut(&str),
+) -> Result<ModelResponse, Box<dyn std::error::Error>> {
+    let api_key = api_key()?;
+
+    let body = build_request_body(prompt, image_data, history, &settings, &shared)?;
+
+    let request = Client::new()
+        .post(format!(
+            "{}/models/{}:streamGenerateContent?alt=sse",
+            api_base(),
+            ai_model
+        ))
+        .header(API_KEY_HEADER, api_key)
+        .header("Content-Type", "application/json")
+        .json(&body);
+    let (res, recorder) = diagnostics::send(request, Some(&ai_model)).await?;
+
+    if !res.status().is_success() {
+        let status = res.status();
+        let error_text = recorder
+            .text(res)
+            .await
+            .unwrap_or_else(|_| "Failed to read error response".to_string());
+        return Err(format!("HTTP Error {}: {}", status, error_text).into());
+    }
+
+    // Every event is a partial response; the parts are merged into one candidate at the end
+    let mut merged = json!({});
+    let mut candidate_seen = false;
+    let mut merged_candidate = json!({});
+    let mut parts: Vec<Value> = Vec::new();
+    let mut events = SseReader::new(res, recorder);
+    while let Some(data) = events.next_data().await? {
+        let event: Value = match serde_json::from_str(&data) {
+            Ok(event) => event,
+            Err(e) => {
+                events.skip(&e);
+                continue;
+            }
+        };
+
+        for key in ["promptFeedback", "usageMetadata"] {
+            if let Some(value) = event.get(key) {
+                merged[key] = value.clone();
+            }
+        }
+        let Some(candidate) = event.pointer("/candidates/0") else {
+            continue;
+        };
+        candidate_seen = true;
+        for part in candidate
+            .pointer("/content/parts")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let is_thought = part.get("thought").and_then(|v| v.as_bool()) == Some(true);
+            if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                if !is_thought {
+                    on_text(text);
+                }
+            }
+            parts.push(part.clone());
+        }
+        for key in ["finishReason", "safetyRatings"] {
+            if let Some(value) = candidate.get(key) {
+                merged_candidate[key] = value.clone();
+            }
+        }
+    }
+
+    if candidate_seen {
+        merged_candidate["content"] = json!({ "parts": parts, "role": "model" });
+        merged["candidates"] = json!([merged_candidate]);
+    }
+    Ok(extract_candidates(&merged)
+        .into_iter()
+        .next()
+        .unwrap_or_default())
+}
+
+/// Stores the system instruction and documents of `shared` server-side for `ttl`
+pub async fn create_context_cache(
+    ai_model: &str,
+    shared: &SharedContext,
+    ttl: Duration,
+) -> Result<ContextCache, Box<dyn std::error::Error>> {
+    let api_key = api_key()?;
+
+    let mut body = json!({
+        "model": format!("models/{}", ai_model),
+        "displayName": "GeminiOxide session",
+        "ttl": format!("{}s", ttl.as_secs()),
+    });
+    if !shared.system_instruction.trim().is_empty() {
+        body["systemInstruction"] = json!({
+            "parts": [{ "text": shared.system_instruction }]
+        });
+    }
+    if !shared.attachments.is_empty() {
+        let parts: Vec<Value> = shared.attachments.iter().map(|a| a.to_part()).collect();
+        body["contents"] = json!([{ "parts": parts, "role": "user" }]);
+    }
+
+    let request = Client::new()
+        .post(format!("{}/cachedContents", api_base()))
+        .header(API_KEY_HEADER, api_key)
+        .json(&body);
+    let (res, recorder) = diagnostics::send(request, Some(ai_model)).await?;
+
+    if !res.status().is_success() {
+        let status = res.status();
+        let error_text = recorder
+            .text(res)
+            .await
+            .unwrap_or_else(|_| "Failed to read error response".to_string());
+        return Err(format!("HTTP Error {}: {}", status, error_text).into());
+    }
+
+    let res_json: Value = serde_json::from_str(&recorder.text(res).await?)?;
+    let name = res_json
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or("Cache response has no name")?;
+
+    Ok(ContextCache {
+        name: name.to_string(),
+        model: ai_model.to_string(),
+        token_count: res_json
+            .pointer("/usageMetadata/totalTokenCount")
+            .and_then(|v| v.as_u64()),
+        expires_at: Instant::now() + ttl,
+    })
+}
+
+pub async fn delete_context_cache(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let api_key = api_key()?;
+
+    let request = Client::new()
+        .delete(format!("{}/{}", api_base(), name))
+        .header(API_KEY_HEADER, api_key);
+    let (res, recorder) = diagnostics::send(request, None).await?;
+
+    if !res.status().is_success() {
+        let status = res.status();
+        let error_text = recorder
+            .text(res)
+            .await
+            .unwrap_or_else(|_| "Failed to read error response".to_string());
+        return Err(format!("HTTP Error {}: {}", status, error_text).into());
+    }
+    recorder.text(res).await?;
+    Ok(())
+}
+
+pub fn spawn_create_context_cache(
+    ai_model: String,
+    shared: SharedContext,
+    ttl: Duration,
+) -> JoinHandle<Result<ContextCache, String>> {
     std::thread::spawn(move || {
-        let response = Runtime::new().unwrap().block_on(async {
-            send_request(prompt, ai_model, image_data, history)
+        Runtime::new().map_err(|e| e.to_string())?.block_on(async {
+            create_context_cache(&ai_model, &shared, ttl)
                 .await
-                .unwrap_or_else(|err| format!("Error: {}", err))
-        });
+                .map_err(|err| diagnostics::redact(&err.to_string()))
+        })
+    })
+}
 
-        Ok::<String, ()>(response.to_string())
+pub fn spawn_delete_context_cache(name: String) -> JoinHandle<Result<(), String>> {
+    std::thread::spawn(move || {
+        Runtime::new().map_err(|e| e.to_string())?.block_on(async {
+            delete_context_cache(&name)
+                .await
+                .map_err(|err| diagnostics::redact(&err.to_string()))
+        })
     })
 }
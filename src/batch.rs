@@ -0,0 +1,521 @@
+use crate::api_client::{
+    fallback_models, load_cached_models, GenerationSettings, ModelInfo, ThinkingControl,
+};
+use crate::commands;
+use crate::documents::SharedContext;
+use crate::img_utils;
+use crate::providers::{ChatProvider, ChatRequest, ProviderSettings};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinSet;
+
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// One request of a manifest; empty fields fall back to the command-line defaults
+pub struct BatchRow {
+    pub id: String,
+    pub prompt: Option<String>,
+    /// Relative paths are resolved against the manifest's folder
+    pub image: Option<PathBuf>,
+    pub model: Option<String>,
+    pub system: Option<String>,
+    pub thinking_budget: Option<i64>,
+    pub thinking_level: Option<String>,
+}
+
+/// Settings for a whole run, from the command line
+pub struct BatchOptions {
+    pub manifest: PathBuf,
+    pub output: PathBuf,
+    pub concurrency: usize,
+    /// Upper bound on requests started per minute; `None` means no limit
+    pub requests_per_minute: Option<u32>,
+    pub model: Option<String>,
+    pub system: String,
+    pub prompt: Option<String>,
+}
+
+/// One line of the output file
+#[derive(Serialize)]
+struct BatchResult {
+    id: String,
+    /// "ok", "blocked" or "error"; only errors are retried when the run is resumed
+    status: &'static str,
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    latency_ms: u128,
+}
+
+/// Counts reported when a run ends
+#[derive(Default)]
+pub struct BatchSummary {
+    pub skipped: usize,
+    pub succeeded: usize,
+    pub blocked: usize,
+    pub failed: usize,
+}
+
+fn is_csv(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("csv"))
+}
+
+/// Splits CSV text into records; handles quoted fields with commas, quotes and newlines
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if in_quotes => in_quotes = false,
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => record.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes => {}
+            '\n' if !in_quotes => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err("Unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    // Blank lines are not rows
+    records.retain(|r| !(r.len() == 1 && r[0].trim().is_empty()));
+    Ok(records)
+}
+
+/// Text of a manifest value; numbers are accepted too, e.g. for ids
+fn field_text(row: &Map<String, Value>, key: &str) -> Option<String> {
+    let text = match row.get(key)? {
+        Value::String(s) => s.trim().to_string(),
+        Value::Null => return None,
+        other => other.to_string(),
+    };
+    (!text.is_empty()).then_some(text)
+}
+
+fn to_row(row: &Map<String, Value>, number: usize, base: &Path) -> Result<BatchRow, String> {
+    let thinking_budget = field_text(row, "thinking_budget")
+        .map(|b| {
+            b.parse()
+                .map_err(|_| format!("Row {}: thinking_budget must be a number", number))
+        })
+        .transpose()?;
+    Ok(BatchRow {
+        // Without an id column the row number identifies the row when resuming
+        id: field_text(row, "id").unwrap_or_else(|| number.to_string()),
+        prompt: field_text(row, "prompt"),
+        image: field_text(row, "image").map(|image| base.join(image)),
+        model: field_text(row, "model"),
+        system: field_text(row, "system"),
+        thinking_budget,
+        thinking_level: field_text(row, "thinking_level"),
+    })
+}
+
+/// Reads a JSONL manifest (one object per line) or a CSV manifest with a header row
+pub fn read_manifest(path: &Path) -> Result<Vec<BatchRow>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let objects: Vec<Map<String, Value>> = if is_csv(path) {
+        let mut records = parse_csv(&text)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+            .into_iter();
+        let header: Vec<String> = records
+            .next()
+            .ok_or_else(|| format!("{} is empty", path.display()))?
+            .into_iter()
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect();
+        records
+            .enumerate()
+            .map(|(i, record)| {
+                if record.len() != header.len() {
+                    return Err(format!(
+                        "Row {} of {} has {} fields but the header has {}",
+                        i + 1,
+                        path.display(),
+                        record.len(),
+                        header.len()
+                    ));
+                }
+                Ok(header
+                    .iter()
+                    .cloned()
+                    .zip(record.into_iter().map(Value::String))
+                    .collect())
+            })
+            .collect::<Result<_, _>>()?
+    } else {
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|e| {
+                    format!("Failed to parse {} line {}: {}", path.display(), i + 1, e)
+                })
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    let rows: Vec<BatchRow> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| to_row(object, i + 1, base))
+        .collect::<Result<_, _>>()?;
+
+    let mut ids = HashSet::new();
+    if let Some(duplicate) = rows.iter().find(|row| !ids.insert(row.id.as_str())) {
+        return Err(format!(
+            "Row id '{}' appears more than once; ids must be unique so runs can be resumed",
+            duplicate.id
+        ));
+    }
+    Ok(rows)
+}
+
+/// Ids already answered in an earlier run; errors are left out so they are retried
+fn finished_ids(output: &Path) -> Result<HashSet<String>, String> {
+    let text = match fs::read_to_string(output) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", output.display(), e)),
+    };
+    // A line cut off by an interruption doesn't parse and is simply run again
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|result| result.get("status").and_then(|v| v.as_str()) != Some("error"))
+        .filter_map(|result| {
+            result
+                .get("id")
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        })
+        .collect())
+}
+
+fn settings_for(row: &BatchRow, model: Option<&ModelInfo>) -> GenerationSettings {
+    let mut settings = GenerationSettings::default();
+    if let Some(ref level) = row.thinking_level {
+        settings.thinking = ThinkingControl::Level(level.to_ascii_lowercase());
+    } else if let Some(budget) = row.thinking_budget {
+        settings.thinking = ThinkingControl::Budget(budget);
+    }
+    let mut settings = settings.for_model(model);
+    // Only the answer is written out, so don't ask for thought summaries
+    settings.include_thoughts = false;
+    settings
+}
+
+/// Waits until the next request may start under the rate limit
+async fn wait_for_slot(next_start: &Mutex<Instant>, interval: Duration) {
+    let mut next = next_start.lock().await;
+    let now = Instant::now();
+    if *next > now {
+        tokio::time::sleep(*next - now).await;
+    }
+    *next = Instant::now().max(*next) + interval;
+}
+
+async fn run_row(
+    row: BatchRow,
+    provider: Result<Box<dyn ChatProvider>, String>,
+    model: String,
+    settings: GenerationSettings,
+    prompt: String,
+    system: String,
+) -> BatchResult {
+    let started = Instant::now();
+    let image_label = row.image.as_ref().map(|p| p.display().to_string());
+    let mut result = BatchResult {
+        id: row.id,
+        status: "error",
+        model: model.clone(),
+        image: image_label,
+        text: None,
+        finish_reason: None,
+        error: None,
+        latency_ms: 0,
+    };
+
+    let provider = match provider {
+        Ok(provider) => provider,
+        Err(e) => {
+            result.error = Some(e);
+            return result;
+        }
+    };
+    let image = match row
+        .image
+        .as_deref()
+        .map(img_utils::load_color_image_from_path)
+    {
+        Some(Err(e)) => {
+            result.error = Some(e);
+            return result;
+        }
+        image => image.and_then(Result::ok),
+    };
+    let shared = SharedContext {
+        system_instruction: system,
+        ..SharedContext::default()
+    };
+
+    let response = provider
+        .send(ChatRequest {
+            prompt,
+            model,
+            image,
+            history: Vec::new(),
+            settings,
+            shared,
+        })
+        .await;
+    result.latency_ms = started.elapsed().as_millis();
+    match response.map(|responses| responses.into_iter().next().unwrap_or_default()) {
+        Ok(response) => {
+            result.status = if response.blocked.is_some() {
+                "blocked"
+            } else {
+                "ok"
+            };
+            result.text = Some(response.text);
+            result.finish_reason = response.finish_reason.or(response.blocked);
+        }
+        Err(e) => result.error = Some(e),
+    }
+    result
+}
+
+/// Runs every row not yet answered in `options.output`, appending one JSON line per row
+pub fn run(options: BatchOptions) -> Result<BatchSummary, String> {
+    let rows = read_manifest(&options.manifest)?;
+    let done = finished_ids(&options.output)?;
+    let total = rows.len();
+    let pending: Vec<BatchRow> = rows
+        .into_iter()
+        .filter(|row| !done.contains(&row.id))
+        .collect();
+    let mut summary = BatchSummary {
+        skipped: total - pending.len(),
+        ..BatchSummary::default()
+    };
+    if summary.skipped > 0 {
+        eprintln!(
+            "Resuming: {} of {} rows are already in {}",
+            summary.skipped,
+            total,
+            options.output.display()
+        );
+    }
+    if pending.is_empty() {
+        return Ok(summary);
+    }
+    if options.prompt.is_none() {
+        if let Some(row) = pending.iter().find(|row| row.prompt.is_none()) {
+            return Err(format!(
+                "Row '{}' has no prompt; add a prompt column or pass --prompt",
+                row.id
+            ));
+        }
+    }
+
+    let mut output = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&options.output)
+        .map_err(|e| format!("Failed to open {}: {}", options.output.display(), e))?;
+    // Start on a fresh line if the last run was cut off mid-line
+    let ends_mid_line = fs::read(&options.output)
+        .ok()
+        .is_some_and(|bytes| bytes.last().is_some_and(|&b| b != b'\n'));
+    if ends_mid_line {
+        writeln!(output).map_err(|e| format!("Failed to write results: {}", e))?;
+    }
+
+    let providers = ProviderSettings::load_headless()?;
+    let models = load_cached_models().unwrap_or_else(fallback_models);
+    let runtime =
+        Runtime::new().map_err(|e| format!("Failed to start the async runtime: {}", e))?;
+    let count = pending.len();
+
+    runtime.block_on(async {
+        let permits = Arc::new(Semaphore::new(options.concurrency.max(1)));
+        let next_start = Arc::new(Mutex::new(Instant::now()));
+        let interval = options
+            .requests_per_minute
+            .filter(|&rpm| rpm > 0)
+            .map(|rpm| Duration::from_secs_f64(60.0 / rpm as f64));
+
+        let mut tasks = JoinSet::new();
+        for row in pending {
+            let model =
+                commands::resolve_model(&models, row.model.as_deref().or(options.model.as_deref()));
+            let settings = settings_for(&row, models.iter().find(|m| m.name == model));
+            let provider = providers.provider_for_model(&model);
+            let prompt = row
+                .prompt
+                .clone()
+                .or_else(|| options.prompt.clone())
+                .unwrap_or_default();
+            let system = row.system.clone().unwrap_or_else(|| options.system.clone());
+            let permits = Arc::clone(&permits);
+            let next_start = Arc::clone(&next_start);
+
+            tasks.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                if let Some(interval) = interval {
+                    wait_for_slot(&next_start, interval).await;
+                }
+                run_row(row, provider, model, settings, prompt, system).await
+            });
+        }
+
+        let mut finished = 0;
+        while let Some(joined) = tasks.join_next().await {
+            let result = joined.map_err(|e| format!("A request task failed: {}", e))?;
+            finished += 1;
+            match result.status {
+                "ok" => summary.succeeded += 1,
+                "blocked" => summary.blocked += 1,
+                _ => summary.failed += 1,
+            }
+            eprintln!(
+                "[{}/{}] {}: {}{}",
+                finished,
+                count,
+                result.id,
+                result.status,
+                result
+                    .error
+                    .as_ref()
+                    .map(|e| format!(" ({})", e))
+                    .unwrap_or_default()
+            );
+
+            let line = serde_json::to_string(&result)
+                .map_err(|e| format!("Failed to serialize result: {}", e))?;
+            // Written and flushed per row, so an interruption loses at most the rows in flight
+            writeln!(output, "{}", line)
+                .and_then(|_| output.flush())
+                .map_err(|e| format!("Failed to write results: {}", e))?;
+        }
+        Ok::<(), String>(())
+    })?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(records: &[&[&str]]) -> Vec<Vec<String>> {
+        records
+            .iter()
+            .map(|record| record.iter().map(|f| f.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parse_csv_handles_quoted_commas_and_newlines() {
+        let records = parse_csv("id,prompt\n1,\"a, b\"\n2,\"line one\nline two\"\n").unwrap();
+        assert_eq!(
+            records,
+            strings(&[
+                &["id", "prompt"],
+                &["1", "a, b"],
+                &["2", "line one\nline two"]
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_csv_unescapes_doubled_quotes() {
+        let records = parse_csv("prompt\n\"say \"\"hi\"\"\"\n").unwrap();
+        assert_eq!(records, strings(&[&["prompt"], &["say \"hi\""]]));
+    }
+
+    #[test]
+    fn parse_csv_skips_bom_and_crlf() {
+        let records = parse_csv("\u{feff}id,prompt\r\n1,hello\r\n\r\n2,bye").unwrap();
+        assert_eq!(
+            records,
+            strings(&[&["id", "prompt"], &["1", "hello"], &["2", "bye"]])
+        );
+    }
+
+    #[test]
+    fn parse_csv_rejects_unterminated_quote() {
+        assert!(parse_csv("id,prompt\n1,\"open\n").is_err());
+    }
+
+    fn temp_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("gemini-oxide-test-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn finished_ids_skips_errors_and_cut_off_lines() {
+        let path = temp_file(
+            "finished.jsonl",
+            "{\"id\":\"a\",\"status\":\"ok\"}\n\
+{\"id\":\"b\",\"status\":\"error\"}\n\
+{\"id\":\"c\",\"status\":\"blocked\"}\n\
+{\"id\":\"d\",\"sta",
+        );
+        let ids = finished_ids(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(ids, HashSet::from(["a".to_string(), "c".to_string()]));
+    }
+
+    #[test]
+    fn finished_ids_is_empty_without_output() {
+        let path = std::env::temp_dir().join("gemini-oxide-test-missing.jsonl");
+        assert!(finished_ids(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_manifest_rejects_rows_with_the_wrong_field_count() {
+        let path = temp_file("fields.csv", "id,prompt\n1,hello\n2,hello,extra\n");
+        let result = read_manifest(&path);
+        fs::remove_file(&path).unwrap();
+        let Err(error) = result else {
+            panic!("a row with an extra field was accepted");
+        };
+        assert!(error.starts_with("Row 2 of"), "{}", error);
+    }
+}
@@ -0,0 +1,704 @@
+use crate::api_client::{fallback_models, load_cached_models, GenerationSettings, ModelInfo};
+use crate::batch::{self, BatchOptions};
+use crate::commands;
+use crate::context::ContextSettings;
+use crate::conversation::{ChatMessage, ConversationTree};
+use crate::documents::SharedContext;
+use crate::img_utils;
+use crate::providers::{ChatRequest, ProviderSettings};
+use crate::server::{self, ServeOptions};
+use crate::session::{self, Session};
+use crate::storage;
+use crate::terminal::{self, MarkdownPrinter};
+use egui::ColorImage;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::runtime::Runtime;
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+/// The prompt or the answer was blocked by the safety filters
+const EXIT_BLOCKED: i32 = 3;
+
+const USAGE: &str = "\
+Usage: gemini-oxide [COMMAND]
+
+Without a command the window opens.
+
+Commands:
+  ask [PROMPT] [-]  Send one prompt and print the answer
+  chat [PROMPT]     Interactive conversation in the terminal, starting with PROMPT if given
+  batch MANIFEST    Run every row of a JSONL or CSV manifest and write the answers as JSONL
+  serve             Serve an OpenAI-compatible API (/v1/chat/completions, /v1/models) locally
+  help              Show this help
+
+Options:
+  -m, --model NAME  Model to use; partial names such as 'flash' are matched
+  -i, --image FILE  Attach an image to the (first) prompt
+  -s, --system TEXT System instruction
+  -h, --help        Show this help
+
+Options for batch (rows may set prompt, image, model, system, thinking_budget and thinking_level):
+  -o, --output FILE      Results file; rows already answered in it are skipped [MANIFEST.results.jsonl]
+  -p, --prompt TEXT      Prompt for rows without one
+  -j, --concurrency N    Requests in flight at once [4]
+  -r, --rate N           At most N requests started per minute
+
+Options for serve (-m sets the model for requests that name none, -s a system instruction
+added to every request):
+  --host ADDR            Address to listen on [127.0.0.1]
+  --port N               Port to listen on [8089]
+  --token TOKEN          Bearer token clients must send [random, printed at startup]
+
+ask: without PROMPT the prompt is read from piped input; '-' reads stdin as well and appends it
+to PROMPT.
+Exit codes: 0 success, 1 request failed, 2 invalid arguments, 3 blocked by safety filters.
+";
+
+/// Runs a command-line invocation instead of the window; returns the process exit code
+pub fn run(args: &[String]) -> i32 {
+    let Some(command) = args.first() else {
+        return usage_error("Missing command");
+    };
+    match command.as_str() {
+        "ask" => ask(&args[1..]),
+        "chat" => chat(&args[1..]),
+        "batch" => batch(&args[1..]),
+        "serve" => serve(&args[1..]),
+        "help" | "-h" | "--help" => {
+            print!("{}", USAGE);
+            0
+        }
+        other => usage_error(&format!("Unknown command '{}'", other)),
+    }
+}
+
+fn usage_error(message: &str) -> i32 {
+    eprintln!("{}\nRun 'gemini-oxide help' for usage.", message);
+    EXIT_USAGE
+}
+
+struct Options {
+    /// The prompt for ask and chat, the manifest for batch
+    argument: Option<String>,
+    /// `-` was given to ask: read stdin even though there is a prompt
+    stdin: bool,
+    model: Option<String>,
+    image: Option<PathBuf>,
+    system: String,
+    output: Option<PathBuf>,
+    prompt: Option<String>,
+    concurrency: Option<usize>,
+    rate: Option<u32>,
+    host: Option<IpAddr>,
+    port: Option<u16>,
+    token: Option<String>,
+}
+
+/// Value of an option given either as `--name value` or `--name=value`
+fn option_value(
+    name: &str,
+    inline: Option<&str>,
+    rest: &mut std::slice::Iter<String>,
+) -> Result<String, String> {
+    match inline {
+        Some(value) => Ok(value.to_string()),
+        None => rest
+            .next()
+            .cloned()
+            .ok_or_else(|| format!("{} needs a value", name)),
+    }
+}
+
+fn number_value<T: std::str::FromStr>(name: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} needs a number, got '{}'", name, value))
+}
+
+/// `None` means help was requested; options that belong to another command are rejected
+fn parse_options(args: &[String], command: &str) -> Result<Option<Options>, String> {
+    let batch = command == "batch";
+    let serve = command == "serve";
+    let mut parsed = Options {
+        argument: None,
+        stdin: false,
+        model: None,
+        image: None,
+        system: String::new(),
+        output: None,
+        prompt: None,
+        concurrency: None,
+        rate: None,
+        host: None,
+        port: None,
+        token: None,
+    };
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value)),
+            _ => (arg.as_str(), None),
+        };
+        match name {
+            "-h" | "--help" => return Ok(None),
+            "-m" | "--model" => parsed.model = Some(option_value(name, inline, &mut rest)?),
+            "-i" | "--image" if !batch && !serve => {
+                parsed.image = Some(option_value(name, inline, &mut rest)?.into())
+            }
+            "-s" | "--system" => parsed.system = option_value(name, inline, &mut rest)?,
+            "-o" | "--output" if batch => {
+                parsed.output = Some(option_value(name, inline, &mut rest)?.into())
+            }
+            "-p" | "--prompt" if batch => {
+                parsed.prompt = Some(option_value(name, inline, &mut rest)?)
+            }
+            "-j" | "--concurrency" if batch => {
+                parsed.concurrency =
+                    Some(number_value(name, option_value(name, inline, &mut rest)?)?)
+            }
+            "-r" | "--rate" if batch => {
+                parsed.rate = Some(number_value(name, option_value(name, inline, &mut rest)?)?)
+            }
+            "--host" if serve => {
+                parsed.host = Some(
+                    option_value(name, inline, &mut rest)?
+                        .parse()
+                        .map_err(|_| "--host needs an IP address such as 127.0.0.1".to_string())?,
+                )
+            }
+            "--port" if serve => {
+                parsed.port = Some(number_value(name, option_value(name, inline, &mut rest)?)?)
+            }
+            "--token" if serve => parsed.token = Some(option_value(name, inline, &mut rest)?),
+            "-" if command == "ask" => parsed.stdin = true,
+            _ if name.starts_with('-') && name != "-" => {
+                return Err(format!("Unknown option '{}'", name))
+            }
+            _ if serve => return Err(format!("Unexpected argument '{}'", arg)),
+            _ if parsed.argument.is_some() && batch => {
+                return Err("Only one manifest can be given".to_string())
+            }
+            _ if parsed.argument.is_some() => {
+                return Err("Only one prompt can be given; quote it if it has spaces".to_string())
+            }
+            _ => parsed.argument = Some(arg.clone()),
+        }
+    }
+    Ok(Some(parsed))
+}
+
+/// The prompt from the argument and/or stdin; stdin is read only without a prompt or with `-`,
+/// so a script that runs ask with an open stdin doesn't hang
+fn read_prompt(arg: Option<String>, read_stdin: bool) -> Result<String, String> {
+    let stdin = std::io::stdin();
+    let piped = if !read_stdin && (arg.is_some() || stdin.is_terminal()) {
+        None
+    } else {
+        let mut input = String::new();
+        stdin
+            .lock()
+            .read_to_string(&mut input)
+            .map_err(|e| format!("Failed to read stdin: {}", e))?;
+        Some(input)
+    };
+
+    let prompt = match (arg, piped) {
+        (Some(prompt), Some(input)) if !input.trim().is_empty() => {
+            format!("{}\n\n{}", prompt, input.trim_end())
+        }
+        (Some(prompt), _) => prompt,
+        (None, Some(input)) => input.trim_end().to_string(),
+        (None, None) => String::new(),
+    };
+    if prompt.trim().is_empty() {
+        return Err("No prompt given; pass it as an argument or pipe it in".to_string());
+    }
+    Ok(prompt)
+}
+
+fn request_settings(models: &[ModelInfo], model_name: &str) -> GenerationSettings {
+    let model = models.iter().find(|m| m.name == model_name);
+    let mut settings = GenerationSettings::default().for_model(model);
+    // Thought summaries aren't printed, so don't ask for them
+    settings.include_thoughts = false;
+    settings
+}
+
+fn ask(args: &[String]) -> i32 {
+    let args = match parse_options(args, "ask") {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return 0;
+        }
+        Err(e) => return usage_error(&e),
+    };
+    let prompt = match read_prompt(args.argument, args.stdin) {
+        Ok(prompt) => prompt,
+        Err(e) => return usage_error(&e),
+    };
+    let image = match args
+        .image
+        .as_deref()
+        .map(img_utils::load_color_image_from_path)
+        .transpose()
+    {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+
+    let models = load_cached_models().unwrap_or_else(fallback_models);
+    let model_name = commands::resolve_model(&models, args.model.as_deref());
+    let settings = request_settings(&models, &model_name);
+    let shared = SharedContext {
+        system_instruction: args.system,
+        ..SharedContext::default()
+    };
+    let provider =
+        match ProviderSettings::load_headless().and_then(|p| p.provider_for_model(&model_name)) {
+            Ok(provider) => provider,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return EXIT_ERROR;
+            }
+        };
+
+    let runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Error: Failed to start the async runtime: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+    let result = runtime.block_on(provider.send(ChatRequest {
+        prompt,
+        model: model_name,
+        image,
+        history: Vec::new(),
+        settings,
+        shared,
+    }));
+    let response = match result {
+        Ok(responses) => responses.into_iter().next().unwrap_or_default(),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+
+    if let Some(ref reason) = response.blocked {
+        eprintln!("{}\n\n{}", reason, response.text);
+        return EXIT_BLOCKED;
+    }
+    if !response.images.is_empty() {
+        eprintln!(
+            "Note: the model returned {} image(s), which are only shown in the window",
+            response.images.len()
+        );
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    let written = writeln!(stdout, "{}", response.text.trim_end()).and_then(|_| stdout.flush());
+    match written {
+        Ok(()) => 0,
+        // A closed pipe (e.g. `| head`) is not worth a second error message
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            eprintln!("Error: Failed to write the answer: {}", e);
+            EXIT_ERROR
+        }
+    }
+}
+
+const CHAT_HELP: &str = "\
+Commands:
+  /image PATH    Attach an image to the next prompt; /image alone removes it
+  /model [NAME]  Switch model, or list the available ones
+  /save          Save the conversation; it then appears in the window's History
+  /clear         Start a new conversation
+  /help          Show this help
+  /quit          Leave (Ctrl+D works too)
+End a line with \\ to continue the prompt on the next line; start it with // to send a
+prompt beginning with /.";
+
+/// State of a `chat` session; the history is a flat list, like the active branch in the window
+struct ChatRepl {
+    runtime: Runtime,
+    providers: ProviderSettings,
+    models: Vec<ModelInfo>,
+    model: String,
+    system_instruction: String,
+    history: Vec<ChatMessage>,
+    image: Option<(PathBuf, ColorImage)>,
+    /// Set by the first /save so later saves update the same session
+    session_id: Option<String>,
+    created_at: u64,
+    color: bool,
+}
+
+fn chat(args: &[String]) -> i32 {
+    let args = match parse_options(args, "chat") {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return 0;
+        }
+        Err(e) => return usage_error(&e),
+    };
+    let runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Error: Failed to start the async runtime: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+
+    let providers = match ProviderSettings::load_headless() {
+        Ok(providers) => providers,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+
+    let models = load_cached_models().unwrap_or_else(fallback_models);
+    let mut repl = ChatRepl {
+        runtime,
+        providers,
+        model: commands::resolve_model(&models, args.model.as_deref()),
+        models,
+        system_instruction: args.system,
+        history: Vec::new(),
+        image: None,
+        session_id: None,
+        created_at: storage::now_secs(),
+        color: terminal::color_enabled(),
+    };
+    if let Some(ref path) = args.image {
+        repl.attach_image(path);
+    }
+
+    println!(
+        "{}",
+        terminal::notice(
+            &format!("Chatting with {}. Type /help for commands.", repl.model),
+            repl.color
+        )
+    );
+    if let Some(prompt) = args.argument {
+        repl.send(prompt);
+    }
+
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    while let Some(input) = read_input(&mut lines) {
+        let input = match input {
+            Ok(input) => input,
+            Err(e) => {
+                eprintln!("Error: Failed to read input: {}", e);
+                return EXIT_ERROR;
+            }
+        };
+        if input.trim().is_empty() {
+            continue;
+        }
+        if input.trim_start().starts_with('/') && !input.trim_start().starts_with("//") {
+            if !repl.run_command(input.trim()) {
+                break;
+            }
+        } else {
+            repl.send(commands::unescape(&input).to_string());
+        }
+    }
+    0
+}
+
+/// One prompt, joining lines that end with a backslash; `None` at end of input
+fn read_input(
+    lines: &mut impl Iterator<Item = std::io::Result<String>>,
+) -> Option<std::io::Result<String>> {
+    let mut input = String::new();
+    let mut marker = "› ";
+    loop {
+        print!("{}", marker);
+        let _ = std::io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => return Some(Err(e)),
+            // Ctrl+D on an empty prompt leaves; with a pending continuation it sends what's there
+            None if input.is_empty() => {
+                println!();
+                return None;
+            }
+            None => return Some(Ok(input)),
+        };
+        match line.strip_suffix('\\') {
+            Some(continued) => {
+                input.push_str(continued);
+                input.push('\n');
+                marker = "… ";
+            }
+            None => {
+                input.push_str(&line);
+                return Some(Ok(input));
+            }
+        }
+    }
+}
+
+impl ChatRepl {
+    fn say(&self, text: &str) {
+        println!("{}", terminal::notice(text, self.color));
+    }
+
+    /// Runs a /command; returns false when the REPL should exit
+    fn run_command(&mut self, input: &str) -> bool {
+        let (name, arg) = match input[1..].split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (&input[1..], ""),
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "quit" | "exit" | "q" => return false,
+            "help" => println!("{}", CHAT_HELP),
+            "image" if arg.is_empty() => {
+                if self.image.take().is_some() {
+                    self.say("Image removed");
+                } else {
+                    self.say("Usage: /image PATH");
+                }
+            }
+            // Quotes are accepted because pasted or dragged paths often carry them
+            "image" => self.attach_image(Path::new(arg.trim_matches(['"', '\'']))),
+            "model" if arg.is_empty() => {
+                for model in &self.models {
+                    let marker = if model.name == self.model { "*" } else { " " };
+                    println!("{} {:<32} {}", marker, model.name, model.label());
+                }
+            }
+            "model" => {
+                self.model = commands::resolve_model(&self.models, Some(arg));
+                self.say(&format!("Model: {}", self.model));
+            }
+            "save" => match self.save() {
+                Ok(title) => self.say(&format!("Saved \"{}\"", title)),
+                Err(e) => eprintln!("Error: Failed to save session: {}", e),
+            },
+            "clear" => {
+                self.history.clear();
+                self.image = None;
+                self.session_id = None;
+                self.created_at = storage::now_secs();
+                self.say("Started a new conversation");
+            }
+            _ => self.say(&format!(
+                "Unknown command /{}. Type /help for the list, or start with // to send a prompt beginning with /",
+                name
+            )),
+        }
+        true
+    }
+
+    fn attach_image(&mut self, path: &Path) {
+        match img_utils::load_color_image_from_path(path) {
+            Ok(image) => {
+                self.say(&format!(
+                    "Attached {} ({}x{}) to the next prompt",
+                    path.display(),
+                    image.size[0],
+                    image.size[1]
+                ));
+                self.image = Some((path.to_path_buf(), image));
+            }
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+
+    /// Streams the answer to stdout and adds both turns to the history
+    fn send(&mut self, prompt: String) {
+        let settings = request_settings(&self.models, &self.model);
+        let shared = SharedContext {
+            system_instruction: self.system_instruction.clone(),
+            ..SharedContext::default()
+        };
+        // The image is only dropped once the request succeeds, so a retry still has it
+        let image = self.image.as_ref().map(|(_, image)| image.clone());
+        let provider = match self.providers.provider_for_model(&self.model) {
+            Ok(provider) => provider,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return;
+            }
+        };
+
+        let mut printer = MarkdownPrinter::new(self.color);
+        let mut stdout = std::io::stdout();
+        let request = ChatRequest {
+            prompt: prompt.clone(),
+            model: self.model.clone(),
+            image,
+            history: self.history.clone(),
+            settings,
+            shared,
+        };
+        let started = Instant::now();
+        let result = self.runtime.block_on(provider.stream(
+            request,
+            Box::new(|text| {
+                let _ = write!(stdout, "{}", printer.push(text));
+                let _ = stdout.flush();
+            }),
+        ));
+        print!("{}", printer.finish());
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return;
+            }
+        };
+        if let Some(ref reason) = response.blocked {
+            eprintln!("{}\n\n{}", reason, terminal::strip_controls(&response.text));
+            return;
+        }
+        if !response.images.is_empty() {
+            self.say(&format!(
+                "The model returned {} image(s); save the session and open it in the window to see them",
+                response.images.len()
+            ));
+        }
+
+        let message = ChatMessage {
+            role: "model".to_string(),
+            content: response.text,
+            thoughts: response.thoughts,
+            finish_reason: response.finish_reason,
+            images: response.images,
+            model: Some(self.model.clone()),
+            pinned: false,
+            created_at: storage::now_secs(),
+            latency_ms: Some(started.elapsed().as_millis() as u64),
+        };
+        if message.was_truncated() {
+            self.say("The answer was cut off (MAX_TOKENS); ask the model to continue");
+        }
+        println!();
+
+        self.image = None;
+        self.history.push(ChatMessage::user(prompt));
+        self.history.push(message);
+    }
+
+    /// Saves the conversation as a session; returns its title
+    fn save(&mut self) -> Result<String, String> {
+        if self.history.is_empty() {
+            return Err("nothing to save yet".to_string());
+        }
+        let mut conversation = ConversationTree::default();
+        let mut parent = None;
+        for message in &self.history {
+            parent = Some(conversation.add_child(parent, message.clone()));
+        }
+
+        let session = Session {
+            id: self
+                .session_id
+                .get_or_insert_with(session::new_session_id)
+                .clone(),
+            title: Session::title_for(&conversation),
+            created_at: self.created_at,
+            updated_at: storage::now_secs(),
+            model: self.model.clone(),
+            settings: GenerationSettings::default(),
+            context: ContextSettings::default(),
+            summary: None,
+            system_instruction: self.system_instruction.clone(),
+            conversation,
+        };
+        session::save_session(&session)?;
+        Ok(session.title)
+    }
+}
+
+fn batch(args: &[String]) -> i32 {
+    let args = match parse_options(args, "batch") {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return 0;
+        }
+        Err(e) => return usage_error(&e),
+    };
+    let Some(manifest) = args.argument.map(PathBuf::from) else {
+        return usage_error("No manifest given");
+    };
+    let output = args
+        .output
+        .unwrap_or_else(|| manifest.with_extension("results.jsonl"));
+
+    let options = BatchOptions {
+        manifest,
+        output,
+        concurrency: args.concurrency.unwrap_or(batch::DEFAULT_CONCURRENCY),
+        requests_per_minute: args.rate,
+        model: args.model,
+        system: args.system,
+        prompt: args.prompt,
+    };
+    let output = options.output.clone();
+    match batch::run(options) {
+        Ok(summary) => {
+            eprintln!(
+                "{} succeeded, {} blocked, {} failed, {} skipped; results in {}",
+                summary.succeeded,
+                summary.blocked,
+                summary.failed,
+                summary.skipped,
+                output.display()
+            );
+            if summary.failed > 0 {
+                eprintln!("Run the same command again to retry the failed rows");
+                EXIT_ERROR
+            } else {
+                0
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            EXIT_ERROR
+        }
+    }
+}
+
+fn serve(args: &[String]) -> i32 {
+    let args = match parse_options(args, "serve") {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return 0;
+        }
+        Err(e) => return usage_error(&e),
+    };
+    let options = ServeOptions {
+        addr: SocketAddr::new(
+            args.host.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            args.port.unwrap_or(server::DEFAULT_PORT),
+        ),
+        token: args.token,
+        model: args.model,
+        system: args.system,
+    };
+    match server::run(options) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            EXIT_ERROR
+        }
+    }
+}
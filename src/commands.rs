@@ -0,0 +1,240 @@
+use crate::api_client::{ModelInfo, DEFAULT_MODEL};
+use crate::export::ExportFormat;
+use crate::templates::PromptTemplate;
+
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+}
+
+pub const COMMANDS: [CommandInfo; 7] = [
+    CommandInfo {
+        name: "model",
+        usage: "/model <name>",
+        description: "Switch to the model whose name best matches, e.g. /model flash",
+    },
+    CommandInfo {
+        name: "clear",
+        usage: "/clear",
+        description: "Start a new conversation; the current one stays saved",
+    },
+    CommandInfo {
+        name: "image",
+        usage: "/image paste|file|clear",
+        description: "Attach an image from the clipboard or a file, or remove it",
+    },
+    CommandInfo {
+        name: "system",
+        usage: "/system [instruction]",
+        description: "Set the system instruction; without text, open the Documents window",
+    },
+    CommandInfo {
+        name: "export",
+        usage: "/export md|html|json",
+        description: "Save the conversation to a file",
+    },
+    CommandInfo {
+        name: "template",
+        usage: "/template <name>",
+        description: "Insert a prompt template",
+    },
+    CommandInfo {
+        name: "help",
+        usage: "/help",
+        description: "List the commands",
+    },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageSource {
+    Paste,
+    File,
+    Clear,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Model(String),
+    Clear,
+    Image(ImageSource),
+    System(String),
+    Export(ExportFormat),
+    Template(String),
+    Help,
+}
+
+/// Splits "/name argument" into its parts; `None` if `input` isn't a command.
+/// A leading "//" escapes the slash so prompts can start with "/".
+fn split_command(input: &str) -> Option<(&str, &str)> {
+    let rest = input.trim_start().strip_prefix('/')?;
+    if rest.starts_with('/') {
+        return None;
+    }
+    Some(match rest.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (rest.trim_end(), ""),
+    })
+}
+
+/// Parses a slash command; `None` means `input` is an ordinary prompt
+pub fn parse(input: &str) -> Option<Result<Command, String>> {
+    let (name, arg) = split_command(input)?;
+    let command = match name.to_ascii_lowercase().as_str() {
+        "model" if arg.is_empty() => Err("Usage: /model <name>".to_string()),
+        "model" => Ok(Command::Model(arg.to_string())),
+        "clear" => Ok(Command::Clear),
+        "image" => match arg.to_ascii_lowercase().as_str() {
+            "" | "paste" => Ok(Command::Image(ImageSource::Paste)),
+            "file" => Ok(Command::Image(ImageSource::File)),
+            "clear" => Ok(Command::Image(ImageSource::Clear)),
+            _ => Err("Usage: /image paste|file|clear".to_string()),
+        },
+        "system" => Ok(Command::System(arg.to_string())),
+        "export" => ExportFormat::from_name(if arg.is_empty() { "md" } else { arg })
+            .map(Command::Export)
+            .ok_or_else(|| format!("Unknown export format '{}'", arg)),
+        "template" if arg.is_empty() => Err("Usage: /template <name>".to_string()),
+        "template" => Ok(Command::Template(arg.to_string())),
+        "help" => Ok(Command::Help),
+        _ => Err(format!(
+            "Unknown command /{}. Type /help for the list, or start with // to send a prompt beginning with /",
+            name
+        )),
+    };
+    Some(command)
+}
+
+/// The escaped prompt for input starting with "//"
+pub fn unescape(input: &str) -> &str {
+    let trimmed = input.trim_start();
+    if trimmed.starts_with("//") {
+        &trimmed[1..]
+    } else {
+        input
+    }
+}
+
+/// Model whose name contains `query`; the shortest such name is the closest match
+pub fn find_model<'a>(models: &'a [ModelInfo], query: &str) -> Option<&'a ModelInfo> {
+    let query = query.to_ascii_lowercase();
+    models
+        .iter()
+        .find(|m| m.name.eq_ignore_ascii_case(&query))
+        .or_else(|| {
+            models
+                .iter()
+                .filter(|m| {
+                    m.name.to_ascii_lowercase().contains(&query)
+                        || m.display_name.to_ascii_lowercase().contains(&query)
+                })
+                .min_by_key(|m| m.name.len())
+        })
+}
+
+/// Full name of the model matching `requested` (the default model if `None`); names
+/// that match nothing are used as given
+pub fn resolve_model(models: &[ModelInfo], requested: Option<&str>) -> String {
+    let requested = requested.unwrap_or(DEFAULT_MODEL);
+    find_model(models, requested).map_or(requested.to_string(), |m| m.name.clone())
+}
+
+/// Template by exact name, falling back to the first one starting with `query`
+pub fn find_template<'a>(
+    templates: &'a [PromptTemplate],
+    query: &str,
+) -> Option<&'a PromptTemplate> {
+    let query = query.to_lowercase();
+    templates
+        .iter()
+        .find(|t| t.name.to_lowercase() == query)
+        .or_else(|| {
+            templates
+                .iter()
+                .find(|t| t.name.to_lowercase().starts_with(&query))
+        })
+}
+
+/// One autocomplete entry: `text` replaces the prompt when chosen
+pub struct Completion {
+    pub text: String,
+    pub label: String,
+    pub hint: String,
+}
+
+/// Completions for a partially typed command: command names first, then arguments
+pub fn complete(
+    input: &str,
+    models: &[ModelInfo],
+    templates: &[PromptTemplate],
+) -> Vec<Completion> {
+    if input.contains('\n') {
+        return Vec::new();
+    }
+    let Some((name, arg)) = split_command(input) else {
+        return Vec::new();
+    };
+    let name = name.to_ascii_lowercase();
+    let typing_name = !input.trim_start().contains(char::is_whitespace);
+
+    if typing_name {
+        return COMMANDS
+            .iter()
+            .filter(|c| c.name.starts_with(&name))
+            .map(|c| Completion {
+                text: format!("/{} ", c.name),
+                label: c.usage.to_string(),
+                hint: c.description.to_string(),
+            })
+            .collect();
+    }
+
+    let arg_lower = arg.to_lowercase();
+    match name.as_str() {
+        "model" => models
+            .iter()
+            .filter(|m| m.name.to_lowercase().contains(&arg_lower))
+            .map(|m| Completion {
+                text: format!("/model {}", m.name),
+                label: m.name.clone(),
+                hint: m.label(),
+            })
+            .collect(),
+        "template" => templates
+            .iter()
+            .filter(|t| t.name.to_lowercase().contains(&arg_lower))
+            .map(|t| Completion {
+                text: format!("/template {}", t.name),
+                label: t.name.clone(),
+                hint: t.description.clone(),
+            })
+            .collect(),
+        "image" => ["paste", "file", "clear"]
+            .into_iter()
+            .filter(|source| source.starts_with(&arg_lower))
+            .map(|source| Completion {
+                text: format!("/image {}", source),
+                label: source.to_string(),
+                hint: String::new(),
+            })
+            .collect(),
+        "export" => ExportFormat::ALL
+            .into_iter()
+            .filter(|format| format.short_name().starts_with(&arg_lower))
+            .map(|format| Completion {
+                text: format!("/export {}", format.short_name()),
+                label: format.short_name().to_string(),
+                hint: format.label().to_string(),
+            })
+            .collect(),
+        _ => COMMANDS
+            .iter()
+            .filter(|c| c.name == name)
+            .map(|c| Completion {
+                text: input.to_string(),
+                label: c.usage.to_string(),
+                hint: c.description.to_string(),
+            })
+            .collect(),
+    }
+}
@@ -0,0 +1,120 @@
+use crate::api_client::{ModelResponse, TokenUsage};
+use crate::providers::{ChatProvider, ChatRequest};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+/// Fewer models than this is just a normal prompt
+pub const MIN_COMPARE_MODELS: usize = 2;
+pub const MAX_COMPARE_MODELS: usize = 4;
+
+/// One model's side of a comparison
+pub struct CompareColumn {
+    pub model: String,
+    started: Instant,
+    thread: Option<JoinHandle<(Result<ModelResponse, String>, Duration)>>,
+    pub result: Option<Result<ModelResponse, String>>,
+    /// Time until the whole answer had arrived
+    pub latency: Option<Duration>,
+}
+
+impl CompareColumn {
+    pub fn is_running(&self) -> bool {
+        self.thread.is_some()
+    }
+
+    /// Time since the request was sent, until it finished
+    pub fn elapsed(&self) -> Duration {
+        self.latency.unwrap_or_else(|| self.started.elapsed())
+    }
+
+    pub fn usage(&self) -> Option<TokenUsage> {
+        self.result.as_ref()?.as_ref().ok()?.usage
+    }
+}
+
+/// The same prompt, history and image sent to several models at once
+pub struct Comparison {
+    pub prompt: String,
+    /// Node the prompt was sent after; a promoted answer is added under it
+    pub parent: Option<usize>,
+    pub columns: Vec<CompareColumn>,
+    /// Column whose answer was added to the conversation
+    pub promoted: Option<usize>,
+}
+
+impl Comparison {
+    /// Sends every request at once; each one names its own model
+    pub fn start(
+        prompt: String,
+        parent: Option<usize>,
+        requests: Vec<(Box<dyn ChatProvider>, ChatRequest)>,
+    ) -> Self {
+        let columns = requests
+            .into_iter()
+            .map(|(provider, request)| CompareColumn {
+                model: request.model.clone(),
+                started: Instant::now(),
+                thread: Some(spawn_column(provider, request)),
+                result: None,
+                latency: None,
+            })
+            .collect();
+        Self {
+            prompt,
+            parent,
+            columns,
+            promoted: None,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.columns.iter().any(CompareColumn::is_running)
+    }
+
+    /// Collects finished answers; returns true if any arrived
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        for column in &mut self.columns {
+            let Some(handle) = column.thread.take() else {
+                continue;
+            };
+            if !handle.is_finished() {
+                column.thread = Some(handle);
+                continue;
+            }
+            let (result, latency) = handle.join().unwrap_or_else(|_| {
+                (
+                    Err("Request panicked".to_string()),
+                    column.started.elapsed(),
+                )
+            });
+            column.result = Some(result);
+            column.latency = Some(latency);
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// Sends one request and times it on its own thread, so slow polling doesn't add to latency
+fn spawn_column(
+    provider: Box<dyn ChatProvider>,
+    request: ChatRequest,
+) -> JoinHandle<(Result<ModelResponse, String>, Duration)> {
+    std::thread::spawn(move || {
+        let started = Instant::now();
+        let result = match Runtime::new() {
+            Ok(runtime) => {
+                runtime
+                    .block_on(provider.send(request))
+                    .map(|responses| ModelResponse {
+                        latency_ms: Some(started.elapsed().as_millis() as u64),
+                        ..responses.into_iter().next().unwrap_or_default()
+                    })
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        (result, started.elapsed())
+    })
+}
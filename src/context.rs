@@ -0,0 +1,164 @@
+use crate::api_client::ModelInfo;
+use crate::conversation::ChatMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Rough cost of one inline image, as counted by Gemini
+const IMAGE_TOKENS: u64 = 258;
+const DEFAULT_WINDOW: usize = 10;
+const MIN_BUDGET: u64 = 1_000;
+const DEFAULT_SUMMARY_MODEL: &str = "gemini-2.5-flash-lite";
+
+/// Which earlier messages are sent along with a new prompt
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ContextStrategy {
+    FullHistory,
+    /// Newest messages that fit into the selected model's token budget
+    TokenBudget,
+    /// The last `window` messages
+    SlidingWindow,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContextSettings {
+    pub strategy: ContextStrategy,
+    pub window: usize,
+    /// Model name -> token budget; models left out use `default_budget`, or send
+    /// everything if their limit is unknown
+    #[serde(default)]
+    pub model_budgets: BTreeMap<String, u64>,
+    /// Fold messages that no longer fit into a running summary instead of dropping them
+    #[serde(default)]
+    pub summarize_dropped: bool,
+    /// Cheap model that writes the summary
+    #[serde(default = "default_summary_model")]
+    pub summary_model: String,
+}
+
+fn default_summary_model() -> String {
+    DEFAULT_SUMMARY_MODEL.to_string()
+}
+
+impl Default for ContextSettings {
+    fn default() -> Self {
+        Self {
+            strategy: ContextStrategy::SlidingWindow,
+            window: DEFAULT_WINDOW,
+            model_budgets: BTreeMap::new(),
+            summarize_dropped: false,
+            summary_model: default_summary_model(),
+        }
+    }
+}
+
+impl ContextSettings {
+    /// Input limit minus room for the answer, so a full context still leaves space to reply;
+    /// `None` when the model didn't report a limit
+    pub fn default_budget(model: Option<&ModelInfo>) -> Option<u64> {
+        model.filter(|m| m.input_token_limit > 0).map(|m| {
+            m.input_token_limit
+                .saturating_sub(m.output_token_limit)
+                .max(MIN_BUDGET)
+        })
+    }
+
+    /// `None` means the whole history is sent
+    pub fn budget_for(&self, model: Option<&ModelInfo>) -> Option<u64> {
+        model
+            .and_then(|m| self.model_budgets.get(&m.name).copied())
+            .or_else(|| Self::default_budget(model))
+    }
+
+    /// Marks which of `messages` will be sent; pinned messages are always kept
+    pub fn select(&self, messages: &[&ChatMessage], model: Option<&ModelInfo>) -> Vec<bool> {
+        let mut included: Vec<bool> = messages.iter().map(|m| m.pinned).collect();
+
+        match self.strategy {
+            ContextStrategy::FullHistory => included.fill(true),
+            ContextStrategy::SlidingWindow => {
+                let start = messages.len().saturating_sub(self.window);
+                included[start..].fill(true);
+            }
+            ContextStrategy::TokenBudget => {
+                let mut used: u64 = messages
+                    .iter()
+                    .filter(|m| m.pinned)
+                    .map(|m| estimate_tokens(m))
+                    .sum();
+                // Without a known limit there is nothing to cut down to
+                let Some(budget) = self.budget_for(model) else {
+                    included.fill(true);
+                    return included;
+                };
+
+                // Walk back from the newest message and stop at the first one that
+                // doesn't fit, so the kept history stays contiguous
+                for (i, msg) in messages.iter().enumerate().rev() {
+                    if msg.pinned {
+                        continue;
+                    }
+                    let cost = estimate_tokens(msg);
+                    if used + cost > budget {
+                        break;
+                    }
+                    used += cost;
+                    included[i] = true;
+                }
+            }
+        }
+
+        included
+    }
+}
+
+/// Index of the newest message that `select` left out; everything sent comes after it
+pub fn last_dropped(included: &[bool]) -> Option<usize> {
+    included.iter().rposition(|&keep| !keep)
+}
+
+/// Running summary standing in for the messages that no longer fit into the context
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    pub text: String,
+    /// Last node covered; the summary replaces everything from the root down to it
+    pub through: usize,
+}
+
+impl ConversationSummary {
+    /// Leading message sent in place of the summarized turns
+    pub fn to_message(&self) -> ChatMessage {
+        ChatMessage::user(format!(
+            "Summary of the earlier part of this conversation:\n\n{}",
+            self.text
+        ))
+    }
+}
+
+/// Prompt asking the summary model to fold `messages` into `previous`
+pub fn summary_prompt(previous: Option<&str>, messages: &[ChatMessage]) -> String {
+    let mut prompt = String::from(
+        "Summarize the conversation excerpt below so it can replace the original messages \
+as context for the rest of the conversation. Keep facts, decisions, names, code identifiers \
+and open questions; leave out pleasantries. Reply with the summary only.\n\n",
+    );
+    if let Some(previous) = previous {
+        prompt.push_str("Summary of what came before, to be extended:\n");
+        prompt.push_str(previous);
+        prompt.push_str("\n\n");
+    }
+    prompt.push_str("Messages:\n");
+    for msg in messages {
+        prompt.push_str(&format!("{}: {}\n\n", msg.role, msg.content));
+    }
+    prompt
+}
+
+/// About four characters per token
+pub fn estimate_text_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(4)
+}
+
+/// Text estimate plus a fixed cost per image
+pub fn estimate_tokens(msg: &ChatMessage) -> u64 {
+    estimate_text_tokens(&msg.content) + msg.images.len() as u64 * IMAGE_TOKENS
+}
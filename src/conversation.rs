@@ -0,0 +1,187 @@
+use crate::img_utils::GeneratedImage;
+use crate::storage;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_TOKENS_FINISH_REASON: &str = "MAX_TOKENS";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+    /// Thought summaries for model turns; never sent back to the API
+    #[serde(default)]
+    pub thoughts: Option<String>,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+    #[serde(default)]
+    pub images: Vec<GeneratedImage>,
+    /// Model that produced a model turn
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Pinned messages are always sent as context
+    #[serde(default)]
+    pub pinned: bool,
+    /// Seconds since the Unix epoch; 0 for messages saved before this was recorded
+    #[serde(default)]
+    pub created_at: u64,
+    /// How long the model took to answer, for model turns
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+}
+
+impl ChatMessage {
+    pub fn user(content: String) -> Self {
+        Self {
+            role: "user".to_string(),
+            content,
+            thoughts: None,
+            finish_reason: None,
+            images: Vec::new(),
+            model: None,
+            pinned: false,
+            created_at: storage::now_secs(),
+            latency_ms: None,
+        }
+    }
+
+    pub fn was_truncated(&self) -> bool {
+        self.finish_reason.as_deref() == Some(MAX_TOKENS_FINISH_REASON)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TurnNode {
+    message: ChatMessage,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    /// Index into `children` of the branch on the active path
+    active_child: usize,
+}
+
+/// Every version of a conversation: edits and regenerations become sibling branches.
+///
+/// Nodes are never removed except by `clear`, so node ids stay valid for the
+/// lifetime of the tree.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConversationTree {
+    nodes: Vec<TurnNode>,
+    roots: Vec<usize>,
+    active_root: usize,
+}
+
+impl ConversationTree {
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn get(&self, node: usize) -> Option<&ChatMessage> {
+        self.nodes.get(node).map(|n| &n.message)
+    }
+
+    pub fn get_mut(&mut self, node: usize) -> Option<&mut ChatMessage> {
+        self.nodes.get_mut(node).map(|n| &mut n.message)
+    }
+
+    pub fn parent(&self, node: usize) -> Option<usize> {
+        self.nodes.get(node).and_then(|n| n.parent)
+    }
+
+    /// Every message in the tree, including inactive branches
+    pub fn all_messages(&self) -> impl Iterator<Item = &ChatMessage> {
+        self.nodes.iter().map(|n| &n.message)
+    }
+
+    /// Node ids from the root to the leaf, following the selected branch at each level
+    pub fn active_path(&self) -> Vec<usize> {
+        let mut path = Vec::new();
+        let mut current = self.roots.get(self.active_root).copied();
+        while let Some(node) = current {
+            path.push(node);
+            let n = &self.nodes[node];
+            current = n.children.get(n.active_child).copied();
+        }
+        path
+    }
+
+    pub fn active_leaf(&self) -> Option<usize> {
+        self.active_path().last().copied()
+    }
+
+    /// Node ids from the root down to and including `node`; only this path is sent to the API
+    pub fn path_to(&self, node: Option<usize>) -> Vec<usize> {
+        let mut path = Vec::new();
+        let mut current = node;
+        while let Some(id) = current {
+            path.push(id);
+            current = self.nodes[id].parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Adds `message` as a new child of `parent` (or a new root) and makes it active
+    pub fn add_child(&mut self, parent: Option<usize>, message: ChatMessage) -> usize {
+        let id = self.nodes.len();
+        self.nodes.push(TurnNode {
+            message,
+            parent,
+            children: Vec::new(),
+            active_child: 0,
+        });
+
+        match parent {
+            Some(parent) => self.nodes[parent].children.push(id),
+            None => self.roots.push(id),
+        }
+        self.activate(id);
+        id
+    }
+
+    /// Makes sure every ancestor of `node` selects the branch leading to it
+    pub fn activate(&mut self, node: usize) {
+        let mut child = node;
+        while let Some(parent) = self.nodes[child].parent {
+            let p = &mut self.nodes[parent];
+            if let Some(pos) = p.children.iter().position(|&c| c == child) {
+                p.active_child = pos;
+            }
+            child = parent;
+        }
+        if let Some(pos) = self.roots.iter().position(|&r| r == child) {
+            self.active_root = pos;
+        }
+    }
+
+    fn siblings(&self, node: usize) -> &[usize] {
+        match self.nodes[node].parent {
+            Some(parent) => &self.nodes[parent].children,
+            None => &self.roots,
+        }
+    }
+
+    /// 1-based position of `node` among its siblings, and the number of siblings
+    pub fn sibling_position(&self, node: usize) -> (usize, usize) {
+        let siblings = self.siblings(node);
+        let pos = siblings.iter().position(|&s| s == node).unwrap_or(0);
+        (pos + 1, siblings.len())
+    }
+
+    /// Switches the active branch to the previous (-1) or next (+1) sibling of `node`
+    pub fn select_sibling(&mut self, node: usize, offset: isize) {
+        let siblings = self.siblings(node);
+        let Some(pos) = siblings.iter().position(|&s| s == node) else {
+            return;
+        };
+        let target = pos as isize + offset;
+        if target < 0 || target as usize >= siblings.len() {
+            return;
+        }
+
+        let target = siblings[target as usize];
+        self.activate(target);
+    }
+}
@@ -0,0 +1,416 @@
+use crate::storage;
+use base64::{engine::general_purpose, Engine as _};
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, RequestBuilder, Response, Url};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+const MAX_RECORDS: usize = 30;
+/// Request bodies are kept for resending up to this total; inline images make them large,
+/// so the bodies of older requests are dropped first
+const MAX_KEPT_BODY_BYTES: usize = 32 * 1024 * 1024;
+/// Raw responses are cut after this many bytes
+const MAX_RESPONSE_BYTES: usize = 512 * 1024;
+const REDACTED: &str = "REDACTED";
+/// Headers and query parameters whose values are credentials
+const SECRET_NAMES: [&str; 3] = ["key", "x-goog-api-key", "authorization"];
+
+static RECORDS: Mutex<VecDeque<RequestRecord>> = Mutex::new(VecDeque::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// Every API key in use, scrubbed from error messages and logged responses
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+/// Shorter values are not treated as keys, so ordinary words are never scrubbed
+const MIN_SECRET_LEN: usize = 8;
+
+/// The request as sent, credentials included; only used to resend it
+#[derive(Clone)]
+struct SentRequest {
+    method: Method,
+    url: Url,
+    headers: HeaderMap,
+    body: Option<Vec<u8>>,
+    /// The body was dropped to stay under `MAX_KEPT_BODY_BYTES`
+    body_dropped: bool,
+}
+
+/// One HTTP request to a model API and what came back
+#[derive(Clone)]
+pub struct RequestRecord {
+    pub id: u64,
+    /// Id of the record this one resent
+    pub resend_of: Option<u64>,
+    pub created_at: u64,
+    pub model: Option<String>,
+    pub status: Option<u16>,
+    /// Until the response headers arrived
+    pub time_to_first_byte: Option<Duration>,
+    /// Until the whole body was read
+    pub total: Option<Duration>,
+    pub body_bytes: usize,
+    /// Decoded size of every inline image in the body
+    pub image_bytes: Vec<usize>,
+    /// Raw response body; the `data:` payloads of a stream, one per line
+    pub response: String,
+    pub error: Option<String>,
+    request: SentRequest,
+}
+
+impl RequestRecord {
+    pub fn method(&self) -> &str {
+        self.request.method.as_str()
+    }
+
+    /// URL with credentials in the query replaced
+    pub fn endpoint(&self) -> String {
+        let mut url = self.request.url.clone();
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(name, value)| {
+                let value = if is_secret(&name) {
+                    REDACTED.to_string()
+                } else {
+                    value.into_owned()
+                };
+                (name.into_owned(), value)
+            })
+            .collect();
+        if !pairs.is_empty() {
+            url.query_pairs_mut().clear().extend_pairs(pairs);
+        }
+        url.to_string()
+    }
+
+    /// Header names and values with credentials replaced
+    pub fn headers(&self) -> Vec<(String, String)> {
+        self.request
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if is_secret(name.as_str()) {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    /// False once the body was dropped from the log
+    pub fn can_resend(&self) -> bool {
+        !self.request.body_dropped
+    }
+
+    pub fn body(&self) -> Option<String> {
+        self.request
+            .body
+            .as_ref()
+            .map(|body| String::from_utf8_lossy(body).into_owned())
+    }
+
+    /// A shell command sending the same request, with credentials redacted
+    pub fn curl_command(&self) -> String {
+        let mut command = format!(
+            "curl -X {} {}",
+            self.method(),
+            shell_quote(&self.endpoint())
+        );
+        for (name, value) in self.headers() {
+            command.push_str(&format!(
+                " \\\n  -H {}",
+                shell_quote(&format!("{}: {}", name, value))
+            ));
+        }
+        if let Some(body) = self.body() {
+            command.push_str(&format!(" \\\n  --data-binary @- <<'JSON'\n{}\nJSON", body));
+        } else if self.request.body_dropped {
+            command.push_str(" \\\n  --data-binary @body.json  # the body was too large to keep");
+        }
+        command
+    }
+}
+
+/// Tracks one request from sending until its body has been read
+pub struct Recorder {
+    id: u64,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Reads the body as text and completes the record with it
+    pub async fn text(&self, res: Response) -> Result<String, reqwest::Error> {
+        match res.text().await {
+            Ok(text) => {
+                self.finish(&text);
+                Ok(text)
+            }
+            Err(e) => {
+                self.fail(&e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Completes the record with an already read body
+    pub fn finish(&self, response: &str) {
+        let total = self.started.elapsed();
+        update(self.id, |record| {
+            record.total = Some(total);
+            record.response = truncate(&redact(response));
+        });
+    }
+
+    pub fn fail(&self, error: &str) {
+        let total = self.started.elapsed();
+        update(self.id, |record| {
+            record.total = Some(total);
+            record.error = Some(redact(error));
+        });
+    }
+}
+
+/// e.g. "512 B", "12 KiB" or "1.4 MiB"
+pub fn size_label(bytes: usize) -> String {
+    let kib = bytes as f64 / 1024.0;
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if kib < 1024.0 {
+        format!("{:.0} KiB", kib)
+    } else {
+        format!("{:.1} MiB", kib / 1024.0)
+    }
+}
+
+/// Adds a key to the values `redact` removes
+pub fn register_secret(secret: &str) {
+    let secret = secret.trim();
+    if secret.len() < MIN_SECRET_LEN {
+        return;
+    }
+    if let Ok(mut secrets) = SECRETS.write() {
+        if !secrets.iter().any(|known| known == secret) {
+            secrets.push(secret.to_string());
+        }
+    }
+}
+
+/// Replaces every registered key, and the value of any `key=` query parameter, in text
+/// that is about to be shown or logged
+pub fn redact(text: &str) -> String {
+    let mut text = text.to_string();
+    if let Ok(secrets) = SECRETS.read() {
+        for secret in secrets.iter() {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+    }
+
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    while let Some(pos) = rest.find("key=") {
+        let in_query = rest[..pos].ends_with(['?', '&']);
+        redacted.push_str(&rest[..pos + "key=".len()]);
+        rest = &rest[pos + "key=".len()..];
+        if in_query {
+            let end = rest
+                .find(|c: char| c == '&' || c == '"' || c == '\'' || c.is_whitespace())
+                .unwrap_or(rest.len());
+            if end > 0 {
+                redacted.push_str(REDACTED);
+            }
+            rest = &rest[end..];
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+fn is_secret(name: &str) -> bool {
+    SECRET_NAMES
+        .iter()
+        .any(|secret| secret.eq_ignore_ascii_case(name))
+}
+
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+fn truncate(text: &str) -> String {
+    if text.len() <= MAX_RESPONSE_BYTES {
+        return text.to_string();
+    }
+    let mut end = MAX_RESPONSE_BYTES;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n… ({} bytes in total)", &text[..end], text.len())
+}
+
+/// Decoded sizes of the base64 images in a Gemini or OpenAI request body
+fn image_sizes(body: &[u8]) -> Vec<usize> {
+    fn collect(value: &Value, sizes: &mut Vec<usize>) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    let data = match (key.as_str(), value) {
+                        ("inline_data" | "inlineData", _) => {
+                            value.get("data").and_then(|v| v.as_str())
+                        }
+                        ("url", Value::String(url)) if url.starts_with("data:") => {
+                            url.split_once(";base64,").map(|(_, data)| data)
+                        }
+                        _ => None,
+                    };
+                    match data {
+                        Some(data) => sizes.push(
+                            general_purpose::STANDARD
+                                .decode(data)
+                                .map_or(data.len() * 3 / 4, |bytes| bytes.len()),
+                        ),
+                        None => collect(value, sizes),
+                    }
+                }
+            }
+            Value::Array(items) => items.iter().for_each(|item| collect(item, sizes)),
+            _ => {}
+        }
+    }
+
+    let mut sizes = Vec::new();
+    if let Ok(value) = serde_json::from_slice::<Value>(body) {
+        collect(&value, &mut sizes);
+    }
+    sizes
+}
+
+fn update(id: u64, change: impl FnOnce(&mut RequestRecord)) {
+    if let Ok(mut records) = RECORDS.lock() {
+        if let Some(record) = records.iter_mut().find(|r| r.id == id) {
+            change(record);
+        }
+    }
+}
+
+/// Sends the request and starts its record; reqwest returns as soon as the response
+/// headers are in, which is taken as the time to first byte
+pub async fn send(
+    builder: RequestBuilder,
+    model: Option<&str>,
+) -> Result<(Response, Recorder), reqwest::Error> {
+    let (client, request) = builder.build_split();
+    let request = request?;
+    let sent = SentRequest {
+        method: request.method().clone(),
+        url: request.url().clone(),
+        headers: request.headers().clone(),
+        body: request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(<[u8]>::to_vec),
+        body_dropped: false,
+    };
+    execute(client, request, sent, model.map(str::to_string), None).await
+}
+
+async fn execute(
+    client: Client,
+    request: reqwest::Request,
+    sent: SentRequest,
+    model: Option<String>,
+    resend_of: Option<u64>,
+) -> Result<(Response, Recorder), reqwest::Error> {
+    let recorder = Recorder {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        started: Instant::now(),
+    };
+    let body = sent.body.as_deref().unwrap_or_default();
+    let record = RequestRecord {
+        id: recorder.id,
+        resend_of,
+        created_at: storage::now_secs(),
+        model,
+        status: None,
+        time_to_first_byte: None,
+        total: None,
+        body_bytes: body.len(),
+        image_bytes: image_sizes(body),
+        response: String::new(),
+        error: None,
+        request: sent,
+    };
+    if let Ok(mut records) = RECORDS.lock() {
+        records.push_back(record);
+        while records.len() > MAX_RECORDS {
+            records.pop_front();
+        }
+        let mut kept = 0;
+        for record in records.iter_mut().rev() {
+            let Some(ref body) = record.request.body else {
+                continue;
+            };
+            if kept + body.len() > MAX_KEPT_BODY_BYTES {
+                record.request.body = None;
+                record.request.body_dropped = true;
+            } else {
+                kept += body.len();
+            }
+        }
+    }
+
+    match client.execute(request).await {
+        Ok(res) => {
+            let status = res.status().as_u16();
+            let elapsed = recorder.started.elapsed();
+            update(recorder.id, |record| {
+                record.status = Some(status);
+                record.time_to_first_byte = Some(elapsed);
+            });
+            Ok((res, recorder))
+        }
+        Err(e) => {
+            recorder.fail(&e.to_string());
+            Err(e)
+        }
+    }
+}
+
+/// Runs `f` on the recorded requests, oldest first, without copying their bodies; requests in
+/// flight wait for it to return, so `f` must not call back into this module
+pub fn with_records<R>(f: impl FnOnce(&VecDeque<RequestRecord>) -> R) -> R {
+    let records = RECORDS.lock().unwrap_or_else(|e| e.into_inner());
+    f(&records)
+}
+
+pub fn clear() {
+    if let Ok(mut records) = RECORDS.lock() {
+        records.clear();
+    }
+}
+
+/// Sends a recorded request again exactly as it was; the result becomes a new record
+pub fn spawn_resend(record: &RequestRecord) -> JoinHandle<Result<(), String>> {
+    let sent = record.request.clone();
+    let model = record.model.clone();
+    let resend_of = record.id;
+    std::thread::spawn(move || {
+        Runtime::new().map_err(|e| e.to_string())?.block_on(async {
+            let client = Client::new();
+            let mut builder = client
+                .request(sent.method.clone(), sent.url.clone())
+                .headers(sent.headers.clone());
+            if let Some(ref body) = sent.body {
+                builder = builder.body(body.clone());
+            }
+            let request = builder.build().map_err(|e| e.to_string())?;
+            let (res, recorder) = execute(client, request, sent, model, Some(resend_of))
+                .await
+                .map_err(|e| e.to_string())?;
+            recorder.text(res).await.map_err(|e| e.to_string())?;
+            Ok(())
+        })
+    })
+}
@@ -0,0 +1,134 @@
+use base64::{engine::general_purpose, Engine as _};
+use rfd::FileDialog;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CACHE_TTL_MINUTES: u64 = 60;
+
+/// A document sent ahead of the conversation, either with every request or through a cache
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub name: String,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    pub fn to_part(&self) -> Value {
+        json!({
+            "inline_data": {
+                "mime_type": self.mime_type,
+                "data": general_purpose::STANDARD.encode(&self.data)
+            }
+        })
+    }
+
+    pub fn size_label(&self) -> String {
+        let kib = self.data.len() as f64 / 1024.0;
+        if kib < 1024.0 {
+            format!("{:.0} KiB", kib)
+        } else {
+            format!("{:.1} MiB", kib / 1024.0)
+        }
+    }
+}
+
+/// MIME type Gemini expects for a document, based on its extension
+fn document_mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("pdf") => "application/pdf",
+        Some("md") => "text/md",
+        Some("html") | Some("htm") => "text/html",
+        Some("csv") => "text/csv",
+        Some("xml") => "text/xml",
+        Some("rtf") => "text/rtf",
+        Some("js") => "text/javascript",
+        Some("py") => "text/x-python",
+        _ => "text/plain",
+    }
+}
+
+/// Lets the user pick one or more documents; an empty list means the dialog was cancelled
+pub fn pick_attachments() -> Result<Vec<Attachment>, String> {
+    let Some(paths) = FileDialog::new()
+        .add_filter(
+            "document",
+            &[
+                "pdf", "txt", "md", "html", "htm", "csv", "xml", "rtf", "json", "js", "py", "rs",
+            ],
+        )
+        .pick_files()
+    else {
+        return Ok(Vec::new());
+    };
+
+    paths
+        .iter()
+        .map(|path| {
+            let data =
+                fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            Ok(Attachment {
+                name: path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| path.display().to_string()),
+                mime_type: document_mime_type(path).to_string(),
+                data,
+            })
+        })
+        .collect()
+}
+
+/// System instruction and documents sent ahead of the conversation
+#[derive(Clone, Debug, Default)]
+pub struct SharedContext {
+    pub system_instruction: String,
+    pub attachments: Vec<Attachment>,
+    /// `cachedContents/...` resource that already holds the instruction and documents
+    pub cached_content: Option<String>,
+}
+
+impl SharedContext {
+    pub fn is_empty(&self) -> bool {
+        self.system_instruction.trim().is_empty() && self.attachments.is_empty()
+    }
+
+    /// The same context, sent by reference to `cache` instead of inline
+    pub fn via_cache(cache: &ContextCache) -> Self {
+        Self {
+            cached_content: Some(cache.name.clone()),
+            ..Self::default()
+        }
+    }
+}
+
+/// A `cachedContents` resource created for the current session
+#[derive(Clone, Debug)]
+pub struct ContextCache {
+    pub name: String,
+    /// Caches only work with the model they were created for
+    pub model: String,
+    pub token_count: Option<u64>,
+    pub expires_at: Instant,
+}
+
+impl ContextCache {
+    pub fn remaining(&self) -> Duration {
+        self.expires_at.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    pub fn ttl_label(&self) -> String {
+        let secs = self.remaining().as_secs();
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
@@ -0,0 +1,394 @@
+use crate::conversation::{ChatMessage, ConversationTree};
+use crate::img_utils::GeneratedImage;
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+use rfd::FileDialog;
+use serde_json::{json, Value};
+use std::fs;
+
+/// File formats a conversation can be written to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Markdown,
+    /// Self-contained page with rendered markdown and embedded images
+    Html,
+    /// Gemini `contents` JSON; the only format that can be imported again
+    Json,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [
+        ExportFormat::Markdown,
+        ExportFormat::Html,
+        ExportFormat::Json,
+    ];
+
+    /// Matches the argument of `/export`, e.g. "md"
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            "html" | "htm" => Some(ExportFormat::Html),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn short_name(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Html => "html",
+            ExportFormat::Json => "json",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "Markdown",
+            ExportFormat::Html => "HTML",
+            ExportFormat::Json => "JSON",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        self.short_name()
+    }
+}
+
+/// What gets exported: the active branch plus the context it was produced with
+pub struct ExportedConversation<'a> {
+    pub title: &'a str,
+    pub model: &'a str,
+    pub system_instruction: &'a str,
+    pub messages: Vec<&'a ChatMessage>,
+}
+
+/// A conversation read back from a JSON export
+pub struct ImportedConversation {
+    pub model: Option<String>,
+    pub system_instruction: String,
+    pub conversation: ConversationTree,
+}
+
+fn role_heading(msg: &ChatMessage) -> String {
+    let role = match msg.role.as_str() {
+        "user" => "User",
+        "model" => "Model",
+        other => other,
+    };
+    match msg.model {
+        Some(ref model) => format!("{} ({})", role, model),
+        None => role.to_string(),
+    }
+}
+
+fn data_url(image: &GeneratedImage) -> String {
+    format!("data:{};base64,{}", image.mime_type, image.to_base64())
+}
+
+/// Markdown transcript; generated images are embedded as data URLs
+pub fn to_markdown(export: &ExportedConversation) -> String {
+    let mut out = format!("# {}\n", export.title);
+    if !export.system_instruction.trim().is_empty() {
+        out.push_str(&format!(
+            "\n## System instruction\n\n{}\n",
+            export.system_instruction.trim_end()
+        ));
+    }
+    for msg in &export.messages {
+        out.push_str(&format!(
+            "\n## {}\n\n{}\n",
+            role_heading(msg),
+            msg.content.trim_end()
+        ));
+        for image in &msg.images {
+            out.push_str(&format!("\n![generated image]({})\n", data_url(image)));
+        }
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders markdown; raw HTML in the text is shown as text rather than interpreted, and
+/// links or images with other schemes than http, https and mailto lose their destination
+fn markdown_to_html(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+    let parser = Parser::new_ext(markdown, options).map(|event| match event {
+        Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        other => other,
+    });
+    let mut out = String::new();
+    html::push_html(&mut out, parser);
+    out
+}
+
+/// `url` if it is relative or uses http, https or mailto; otherwise empty, so e.g. a
+/// `javascript:` link in a model answer can't run in the exported page
+fn safe_url(url: CowStr<'_>) -> CowStr<'_> {
+    // Browsers skip whitespace and control characters when reading the scheme
+    let compact: String = url
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace() && !c.is_control())
+        .collect();
+    let scheme = compact
+        .find([':', '/', '?', '#'])
+        .filter(|&end| compact[end..].starts_with(':'))
+        .map(|end| compact[..end].to_ascii_lowercase());
+    match scheme.as_deref() {
+        None | Some("http" | "https" | "mailto") => url,
+        Some(_) => CowStr::Borrowed(""),
+    }
+}
+
+const HTML_STYLE: &str = "body{font-family:system-ui,sans-serif;max-width:860px;margin:2em auto;\
+padding:0 1em;line-height:1.5;color:#1f2328}\
+section{border-top:1px solid #d0d7de;padding:.5em 0}\
+h2{font-size:1em;color:#57606a}\
+section.user{background:#f6f8fa;padding:.5em 1em;border-radius:6px}\
+pre{background:#f6f8fa;padding:.75em;overflow-x:auto;border-radius:6px}\
+code{font-family:ui-monospace,monospace}\
+table{border-collapse:collapse}td,th{border:1px solid #d0d7de;padding:.25em .5em}\
+img{max-width:100%}details{color:#57606a}";
+
+/// Self-contained HTML page with rendered markdown and embedded images
+pub fn to_html(export: &ExportedConversation) -> String {
+    let title = escape_html(export.title);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        title, HTML_STYLE, title
+    );
+    if !export.system_instruction.trim().is_empty() {
+        out.push_str(&format!(
+            "<details>\n<summary>System instruction</summary>\n{}</details>\n",
+            markdown_to_html(export.system_instruction)
+        ));
+    }
+    for msg in &export.messages {
+        out.push_str(&format!(
+            "<section class=\"{}\">\n<h2>{}</h2>\n",
+            escape_html(&msg.role),
+            escape_html(&role_heading(msg))
+        ));
+        if let Some(ref thoughts) = msg.thoughts {
+            out.push_str(&format!(
+                "<details>\n<summary>Reasoning</summary>\n{}</details>\n",
+                markdown_to_html(thoughts)
+            ));
+        }
+        out.push_str(&markdown_to_html(&msg.content));
+        for image in &msg.images {
+            out.push_str(&format!(
+                "<p><img src=\"{}\" alt=\"generated image\"></p>\n",
+                data_url(image)
+            ));
+        }
+        out.push_str("</section>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// The conversation as a Gemini request body: `contents`, plus the system instruction
+pub fn to_contents_json(export: &ExportedConversation) -> Value {
+    let contents: Vec<Value> = export
+        .messages
+        .iter()
+        .map(|msg| {
+            let mut parts = Vec::new();
+            if let Some(ref thoughts) = msg.thoughts {
+                parts.push(json!({ "text": thoughts, "thought": true }));
+            }
+            if !msg.content.is_empty() {
+                parts.push(json!({ "text": msg.content }));
+            }
+            for image in &msg.images {
+                parts.push(json!({
+                    "inline_data": {
+                        "mime_type": image.mime_type,
+                        "data": image.to_base64()
+                    }
+                }));
+            }
+            json!({ "role": msg.role, "parts": parts })
+        })
+        .collect();
+
+    let mut body = json!({
+        "model": export.model,
+        "contents": contents,
+    });
+    if !export.system_instruction.trim().is_empty() {
+        body["systemInstruction"] = json!({
+            "parts": [{ "text": export.system_instruction }]
+        });
+    }
+    body
+}
+
+/// Reads a `contents` JSON export back into a single-branch conversation
+pub fn from_contents_json(body: &Value) -> Result<ImportedConversation, String> {
+    let contents = body
+        .get("contents")
+        .and_then(|v| v.as_array())
+        .ok_or("The file has no \"contents\" array")?;
+    let model = body
+        .get("model")
+        .and_then(|v| v.as_str())
+        .map(|m| m.trim_start_matches("models/").to_string())
+        .filter(|m| !m.is_empty());
+
+    let mut conversation = ConversationTree::default();
+    let mut parent = None;
+    for (i, content) in contents.iter().enumerate() {
+        let role = content
+            .get("role")
+            .and_then(|v| v.as_str())
+            .unwrap_or("user");
+        let parts = content
+            .get("parts")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| format!("Message {} has no parts", i + 1))?;
+
+        let mut msg = ChatMessage::user(String::new());
+        msg.role = role.to_string();
+        if role == "model" {
+            msg.model = model.clone();
+        }
+        let mut thoughts = String::new();
+        for part in parts {
+            let inline = part.get("inline_data").or_else(|| part.get("inlineData"));
+            if let Some(inline) = inline {
+                let mime_type = inline
+                    .get("mime_type")
+                    .or_else(|| inline.get("mimeType"))
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("image/png");
+                let data = inline.get("data").and_then(|v| v.as_str()).unwrap_or("");
+                let image = GeneratedImage::from_base64(mime_type, data)
+                    .map_err(|e| format!("Message {}: {}", i + 1, e))?;
+                msg.images.push(image);
+            } else if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                let is_thought = part.get("thought").and_then(|v| v.as_bool()) == Some(true);
+                let target = if is_thought {
+                    &mut thoughts
+                } else {
+                    &mut msg.content
+                };
+                target.push_str(text);
+            }
+        }
+        if !thoughts.is_empty() {
+            msg.thoughts = Some(thoughts);
+        }
+        parent = Some(conversation.add_child(parent, msg));
+    }
+
+    let system_instruction = body
+        .pointer("/systemInstruction/parts")
+        .or_else(|| body.pointer("/system_instruction/parts"))
+        .and_then(|v| v.as_array())
+        .map(|parts| {
+            parts
+                .iter()
+                .filter_map(|p| p.get("text").and_then(|v| v.as_str()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .unwrap_or_default();
+
+    Ok(ImportedConversation {
+        model,
+        system_instruction,
+        conversation,
+    })
+}
+
+/// File name made from the session title, safe on every platform
+fn file_stem(title: &str) -> String {
+    let stem: String = title
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .take(40)
+        .collect();
+    let stem = stem.trim_matches('-');
+    if stem.is_empty() {
+        "conversation".to_string()
+    } else {
+        stem.to_string()
+    }
+}
+
+/// Asks for a destination and writes the conversation there; returns false if cancelled
+pub fn export_dialog(format: ExportFormat, export: &ExportedConversation) -> Result<bool, String> {
+    let Some(path) = FileDialog::new()
+        .add_filter(format.label(), &[format.extension()])
+        .set_file_name(format!(
+            "{}.{}",
+            file_stem(export.title),
+            format.extension()
+        ))
+        .save_file()
+    else {
+        return Ok(false);
+    };
+
+    let text = match format {
+        ExportFormat::Markdown => to_markdown(export),
+        ExportFormat::Html => to_html(export),
+        ExportFormat::Json => serde_json::to_string_pretty(&to_contents_json(export))
+            .map_err(|e| format!("Failed to serialize conversation: {}", e))?,
+    };
+    fs::write(&path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(true)
+}
+
+/// Asks for a JSON export and reads it; `Ok(None)` if the dialog was cancelled
+pub fn import_dialog() -> Result<Option<ImportedConversation>, String> {
+    let Some(path) = FileDialog::new().add_filter("JSON", &["json"]).pick_file() else {
+        return Ok(None);
+    };
+
+    let text = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let body: Value = serde_json::from_str(&text)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    from_contents_json(&body).map(Some)
+}
@@ -1,4 +1,3 @@
-use egui;
 use std::sync::Arc;
 
 pub fn setup_custom_fonts(ctx: &egui::Context) {
@@ -25,7 +24,6 @@ pub fn setup_custom_fonts(ctx: &egui::Context) {
         .or_default()
         .insert(0, "noto_sans_cjk".to_owned());
     ctx.set_fonts(fonts);
-    return;
 
     // Option 2: Load system fonts
     // This tries to find and load Asian-compatible fonts from the system
@@ -1,9 +1,116 @@
 use arboard::Clipboard;
+use base64::{engine::general_purpose, Engine as _};
 use eframe::egui;
 use egui::ColorImage;
 use rfd::FileDialog;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
 use std::fs;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_IMAGE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// An image returned by the model, kept both encoded (for saving) and decoded (for display)
+#[derive(Clone, Debug)]
+pub struct GeneratedImage {
+    /// Unique per process; used as the texture cache key
+    pub id: u64,
+    pub mime_type: String,
+    pub bytes: Vec<u8>,
+    pub image: ColorImage,
+}
+
+/// On-disk form of a `GeneratedImage`: the original encoded bytes as base64
+#[derive(Serialize, Deserialize)]
+struct EncodedImage {
+    mime_type: String,
+    data: String,
+}
+
+impl Serialize for GeneratedImage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        EncodedImage {
+            mime_type: self.mime_type.clone(),
+            data: self.to_base64(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for GeneratedImage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = EncodedImage::deserialize(deserializer)?;
+        GeneratedImage::from_base64(&encoded.mime_type, &encoded.data)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl GeneratedImage {
+    pub fn to_base64(&self) -> String {
+        general_purpose::STANDARD.encode(&self.bytes)
+    }
+
+    pub fn from_base64(mime_type: &str, data: &str) -> Result<Self, String> {
+        let bytes = general_purpose::STANDARD
+            .decode(data)
+            .map_err(|e| format!("Invalid image data: {}", e))?;
+        Self::from_bytes(mime_type, bytes)
+    }
+
+    pub fn from_bytes(mime_type: &str, bytes: Vec<u8>) -> Result<Self, String> {
+        let image = load_color_image_from_bytes(&bytes)?;
+        Ok(Self {
+            id: NEXT_IMAGE_ID.fetch_add(1, Ordering::Relaxed),
+            mime_type: mime_type.to_string(),
+            bytes,
+            image,
+        })
+    }
+
+    pub fn file_extension(&self) -> &str {
+        match self.mime_type.as_str() {
+            "image/jpeg" => "jpg",
+            "image/webp" => "webp",
+            "image/gif" => "gif",
+            _ => "png",
+        }
+    }
+
+    /// Asks for a destination and writes the original encoded bytes there
+    pub fn save_to_file(&self) -> Result<(), String> {
+        let extension = self.file_extension();
+        let Some(path) = FileDialog::new()
+            .add_filter("image", &[extension])
+            .set_file_name(format!("gemini-image-{}.{}", self.id, extension))
+            .save_file()
+        else {
+            return Ok(());
+        };
+
+        fs::write(&path, &self.bytes).map_err(|e| format!("Failed to save image: {}", e))
+    }
+
+    pub fn copy_to_clipboard(&self) -> Result<(), String> {
+        let mut clipboard =
+            Clipboard::new().map_err(|e| format!("Failed to create clipboard: {}", e))?;
+        let [width, height] = self.image.size;
+        let rgba: Vec<u8> = self
+            .image
+            .pixels
+            .iter()
+            .flat_map(|color| color.to_srgba_unmultiplied())
+            .collect();
+
+        clipboard
+            .set_image(arboard::ImageData {
+                width,
+                height,
+                bytes: Cow::Owned(rgba),
+            })
+            .map_err(|e| format!("Failed to copy image: {}", e))
+    }
+}
 
 pub fn pick_image_file() -> Result<ColorImage, String> {
     let file = FileDialog::new()
@@ -21,6 +128,11 @@ pub fn pick_image_file() -> Result<ColorImage, String> {
     }
 }
 
+pub fn load_color_image_from_path(path: &Path) -> Result<ColorImage, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    load_color_image_from_bytes(&bytes)
+}
+
 pub fn load_color_image_from_bytes(bytes: &[u8]) -> Result<ColorImage, String> {
     let img = image::load_from_memory(bytes)
         .map_err(|e| format!("Failed to load image: {}", e))?
@@ -56,31 +168,26 @@ pub fn image_from_clipboard(ctx: &egui::Context) -> Result<ColorImage, String> {
         Clipboard::new().map_err(|e| format!("Failed to create clipboard: {}", e))?;
 
     match clipboard.get_image() {
-        Ok(img_data) => {
-            return process_image_data(ctx, img_data);
-        }
+        Ok(img_data) => process_image_data(ctx, img_data),
         Err(_) => match clipboard.get_text() {
             Ok(text) => {
                 let cleaned_text = text.trim().trim_matches('"');
                 let path = Path::new(cleaned_text);
-                if path.exists() && is_image_file(&path) {
+                if path.exists() && is_image_file(path) {
                     return load_image_from_file(ctx, cleaned_text);
-                } else {
-                    if let Some(file_path) = extract_file_path_from_text(&text) {
-                        let path = Path::new(&file_path);
-                        if path.exists() && is_image_file(&path) {
-                            return load_image_from_file(ctx, &file_path);
-                        }
+                }
+                if let Some(file_path) = extract_file_path_from_text(&text) {
+                    let path = Path::new(&file_path);
+                    if path.exists() && is_image_file(path) {
+                        return load_image_from_file(ctx, &file_path);
                     }
-                    return Err(format!(
-                        "Clipboard contains text '{}' but not a valid image file path",
-                        text
-                    ));
                 }
+                Err(format!(
+                    "Clipboard contains text '{}' but not a valid image file path",
+                    text
+                ))
             }
-            Err(_) => {
-                return Err("No image found in clipboard.\n\nTo paste an image, try one of these methods:\n1. Right-click an image in a browser and select 'Copy Image'\n2. Take a screenshot (Ctrl+Shift+S)\n3. Copy the file path manually:\n   - Right-click image file → Properties → Copy path\n   - Or hold Shift + Right-click → 'Copy as path'".to_string());
-            }
+            Err(_) => Err("No image found in clipboard.\n\nTo paste an image, try one of these methods:\n1. Right-click an image in a browser and select 'Copy Image'\n2. Take a screenshot (Ctrl+Shift+S)\n3. Copy the file path manually:\n   - Right-click image file → Properties → Copy path\n   - Or hold Shift + Right-click → 'Copy as path'".to_string()),
         },
     }
 }
@@ -89,14 +196,8 @@ fn process_image_data(
     _ctx: &egui::Context,
     img_data: arboard::ImageData,
 ) -> Result<ColorImage, String> {
-    let width = img_data
-        .width
-        .try_into()
-        .map_err(|_| "Invalid image width")?;
-    let height = img_data
-        .height
-        .try_into()
-        .map_err(|_| "Invalid image height")?;
+    let width = img_data.width;
+    let height = img_data.height;
     let mut pixels = img_data.bytes.into_owned();
 
     // Check if all pixels are transparent - had a issue with copying from firefox
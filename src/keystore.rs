@@ -0,0 +1,212 @@
+use crate::storage;
+use base64::{engine::general_purpose, Engine as _};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::num::NonZeroU32;
+
+/// Service name of the entries in the platform secret store
+const SERVICE: &str = "gemini-oxide";
+const ENCRYPTED_FILE: &str = "keys.json";
+/// Read by the command-line modes when keys are in the encrypted file
+pub const PASSPHRASE_VAR: &str = "GEMINI_OXIDE_PASSPHRASE";
+const PBKDF2_ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// Where a provider's API key is kept
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStorage {
+    /// In providers.json as plain text, how keys were stored before
+    #[default]
+    ConfigFile,
+    /// Secret Service on Linux, Keychain on macOS, Credential Manager on Windows
+    Keyring,
+    /// `keys.json`, encrypted with a key derived from a passphrase
+    EncryptedFile,
+}
+
+impl KeyStorage {
+    pub const ALL: [KeyStorage; 3] = [
+        KeyStorage::Keyring,
+        KeyStorage::EncryptedFile,
+        KeyStorage::ConfigFile,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            KeyStorage::Keyring => "System keyring",
+            KeyStorage::EncryptedFile => "Encrypted file",
+            KeyStorage::ConfigFile => "Config file (plain text)",
+        }
+    }
+}
+
+/// Reads the key of provider `id`; `Ok(None)` if none was stored
+pub fn load(
+    storage: KeyStorage,
+    id: &str,
+    passphrase: Option<&str>,
+) -> Result<Option<String>, String> {
+    match storage {
+        KeyStorage::ConfigFile => Ok(None),
+        KeyStorage::Keyring => match entry(id)?.get_password() {
+            Ok(key) => Ok(Some(key)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(format!(
+                "Failed to read the key from the system keyring: {}",
+                e
+            )),
+        },
+        KeyStorage::EncryptedFile => {
+            let file = EncryptedKeys::read()?;
+            let Some(sealed) = file.keys.get(id) else {
+                return Ok(None);
+            };
+            let passphrase = passphrase.ok_or("The encrypted key file is locked")?;
+            file.open(sealed, passphrase).map(Some)
+        }
+    }
+}
+
+/// Stores the key of provider `id`, replacing an earlier one
+pub fn store(
+    storage: KeyStorage,
+    id: &str,
+    key: &str,
+    passphrase: Option<&str>,
+) -> Result<(), String> {
+    match storage {
+        KeyStorage::ConfigFile => Ok(()),
+        KeyStorage::Keyring => entry(id)?
+            .set_password(key)
+            .map_err(|e| format!("Failed to store the key in the system keyring: {}", e)),
+        KeyStorage::EncryptedFile => {
+            let passphrase = passphrase
+                .filter(|p| !p.is_empty())
+                .ok_or("A passphrase is needed for the encrypted key file")?;
+            let mut file = EncryptedKeys::read()?;
+            file.check_passphrase(passphrase)?;
+            let sealed = file.seal(key, passphrase)?;
+            file.keys.insert(id.to_string(), sealed);
+            storage::write_json(ENCRYPTED_FILE, &file)
+        }
+    }
+}
+
+/// Removes the key of provider `id`; succeeds if there was none
+pub fn delete(storage: KeyStorage, id: &str) -> Result<(), String> {
+    match storage {
+        KeyStorage::ConfigFile => Ok(()),
+        KeyStorage::Keyring => match entry(id)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(format!(
+                "Failed to remove the key from the system keyring: {}",
+                e
+            )),
+        },
+        KeyStorage::EncryptedFile => {
+            let mut file = EncryptedKeys::read()?;
+            if file.keys.remove(id).is_some() {
+                storage::write_json(ENCRYPTED_FILE, &file)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn entry(id: &str) -> Result<keyring::Entry, String> {
+    keyring::Entry::new(SERVICE, id)
+        .map_err(|e| format!("The system keyring is not available: {}", e))
+}
+
+/// One encrypted key; the nonce is new for every key
+#[derive(Clone, Serialize, Deserialize)]
+struct SealedKey {
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Contents of `keys.json`: AES-256-GCM with a PBKDF2-SHA256 key from the passphrase
+#[derive(Default, Serialize, Deserialize)]
+struct EncryptedKeys {
+    salt: String,
+    iterations: u32,
+    keys: BTreeMap<String, SealedKey>,
+}
+
+impl EncryptedKeys {
+    fn read() -> Result<Self, String> {
+        let mut file: Self = storage::read_json(ENCRYPTED_FILE)?.unwrap_or_default();
+        if file.salt.is_empty() {
+            let mut salt = [0u8; SALT_LEN];
+            SystemRandom::new()
+                .fill(&mut salt)
+                .map_err(|_| "Failed to generate a salt")?;
+            file.salt = general_purpose::STANDARD.encode(salt);
+            file.iterations = PBKDF2_ITERATIONS;
+        }
+        Ok(file)
+    }
+
+    fn cipher(&self, passphrase: &str) -> Result<LessSafeKey, String> {
+        let salt = general_purpose::STANDARD
+            .decode(&self.salt)
+            .map_err(|e| format!("The encrypted key file is damaged: {}", e))?;
+        let iterations =
+            NonZeroU32::new(self.iterations).ok_or("The encrypted key file is damaged")?;
+        let mut key = [0u8; KEY_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| "Failed to derive the key")?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    /// Every key in the file uses the same passphrase, so a new one must open the others
+    fn check_passphrase(&self, passphrase: &str) -> Result<(), String> {
+        match self.keys.values().next() {
+            Some(sealed) => self.open(sealed, passphrase).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    fn seal(&self, key: &str, passphrase: &str) -> Result<SealedKey, String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| "Failed to generate a nonce")?;
+        let mut data = key.as_bytes().to_vec();
+        self.cipher(passphrase)?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+            .map_err(|_| "Failed to encrypt the key")?;
+        Ok(SealedKey {
+            nonce: general_purpose::STANDARD.encode(nonce),
+            ciphertext: general_purpose::STANDARD.encode(data),
+        })
+    }
+
+    fn open(&self, sealed: &SealedKey, passphrase: &str) -> Result<String, String> {
+        let damaged = |_| "The encrypted key file is damaged".to_string();
+        let nonce: [u8; NONCE_LEN] = general_purpose::STANDARD
+            .decode(&sealed.nonce)
+            .map_err(damaged)?
+            .try_into()
+            .map_err(|_| "The encrypted key file is damaged")?;
+        let mut data = general_purpose::STANDARD
+            .decode(&sealed.ciphertext)
+            .map_err(damaged)?;
+        let key = self
+            .cipher(passphrase)?
+            .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+            .map_err(|_| "Wrong passphrase for the encrypted key file")?;
+        String::from_utf8(key.to_vec()).map_err(|_| "The encrypted key file is damaged".to_string())
+    }
+}
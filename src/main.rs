@@ -1,12 +1,38 @@
-#![windows_subsystem = "windows"]
 use eframe::{egui, NativeOptions};
 mod api_client;
+mod batch;
+mod cli;
+mod commands;
+mod compare;
+mod context;
+mod conversation;
+mod diagnostics;
+mod documents;
+mod export;
 mod font_setup;
 mod img_utils;
+mod keystore;
+mod prompt_editor;
+mod providers;
+mod search;
+mod server;
+mod session;
+mod storage;
+mod templates;
+mod terminal;
 mod ui_components;
 use ui_components::{create_viewport_with_icon, UIState};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Any argument selects a headless command; the window is never created for those
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+
+    #[cfg(windows)]
+    hide_own_console();
+
     let imported_img_bytes = include_bytes!("heart_inlineBG.png");
     let custom_viewport = create_viewport_with_icon("GeminiOxide", imported_img_bytes)?;
 
@@ -21,12 +47,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Box::new(|cc| {
             // Load custom fonts for Asian character support
             font_setup::setup_custom_fonts(&cc.egui_ctx);
-            Ok(Box::new(MyApp::default()))
+            let mut app = MyApp::default();
+            app.ui_state.load_provider_settings();
+            app.ui_state.load_model_list();
+            app.ui_state.load_editor_preferences();
+            app.ui_state.load_template_library();
+            Ok(Box::new(app))
         }),
     )?;
     Ok(())
 }
 
+/// The binary is a console program so the command-line modes can print and read stdin on
+/// Windows; when the window was started outside a terminal, the console it got is closed again
+#[cfg(windows)]
+fn hide_own_console() {
+    use windows_sys::Win32::System::Console::{FreeConsole, GetConsoleProcessList};
+    let mut processes = [0u32; 2];
+    // Only this process is attached when Windows created the console for it
+    if unsafe { GetConsoleProcessList(processes.as_mut_ptr(), processes.len() as u32) } == 1 {
+        unsafe { FreeConsole() };
+    }
+}
+
 #[derive(Default)]
 struct MyApp {
     ui_state: UIState,
@@ -43,6 +86,11 @@ impl eframe::App for MyApp {
             self.ui_state.first_frame = false;
         }
 
+        self.ui_state.poll_model_list(ctx);
+        self.ui_state.poll_summary(ctx);
+        self.ui_state.poll_context_cache(ctx);
+        self.ui_state.poll_search_index(ctx);
+        self.ui_state.poll_comparison(ctx);
         self.ui_state.render_error_section(ctx);
 
         egui::CentralPanel::default().show(ctx, |ui| {
@@ -51,8 +99,7 @@ impl eframe::App for MyApp {
                 self.ui_state.render_action_buttons(ui, ctx);
 
             if should_generate_from_input || should_generate_from_button {
-                let prompt_clone = self.ui_state.prompt.clone();
-                self.ui_state.start_async_request(prompt_clone);
+                self.ui_state.submit_prompt(ctx);
             }
 
             self.ui_state.render_response_section(ui, ctx);
@@ -60,5 +107,16 @@ impl eframe::App for MyApp {
 
         self.ui_state.render_loading_indicator(ctx);
         self.ui_state.render_history_window(ctx);
+        self.ui_state.render_settings_window(ctx);
+        self.ui_state.render_providers_window(ctx);
+        self.ui_state.render_compare_window(ctx);
+        self.ui_state.render_diagnostics_window(ctx);
+        self.ui_state.render_documents_window(ctx);
+        self.ui_state.render_templates_window(ctx);
+        self.ui_state.render_template_form(ctx);
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.ui_state.shutdown();
     }
 }
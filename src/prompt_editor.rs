@@ -0,0 +1,123 @@
+use crate::storage;
+use eframe::egui::{InputState, Key, KeyboardShortcut, Modifiers};
+use serde::{Deserialize, Serialize};
+
+const PREFERENCES_FILE: &str = "prompt_editor.json";
+const MAX_HISTORY: usize = 200;
+
+/// Key combination that sends the prompt; the other Enter combination adds a newline
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum SendShortcut {
+    #[default]
+    CtrlEnter,
+    ShiftEnter,
+    Enter,
+}
+
+impl SendShortcut {
+    pub const ALL: [SendShortcut; 3] = [
+        SendShortcut::CtrlEnter,
+        SendShortcut::ShiftEnter,
+        SendShortcut::Enter,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SendShortcut::CtrlEnter => "Ctrl+Enter",
+            SendShortcut::ShiftEnter => "Shift+Enter",
+            SendShortcut::Enter => "Enter",
+        }
+    }
+
+    /// Shortcut the text editor uses for inserting a newline
+    pub fn newline_key(self) -> KeyboardShortcut {
+        match self {
+            SendShortcut::Enter => KeyboardShortcut::new(Modifiers::SHIFT, Key::Enter),
+            _ => KeyboardShortcut::new(Modifiers::NONE, Key::Enter),
+        }
+    }
+
+    /// Consumes the send key press, if there is one, so the editor doesn't see it
+    pub fn consume(self, input: &mut InputState) -> bool {
+        match self {
+            SendShortcut::CtrlEnter => input.consume_key(Modifiers::COMMAND, Key::Enter),
+            SendShortcut::ShiftEnter => input.consume_key(Modifiers::SHIFT, Key::Enter),
+            // consume_key ignores extra Shift, which is the newline combination here
+            SendShortcut::Enter => {
+                !input.modifiers.shift && input.consume_key(Modifiers::NONE, Key::Enter)
+            }
+        }
+    }
+}
+
+/// Previously sent prompts, browsed with Ctrl+Up/Down
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct InputHistory {
+    entries: Vec<String>,
+    /// Entry currently shown in the editor; `None` while editing a new prompt
+    #[serde(skip)]
+    position: Option<usize>,
+    /// The unsent prompt, restored when browsing past the newest entry
+    #[serde(skip)]
+    draft: String,
+}
+
+impl InputHistory {
+    pub fn push(&mut self, prompt: &str) {
+        self.position = None;
+        self.draft.clear();
+        if prompt.trim().is_empty() || self.entries.last().is_some_and(|last| last == prompt) {
+            return;
+        }
+        self.entries.push(prompt.to_string());
+        if self.entries.len() > MAX_HISTORY {
+            self.entries.remove(0);
+        }
+    }
+
+    /// Older entry to show instead of `current`
+    pub fn previous(&mut self, current: &str) -> Option<String> {
+        let position = match self.position {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            }
+            Some(0) => return None,
+            Some(p) => p - 1,
+        };
+        self.position = Some(position);
+        Some(self.entries[position].clone())
+    }
+
+    /// Newer entry, or the draft once the newest entry is passed
+    pub fn next(&mut self) -> Option<String> {
+        let position = self.position?;
+        if position + 1 < self.entries.len() {
+            self.position = Some(position + 1);
+            Some(self.entries[position + 1].clone())
+        } else {
+            self.position = None;
+            Some(std::mem::take(&mut self.draft))
+        }
+    }
+}
+
+/// Editor preferences and input history, kept across restarts
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EditorPreferences {
+    #[serde(default)]
+    pub send_shortcut: SendShortcut,
+    #[serde(default)]
+    pub history: InputHistory,
+}
+
+impl EditorPreferences {
+    pub fn load() -> Result<Self, String> {
+        Ok(storage::read_json(PREFERENCES_FILE)?.unwrap_or_default())
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        storage::write_json(PREFERENCES_FILE, self)
+    }
+}
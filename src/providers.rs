@@ -0,0 +1,742 @@
+use crate::api_client::{
+    self, GenerationSettings, ModelInfo, ModelResponse, SseReader, TokenUsage, GEMINI_PROVIDER,
+};
+use crate::conversation::ChatMessage;
+use crate::diagnostics::{self, Recorder};
+use crate::documents::SharedContext;
+use crate::keystore::{self, KeyStorage};
+use crate::storage;
+use egui::ColorImage;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::pin::Pin;
+use std::thread::JoinHandle;
+use std::time::Instant;
+use tokio::runtime::Runtime;
+
+const PROVIDERS_FILE: &str = "providers.json";
+
+/// API a provider speaks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderKind {
+    Gemini,
+    /// `/v1/chat/completions` servers such as Ollama, llama.cpp or vLLM
+    OpenAiCompatible,
+}
+
+/// A configured backend; models of providers other than Gemini are named `id/model`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProviderConfig {
+    pub id: String,
+    pub name: String,
+    pub kind: ProviderKind,
+    pub base_url: String,
+    /// Empty for servers without authentication; for Gemini it means GEMINI_API_KEY.
+    /// Only written to providers.json when `key_storage` is the config file
+    #[serde(default)]
+    pub api_key: String,
+    /// Files written before this existed kept the key in providers.json
+    #[serde(default)]
+    pub key_storage: KeyStorage,
+    /// Where the key was last stored outside providers.json; only looked up there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_in: Option<KeyStorage>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl ProviderConfig {
+    pub fn gemini() -> Self {
+        Self {
+            id: GEMINI_PROVIDER.to_string(),
+            name: "Gemini".to_string(),
+            kind: ProviderKind::Gemini,
+            base_url: api_client::API_BASE.to_string(),
+            api_key: String::new(),
+            key_storage: KeyStorage::Keyring,
+            stored_in: None,
+            enabled: true,
+        }
+    }
+
+    /// A disabled OpenAI-compatible provider, e.g. for the "Add provider" button
+    pub fn openai_compatible(id: &str, name: &str, base_url: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            kind: ProviderKind::OpenAiCompatible,
+            base_url: base_url.to_string(),
+            api_key: String::new(),
+            key_storage: KeyStorage::Keyring,
+            stored_in: None,
+            enabled: false,
+        }
+    }
+
+    fn is_gemini(&self) -> bool {
+        self.kind == ProviderKind::Gemini
+    }
+
+    /// Stores `api_key` where `key_storage` says, moving it out of the place it was kept before
+    fn store_key(&mut self, passphrase: Option<&str>) -> Result<(), String> {
+        let storage = self.key_storage;
+        keystore::store(storage, &self.id, &self.api_key, passphrase)
+            .map_err(|e| format!("{}: {}", self.name, e))?;
+        if let Some(old) = self.stored_in.filter(|&old| old != storage) {
+            keystore::delete(old, &self.id).map_err(|e| format!("{}: {}", self.name, e))?;
+        }
+        self.stored_in = (storage != KeyStorage::ConfigFile).then_some(storage);
+        Ok(())
+    }
+
+    pub fn provider(&self) -> Box<dyn ChatProvider> {
+        match self.kind {
+            ProviderKind::Gemini => Box::new(GeminiProvider),
+            ProviderKind::OpenAiCompatible => {
+                diagnostics::register_secret(&self.api_key);
+                Box::new(OpenAiProvider {
+                    config: self.clone(),
+                })
+            }
+        }
+    }
+}
+
+/// Every configured provider; Gemini is always present
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProviderSettings {
+    pub providers: Vec<ProviderConfig>,
+}
+
+impl Default for ProviderSettings {
+    fn default() -> Self {
+        Self {
+            providers: vec![
+                ProviderConfig::gemini(),
+                ProviderConfig::openai_compatible("ollama", "Ollama", "http://localhost:11434/v1"),
+                ProviderConfig::openai_compatible(
+                    "llamacpp",
+                    "llama.cpp",
+                    "http://localhost:8080/v1",
+                ),
+            ],
+        }
+    }
+}
+
+impl ProviderSettings {
+    pub fn load() -> Result<Self, String> {
+        let mut settings: Self = storage::read_json(PROVIDERS_FILE)?.unwrap_or_default();
+        if !settings.providers.iter().any(|p| p.is_gemini()) {
+            settings.providers.insert(0, ProviderConfig::gemini());
+        }
+        Ok(settings)
+    }
+
+    /// Settings with stored keys read, for the command-line modes; the encrypted key file
+    /// is unlocked with the passphrase in GEMINI_OXIDE_PASSPHRASE
+    pub fn load_headless() -> Result<Self, String> {
+        let mut settings = Self::load()?;
+        let passphrase = std::env::var(keystore::PASSPHRASE_VAR).ok();
+        let errors = settings.load_keys(passphrase.as_deref());
+        for error in &errors {
+            eprintln!("Warning: {}", error);
+        }
+        let uses_file = settings
+            .providers
+            .iter()
+            .any(|p| p.key_storage == KeyStorage::EncryptedFile);
+        if !errors.is_empty() && uses_file && passphrase.is_none() {
+            eprintln!(
+                "Set {} to unlock the encrypted key file",
+                keystore::PASSPHRASE_VAR
+            );
+        }
+        settings.apply();
+        Ok(settings)
+    }
+
+    /// Reads the keys kept outside providers.json; returns what could not be read
+    pub fn load_keys(&mut self, passphrase: Option<&str>) -> Vec<String> {
+        let mut errors = Vec::new();
+        for provider in &mut self.providers {
+            let Some(storage) = provider.stored_in else {
+                continue;
+            };
+            match keystore::load(storage, &provider.id, passphrase) {
+                Ok(Some(key)) => provider.api_key = key,
+                Ok(None) => {}
+                Err(e) => errors.push(format!("Key of {}: {}", provider.name, e)),
+            }
+        }
+        errors
+    }
+
+    /// Stores every entered key where its provider keeps it, moving it out of the place it
+    /// was kept before, then writes providers.json without the keys that live elsewhere.
+    /// An empty key leaves a stored one where it is
+    pub fn save(&mut self, passphrase: Option<&str>) -> Result<(), String> {
+        self.validate()?;
+        for provider in &mut self.providers {
+            if !provider.api_key.is_empty() {
+                provider.store_key(passphrase)?;
+            }
+        }
+        self.write()
+    }
+
+    /// Replaces the key of the provider at `index` as its saved settings keep it
+    pub fn save_key(
+        &mut self,
+        index: usize,
+        api_key: &str,
+        passphrase: Option<&str>,
+    ) -> Result<(), String> {
+        self.update_saved(index, |provider| {
+            provider.api_key = api_key.to_string();
+            provider.store_key(passphrase)
+        })?;
+        self.providers[index].api_key = api_key.to_string();
+        Ok(())
+    }
+
+    /// Removes the stored key of the provider at `index`
+    pub fn forget_key(&mut self, index: usize) -> Result<(), String> {
+        self.update_saved(index, |provider| {
+            if let Some(storage) = provider.stored_in.take() {
+                keystore::delete(storage, &provider.id)?;
+            }
+            provider.api_key.clear();
+            Ok(())
+        })?;
+        self.providers[index].api_key.clear();
+        Ok(())
+    }
+
+    /// Changes the saved entry of the provider at `index` and writes providers.json from disk,
+    /// so unsaved edits of the others stay out of it
+    fn update_saved(
+        &mut self,
+        index: usize,
+        change: impl FnOnce(&mut ProviderConfig) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let id = &self.providers[index].id;
+        let mut saved = Self::load()?;
+        let provider = saved
+            .providers
+            .iter_mut()
+            .find(|p| &p.id == id)
+            .ok_or_else(|| format!("Save the providers before changing the key of '{}'", id))?;
+        change(provider)?;
+        self.providers[index].stored_in = provider.stored_in;
+        saved.write()
+    }
+
+    fn write(&self) -> Result<(), String> {
+        let mut file = self.clone();
+        for provider in &mut file.providers {
+            if provider.key_storage != KeyStorage::ConfigFile {
+                provider.api_key.clear();
+            }
+        }
+        storage::write_json(PROVIDERS_FILE, &file)
+    }
+
+    /// Points the Gemini client at the configured base URL and key
+    pub fn apply(&self) {
+        if let Some(gemini) = self.providers.iter().find(|p| p.is_gemini()) {
+            api_client::set_gemini_endpoint(&gemini.base_url, &gemini.api_key);
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (index, provider) in self.providers.iter().enumerate() {
+            if provider.id.trim().is_empty() || provider.id.contains(['/', ' ']) {
+                return Err(format!(
+                    "Provider id '{}' must be non-empty and contain no '/' or spaces",
+                    provider.id
+                ));
+            }
+            if self.providers[..index].iter().any(|p| p.id == provider.id) {
+                return Err(format!("Provider id '{}' is used twice", provider.id));
+            }
+            if !provider.base_url.starts_with("http://")
+                && !provider.base_url.starts_with("https://")
+            {
+                return Err(format!(
+                    "Base URL of '{}' must start with http:// or https://",
+                    provider.name
+                ));
+            }
+        }
+        if self.providers.iter().filter(|p| p.is_gemini()).count() != 1 {
+            return Err("There must be exactly one Gemini provider".to_string());
+        }
+        Ok(())
+    }
+
+    /// Display name of the provider with `id`, e.g. for picker headings
+    pub fn name_of<'a>(&'a self, id: &'a str) -> &'a str {
+        self.providers
+            .iter()
+            .find(|p| p.id == id)
+            .map(|p| p.name.as_str())
+            .unwrap_or(id)
+    }
+
+    /// The provider serving `model`; names without a known `id/` prefix are Gemini models
+    pub fn provider_for_model(&self, model: &str) -> Result<Box<dyn ChatProvider>, String> {
+        let prefixed = model
+            .split_once('/')
+            .and_then(|(id, _)| self.providers.iter().find(|p| p.id == id && !p.is_gemini()));
+        match prefixed {
+            Some(provider) if !provider.enabled => {
+                Err(format!("Provider '{}' is disabled", provider.name))
+            }
+            Some(provider) => Ok(provider.provider()),
+            None => Ok(Box::new(GeminiProvider)),
+        }
+    }
+}
+
+/// Everything a provider needs to answer one prompt
+#[derive(Clone)]
+pub struct ChatRequest {
+    pub prompt: String,
+    /// Model name as listed, including the `id/` prefix of non-Gemini providers
+    pub model: String,
+    pub image: Option<ColorImage>,
+    pub history: Vec<ChatMessage>,
+    pub settings: GenerationSettings,
+    pub shared: SharedContext,
+}
+
+pub type ProviderFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, String>> + Send + 'a>>;
+
+/// A backend that can list its models and answer prompts, whole or streamed
+pub trait ChatProvider: Send + Sync {
+    fn list_models(&self) -> ProviderFuture<'_, Vec<ModelInfo>>;
+
+    /// One response per requested candidate
+    fn send(&self, request: ChatRequest) -> ProviderFuture<'_, Vec<ModelResponse>>;
+
+    /// The first candidate, handing answer text to `on_text` as it arrives
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest,
+        on_text: Box<dyn FnMut(&str) + Send + 'a>,
+    ) -> ProviderFuture<'a, ModelResponse>;
+}
+
+/// The Gemini API through `api_client`
+pub struct GeminiProvider;
+
+impl ChatProvider for GeminiProvider {
+    fn list_models(&self) -> ProviderFuture<'_, Vec<ModelInfo>> {
+        Box::pin(async {
+            api_client::list_models()
+                .await
+                .map_err(|e| diagnostics::redact(&e.to_string()))
+        })
+    }
+
+    fn send(&self, request: ChatRequest) -> ProviderFuture<'_, Vec<ModelResponse>> {
+        Box::pin(async move {
+            api_client::send_request(
+                request.prompt,
+                request.model,
+                request.image,
+                request.history,
+                request.settings,
+                request.shared,
+            )
+            .await
+            .map_err(|e| diagnostics::redact(&e.to_string()))
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest,
+        on_text: Box<dyn FnMut(&str) + Send + 'a>,
+    ) -> ProviderFuture<'a, ModelResponse> {
+        Box::pin(async move {
+            api_client::stream_request(
+                request.prompt,
+                request.model,
+                request.image,
+                request.history,
+                request.settings,
+                request.shared,
+                on_text,
+            )
+            .await
+            .map_err(|e| diagnostics::redact(&e.to_string()))
+        })
+    }
+}
+
+/// A server speaking the OpenAI chat-completions API
+pub struct OpenAiProvider {
+    config: ProviderConfig,
+}
+
+impl OpenAiProvider {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.base_url.trim_end_matches('/'), path)
+    }
+
+    fn authorize(&self, builder: RequestBuilder) -> RequestBuilder {
+        if self.config.api_key.trim().is_empty() {
+            builder
+        } else {
+            builder.bearer_auth(self.config.api_key.trim())
+        }
+    }
+
+    /// Model name without this provider's `id/` prefix
+    fn model_id<'a>(&self, model: &'a str) -> &'a str {
+        model
+            .strip_prefix(&self.config.id)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .unwrap_or(model)
+    }
+
+    fn request_body(&self, request: &ChatRequest, stream: bool) -> Result<Value, String> {
+        if !request.shared.attachments.is_empty() || request.shared.cached_content.is_some() {
+            return Err(format!(
+                "Documents and context caches are only supported by Gemini, not {}",
+                self.config.name
+            ));
+        }
+
+        let mut messages = Vec::new();
+        if !request.shared.system_instruction.trim().is_empty() {
+            messages.push(json!({
+                "role": "system",
+                "content": request.shared.system_instruction,
+            }));
+        }
+        for message in &request.history {
+            let role = if message.role == "model" {
+                "assistant"
+            } else {
+                "user"
+            };
+            messages.push(json!({ "role": role, "content": message.content }));
+        }
+        let content = match request.image {
+            Some(ref image) => {
+                let data = api_client::encode_image_to_base64(image).map_err(|e| e.to_string())?;
+                json!([
+                    { "type": "image_url", "image_url": { "url": format!("data:image/png;base64,{}", data) } },
+                    { "type": "text", "text": request.prompt },
+                ])
+            }
+            None => json!(request.prompt),
+        };
+        messages.push(json!({ "role": "user", "content": content }));
+
+        let mut body = json!({
+            "model": self.model_id(&request.model),
+            "messages": messages,
+            "stream": stream,
+        });
+        if request.settings.candidate_count > 1 {
+            body["n"] = json!(request.settings.candidate_count);
+        }
+        if stream {
+            // Token counts then arrive in a last chunk without choices
+            body["stream_options"] = json!({ "include_usage": true });
+        }
+        Ok(body)
+    }
+
+    /// Sends with this provider's key; responses other than 2xx become errors
+    async fn send_checked(
+        &self,
+        builder: RequestBuilder,
+        model: Option<&str>,
+    ) -> Result<(reqwest::Response, Recorder), String> {
+        let (res, recorder) = diagnostics::send(self.authorize(builder), model)
+            .await
+            .map_err(|e| {
+                diagnostics::redact(&format!("{} is unreachable: {}", self.config.name, e))
+            })?;
+        if res.status().is_success() {
+            return Ok((res, recorder));
+        }
+        let status = res.status();
+        let error_text = recorder
+            .text(res)
+            .await
+            .unwrap_or_else(|_| "Failed to read error response".to_string());
+        Err(diagnostics::redact(&format!(
+            "HTTP Error {}: {}",
+            status, error_text
+        )))
+    }
+
+    async fn post(&self, body: &Value) -> Result<(reqwest::Response, Recorder), String> {
+        let builder = Client::new().post(self.url("/chat/completions")).json(body);
+        self.send_checked(builder, body.get("model").and_then(|v| v.as_str()))
+            .await
+    }
+}
+
+impl ChatProvider for OpenAiProvider {
+    fn list_models(&self) -> ProviderFuture<'_, Vec<ModelInfo>> {
+        Box::pin(async move {
+            let (res, recorder) = self
+                .send_checked(Client::new().get(self.url("/models")), None)
+                .await?;
+            let text = recorder.text(res).await.map_err(|e| e.to_string())?;
+            let res_json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+            let mut models: Vec<ModelInfo> = res_json
+                .get("data")
+                .and_then(|v| v.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|model| model.get("id").and_then(|v| v.as_str()))
+                .map(|id| ModelInfo {
+                    name: format!("{}/{}", self.config.id, id),
+                    display_name: id.to_string(),
+                    input_token_limit: 0,
+                    output_token_limit: 0,
+                    thinking: false,
+                    provider: self.config.id.clone(),
+                })
+                .collect();
+            models.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+            Ok(models)
+        })
+    }
+
+    fn send(&self, request: ChatRequest) -> ProviderFuture<'_, Vec<ModelResponse>> {
+        Box::pin(async move {
+            let body = self.request_body(&request, false)?;
+            let (res, recorder) = self.post(&body).await?;
+            let text = recorder.text(res).await.map_err(|e| e.to_string())?;
+            let res_json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+            let responses: Vec<ModelResponse> = res_json
+                .get("choices")
+                .and_then(|v| v.as_array())
+                .into_iter()
+                .flatten()
+                .map(|choice| {
+                    let message = choice.get("message").cloned().unwrap_or_default();
+                    choice_response(
+                        text_field(&message, "content"),
+                        text_field(&message, "reasoning_content"),
+                        choice.get("finish_reason").and_then(|v| v.as_str()),
+                    )
+                })
+                .collect();
+            if responses.is_empty() {
+                return Err(format!("{} returned no choices", self.config.name));
+            }
+            let usage = openai_usage(&res_json);
+            Ok(responses
+                .into_iter()
+                .map(|response| ModelResponse { usage, ..response })
+                .collect())
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest,
+        mut on_text: Box<dyn FnMut(&str) + Send + 'a>,
+    ) -> ProviderFuture<'a, ModelResponse> {
+        Box::pin(async move {
+            let body = self.request_body(&request, true)?;
+            let (res, recorder) = self.post(&body).await?;
+            let mut events = SseReader::new(res, recorder);
+
+            let mut text = String::new();
+            let mut thoughts = String::new();
+            let mut finish_reason = None;
+            let mut usage = None;
+            while let Some(data) = events.next_data().await.map_err(|e| e.to_string())? {
+                // Read on to the end of the response so the diagnostics record completes
+                if data == "[DONE]" {
+                    continue;
+                }
+                let event: Value = match serde_json::from_str(&data) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        events.skip(&e);
+                        continue;
+                    }
+                };
+                usage = openai_usage(&event).or(usage);
+                let Some(choice) = event.pointer("/choices/0") else {
+                    continue;
+                };
+                let delta = choice.get("delta").cloned().unwrap_or_default();
+                let piece = text_field(&delta, "content");
+                if !piece.is_empty() {
+                    on_text(&piece);
+                    text.push_str(&piece);
+                }
+                thoughts.push_str(&text_field(&delta, "reasoning_content"));
+                if let Some(reason) = choice.get("finish_reason").and_then(|v| v.as_str()) {
+                    finish_reason = Some(reason.to_string());
+                }
+            }
+            Ok(ModelResponse {
+                usage,
+                ..choice_response(text, thoughts, finish_reason.as_deref())
+            })
+        })
+    }
+}
+
+fn text_field(value: &Value, key: &str) -> String {
+    value
+        .get(key)
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Maps an OpenAI choice onto the Gemini-shaped response the rest of the app uses
+fn choice_response(text: String, thoughts: String, finish_reason: Option<&str>) -> ModelResponse {
+    let finish_reason = finish_reason.map(|reason| match reason {
+        "length" => "MAX_TOKENS".to_string(),
+        "content_filter" => "SAFETY".to_string(),
+        other => other.to_uppercase(),
+    });
+    let blocked = (finish_reason.as_deref() == Some("SAFETY") && text.trim().is_empty())
+        .then(|| "Response blocked: content filter".to_string());
+    ModelResponse {
+        text: match blocked {
+            Some(_) => "**The response was blocked** by the server's content filter.".to_string(),
+            None => text,
+        },
+        thoughts: (!thoughts.trim().is_empty()).then_some(thoughts),
+        blocked,
+        finish_reason,
+        images: Vec::new(),
+        usage: None,
+        latency_ms: None,
+    }
+}
+
+/// Token counts of an OpenAI `usage` object
+fn openai_usage(res_json: &Value) -> Option<TokenUsage> {
+    let usage = res_json.get("usage").filter(|v| v.is_object())?;
+    let count = |pointer: &str| usage.pointer(pointer).and_then(|v| v.as_u64()).unwrap_or(0);
+    let thoughts = count("/completion_tokens_details/reasoning_tokens");
+    Some(TokenUsage {
+        prompt: count("/prompt_tokens"),
+        output: count("/completion_tokens").saturating_sub(thoughts),
+        thoughts,
+        total: count("/total_tokens"),
+    })
+}
+
+/// Models of every enabled provider, plus the error of each provider that couldn't list them
+pub struct ModelListing {
+    pub models: Vec<ModelInfo>,
+    /// Provider id and error message
+    pub errors: Vec<(String, String)>,
+}
+
+/// Lists models with the base URL and key of `config` without applying them, to check
+/// a new key; returns how many models it can use
+pub fn spawn_check_key(config: ProviderConfig) -> JoinHandle<Result<usize, String>> {
+    diagnostics::register_secret(&config.api_key);
+    std::thread::spawn(move || {
+        Runtime::new().map_err(|e| e.to_string())?.block_on(async {
+            let models = match config.kind {
+                ProviderKind::Gemini => {
+                    api_client::list_models_at(&config.base_url, config.api_key.trim())
+                        .await
+                        .map_err(|e| diagnostics::redact(&e.to_string()))?
+                }
+                ProviderKind::OpenAiCompatible => config.provider().list_models().await?,
+            };
+            Ok(models.len())
+        })
+    })
+}
+
+pub fn spawn_list_models(settings: ProviderSettings) -> JoinHandle<Result<ModelListing, String>> {
+    std::thread::spawn(move || {
+        Runtime::new().map_err(|e| e.to_string())?.block_on(async {
+            let mut listing = ModelListing {
+                models: Vec::new(),
+                errors: Vec::new(),
+            };
+            for config in settings.providers.iter().filter(|p| p.enabled) {
+                match config.provider().list_models().await {
+                    Ok(models) => listing.models.extend(models),
+                    Err(err) => listing.errors.push((config.id.clone(), err)),
+                }
+            }
+            Ok(listing)
+        })
+    })
+}
+
+pub fn spawn_request(
+    provider: Box<dyn ChatProvider>,
+    request: ChatRequest,
+) -> JoinHandle<Result<Vec<ModelResponse>, String>> {
+    std::thread::spawn(move || {
+        let started = Instant::now();
+        let responses = Runtime::new()
+            .map_err(|e| e.to_string())?
+            .block_on(provider.send(request))?;
+
+        let latency_ms = Some(started.elapsed().as_millis() as u64);
+        Ok(responses
+            .into_iter()
+            .map(|response| ModelResponse {
+                latency_ms,
+                ..response
+            })
+            .collect())
+    })
+}
+
+/// Sends a one-off prompt without history and returns the answer text, e.g. for summaries
+pub fn spawn_summary_request(
+    provider: Box<dyn ChatProvider>,
+    prompt: String,
+    model: String,
+    settings: GenerationSettings,
+) -> JoinHandle<Result<String, String>> {
+    std::thread::spawn(move || {
+        let request = ChatRequest {
+            prompt,
+            model,
+            image: None,
+            history: Vec::new(),
+            settings,
+            shared: SharedContext::default(),
+        };
+        let responses = Runtime::new()
+            .map_err(|e| e.to_string())?
+            .block_on(provider.send(request))?;
+
+        let response = responses.into_iter().next().unwrap_or_default();
+        if let Some(reason) = response.blocked {
+            return Err(reason);
+        }
+        if response.text.trim().is_empty() {
+            return Err("The model returned an empty answer".to_string());
+        }
+        Ok(response.text.trim().to_string())
+    })
+}
@@ -0,0 +1,353 @@
+use crate::session::{self, Session};
+use crate::storage;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::thread::JoinHandle;
+
+const MAX_RESULTS: usize = 100;
+/// Characters of context shown on each side of the first match
+const SNIPPET_CONTEXT: usize = 80;
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// One message of a saved session, as stored in the index
+struct IndexedMessage {
+    session_id: String,
+    session_title: String,
+    node: usize,
+    role: String,
+    model: Option<String>,
+    timestamp: u64,
+    content: String,
+}
+
+/// Word index over every message of every saved session
+#[derive(Default)]
+pub struct SearchIndex {
+    /// Message id -> message; ids are never reused, so re-indexing one session leaves
+    /// the postings of all others alone
+    messages: BTreeMap<usize, IndexedMessage>,
+    next_id: usize,
+    /// Session id -> node -> message id
+    sessions: BTreeMap<String, BTreeMap<usize, usize>>,
+    /// Lowercased word -> message ids
+    terms: BTreeMap<String, BTreeSet<usize>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DateFilter {
+    #[default]
+    AnyTime,
+    Today,
+    LastWeek,
+    LastMonth,
+    LastYear,
+}
+
+impl DateFilter {
+    pub const ALL: [DateFilter; 5] = [
+        DateFilter::AnyTime,
+        DateFilter::Today,
+        DateFilter::LastWeek,
+        DateFilter::LastMonth,
+        DateFilter::LastYear,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DateFilter::AnyTime => "Any time",
+            DateFilter::Today => "Last 24 hours",
+            DateFilter::LastWeek => "Last 7 days",
+            DateFilter::LastMonth => "Last 30 days",
+            DateFilter::LastYear => "Last year",
+        }
+    }
+
+    fn max_age(self) -> Option<u64> {
+        match self {
+            DateFilter::AnyTime => None,
+            DateFilter::Today => Some(DAY_SECS),
+            DateFilter::LastWeek => Some(7 * DAY_SECS),
+            DateFilter::LastMonth => Some(30 * DAY_SECS),
+            DateFilter::LastYear => Some(365 * DAY_SECS),
+        }
+    }
+}
+
+/// What the search box and its filters currently ask for
+#[derive(Clone, Debug, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    pub model: Option<String>,
+    pub role: Option<String>,
+    pub date: DateFilter,
+}
+
+pub struct SearchHit {
+    pub session_id: String,
+    pub session_title: String,
+    pub node: usize,
+    pub role: String,
+    pub model: Option<String>,
+    pub timestamp: u64,
+    /// Part of the message around the first match
+    pub snippet: String,
+    /// Byte ranges of the matches within `snippet`
+    pub highlights: Vec<Range<usize>>,
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+impl SearchIndex {
+    /// Loads and indexes every saved session
+    pub fn build() -> Result<Self, String> {
+        let mut index = Self::default();
+        for summary in session::list_sessions()? {
+            // A session that fails to load just isn't searchable
+            if let Ok(session) = session::load_session(&summary.id) {
+                index.update_session(&session);
+            }
+        }
+        Ok(index)
+    }
+
+    fn index_message(&mut self, msg: IndexedMessage) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        for term in tokenize(&msg.content) {
+            self.terms.entry(term).or_default().insert(id);
+        }
+        self.messages.insert(id, msg);
+        id
+    }
+
+    fn unindex_message(&mut self, id: usize) {
+        let Some(msg) = self.messages.remove(&id) else {
+            return;
+        };
+        for term in tokenize(&msg.content) {
+            if let Some(postings) = self.terms.get_mut(&term) {
+                postings.remove(&id);
+                if postings.is_empty() {
+                    self.terms.remove(&term);
+                }
+            }
+        }
+    }
+
+    pub fn remove_session(&mut self, id: &str) {
+        for message_id in self
+            .sessions
+            .remove(id)
+            .into_iter()
+            .flat_map(|n| n.into_values())
+        {
+            self.unindex_message(message_id);
+        }
+    }
+
+    /// Indexes a session after it was saved; only messages that are new or changed since
+    /// the last time are tokenized
+    pub fn update_session(&mut self, session: &Session) {
+        let mut previous = self.sessions.remove(&session.id).unwrap_or_default();
+        let mut nodes = BTreeMap::new();
+        for (node, msg) in session.conversation.all_messages().enumerate() {
+            let indexed = IndexedMessage {
+                session_id: session.id.clone(),
+                session_title: session.title.clone(),
+                node,
+                role: msg.role.clone(),
+                model: msg.model.clone(),
+                // Messages from before timestamps were recorded fall back to the session's
+                timestamp: if msg.created_at > 0 {
+                    msg.created_at
+                } else {
+                    session.updated_at
+                },
+                content: msg.content.clone(),
+            };
+            let id = match previous.remove(&node) {
+                Some(id)
+                    if self
+                        .messages
+                        .get(&id)
+                        .is_some_and(|m| m.content == msg.content) =>
+                {
+                    // Same words, so the postings stay; title or model may have changed
+                    self.messages.insert(id, indexed);
+                    id
+                }
+                stale => {
+                    if let Some(id) = stale {
+                        self.unindex_message(id);
+                    }
+                    self.index_message(indexed)
+                }
+            };
+            nodes.insert(node, id);
+        }
+        for id in previous.into_values() {
+            self.unindex_message(id);
+        }
+        self.sessions.insert(session.id.clone(), nodes);
+    }
+
+    /// Models that appear in the index, for the model filter
+    pub fn models(&self) -> BTreeSet<&str> {
+        self.messages
+            .values()
+            .filter_map(|m| m.model.as_deref())
+            .collect()
+    }
+
+    /// Messages containing every word of the query as a word prefix, newest first
+    pub fn search(&self, query: &SearchQuery) -> Vec<SearchHit> {
+        let words: Vec<String> = tokenize(&query.text).collect();
+        if words.is_empty() {
+            return Vec::new();
+        }
+
+        let mut matching: Option<BTreeSet<usize>> = None;
+        for word in &words {
+            let with_prefix: BTreeSet<usize> = self
+                .terms
+                .range(word.clone()..)
+                .take_while(|(term, _)| term.starts_with(word.as_str()))
+                .flat_map(|(_, postings)| postings.iter().copied())
+                .collect();
+            matching = Some(match matching {
+                Some(previous) => previous.intersection(&with_prefix).copied().collect(),
+                None => with_prefix,
+            });
+        }
+
+        let now = storage::now_secs();
+        let mut hits: Vec<&IndexedMessage> = matching
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|idx| self.messages.get(&idx))
+            .filter(|m| query.role.as_ref().is_none_or(|role| &m.role == role))
+            .filter(|m| query.model.is_none() || m.model == query.model)
+            .filter(|m| {
+                query
+                    .date
+                    .max_age()
+                    .is_none_or(|age| now.saturating_sub(m.timestamp) <= age)
+            })
+            .collect();
+        hits.sort_by_key(|m| std::cmp::Reverse(m.timestamp));
+
+        hits.into_iter()
+            .take(MAX_RESULTS)
+            .map(|m| {
+                let (snippet, highlights) = snippet(&m.content, &words);
+                SearchHit {
+                    session_id: m.session_id.clone(),
+                    session_title: m.session_title.clone(),
+                    node: m.node,
+                    role: m.role.clone(),
+                    model: m.model.clone(),
+                    timestamp: m.timestamp,
+                    snippet,
+                    highlights,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Byte ranges in `text` where any of `words` occurs, ignoring case; sorted and merged
+fn find_matches(text: &str, words: &[String]) -> Vec<Range<usize>> {
+    // Lowercase char by char so positions still map back to `text`
+    let chars: Vec<(usize, char)> = text
+        .char_indices()
+        .map(|(i, c)| (i, c.to_lowercase().next().unwrap_or(c)))
+        .collect();
+
+    let mut ranges = Vec::new();
+    for word in words {
+        let word: Vec<char> = word.chars().collect();
+        if word.is_empty() || word.len() > chars.len() {
+            continue;
+        }
+        for start in 0..=chars.len() - word.len() {
+            if chars[start..start + word.len()]
+                .iter()
+                .map(|(_, c)| *c)
+                .eq(word.iter().copied())
+            {
+                let end = chars
+                    .get(start + word.len())
+                    .map_or(text.len(), |(i, _)| *i);
+                ranges.push(chars[start].0..end);
+            }
+        }
+    }
+
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// A single-line excerpt around the first match, with match ranges relative to it
+fn snippet(content: &str, words: &[String]) -> (String, Vec<Range<usize>>) {
+    let matches = find_matches(content, words);
+    let first = matches.first().map_or(0, |r| r.start);
+
+    let start = content[..first]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT)
+        .map_or(0, |(i, _)| i);
+    let end = content[first..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT * 2)
+        .map_or(content.len(), |(i, _)| first + i);
+
+    let prefix = if start > 0 { "…" } else { "" };
+    let suffix = if end < content.len() { "…" } else { "" };
+    // Newlines become spaces, which keeps every byte offset valid
+    let snippet = format!(
+        "{}{}{}",
+        prefix,
+        content[start..end].replace('\n', " "),
+        suffix
+    );
+
+    let shift = prefix.len();
+    let highlights = matches
+        .into_iter()
+        .filter(|r| r.start >= start && r.end <= end)
+        .map(|r| r.start - start + shift..r.end - start + shift)
+        .collect();
+    (snippet, highlights)
+}
+
+/// "YYYY-MM-DD" in UTC for a Unix timestamp
+pub fn format_date(secs: u64) -> String {
+    // Civil-from-days conversion (Howard Hinnant's algorithm)
+    let days = (secs / DAY_SECS) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+pub fn spawn_build_index() -> JoinHandle<Result<SearchIndex, String>> {
+    std::thread::spawn(SearchIndex::build)
+}
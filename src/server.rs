@@ -0,0 +1,708 @@
+use crate::api_client::{
+    fallback_models, load_cached_models, GenerationSettings, ModelInfo, ModelResponse,
+    MAX_CANDIDATES,
+};
+use crate::commands;
+use crate::conversation::ChatMessage;
+use crate::documents::SharedContext;
+use crate::img_utils;
+use crate::providers::{ChatRequest, ProviderSettings};
+use crate::session;
+use crate::storage;
+use base64::{engine::general_purpose, Engine as _};
+use egui::ColorImage;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+/// Not 8080, which llama.cpp and many other local servers take
+pub const DEFAULT_PORT: u16 = 8089;
+const MAX_HEADER_BYTES: usize = 64 * 1024;
+/// A client that sends nothing for this long is dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Pause after a failed accept so running out of file descriptors doesn't spin
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Large enough for a few inline screenshots
+const MAX_BODY_BYTES: usize = 32 * 1024 * 1024;
+
+pub struct ServeOptions {
+    pub addr: SocketAddr,
+    /// Bearer token clients must send; a random one is generated when not given
+    pub token: Option<String>,
+    /// Used when a request names no model
+    pub model: Option<String>,
+    /// Prepended to the system messages of every request
+    pub system: String,
+}
+
+struct ServerState {
+    /// Address the server is bound to, for the `Host` check
+    bind: IpAddr,
+    token: String,
+    providers: ProviderSettings,
+    models: Vec<ModelInfo>,
+    default_model: String,
+    system: String,
+}
+
+/// An error sent back in OpenAI's `{"error": {...}}` shape
+struct ApiError {
+    status: u16,
+    kind: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self {
+            status: 400,
+            kind: "invalid_request_error",
+            message: message.into(),
+        }
+    }
+
+    fn upstream(message: String) -> Self {
+        Self {
+            status: 502,
+            kind: "upstream_error",
+            message,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "error": { "message": self.message, "type": self.kind, "code": Value::Null } })
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        502 => "Bad Gateway",
+        _ => "Internal Server Error",
+    }
+}
+
+/// One read from the client, failing if it stalls
+async fn read_chunk(stream: &mut TcpStream, chunk: &mut [u8]) -> Result<usize, ApiError> {
+    let read = tokio::time::timeout(READ_TIMEOUT, stream.read(chunk))
+        .await
+        .map_err(|_| ApiError {
+            status: 408,
+            kind: "invalid_request_error",
+            message: "Timed out waiting for the request".to_string(),
+        })?
+        .map_err(|e| ApiError::bad_request(format!("Failed to read request: {}", e)))?;
+    if read == 0 {
+        return Err(ApiError::bad_request("Connection closed mid-request"));
+    }
+    Ok(read)
+}
+
+/// Reads one request; bodies must come with a `Content-Length`. The `Host` and token are
+/// checked before the body is read, so clients without the token can't make it buffer one
+async fn read_request(
+    stream: &mut TcpStream,
+    state: &ServerState,
+) -> Result<HttpRequest, ApiError> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let header_end = loop {
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if buffer.len() > MAX_HEADER_BYTES {
+            return Err(ApiError::bad_request("Request headers are too large"));
+        }
+        let read = read_chunk(stream, &mut chunk).await?;
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    let mut expects_continue = false;
+    let mut host = None;
+    let mut authorization = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| ApiError::bad_request("Invalid Content-Length"))?
+            }
+            "transfer-encoding" if value.eq_ignore_ascii_case("chunked") => {
+                return Err(ApiError {
+                    status: 411,
+                    kind: "invalid_request_error",
+                    message: "Chunked request bodies are not supported; send a Content-Length"
+                        .to_string(),
+                })
+            }
+            "expect" if value.eq_ignore_ascii_case("100-continue") => expects_continue = true,
+            "host" => host = Some(value.to_string()),
+            "authorization" => authorization = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    if !host_allowed(host.as_deref(), state.bind) {
+        return Err(ApiError {
+            status: 403,
+            kind: "invalid_request_error",
+            message: format!("Host '{}' is not allowed", host.unwrap_or_default()),
+        });
+    }
+    if !token_matches(authorization.as_deref(), &state.token) {
+        return Err(ApiError {
+            status: 401,
+            kind: "invalid_request_error",
+            message: "Send the token printed at startup as 'Authorization: Bearer TOKEN'"
+                .to_string(),
+        });
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(ApiError {
+            status: 413,
+            kind: "invalid_request_error",
+            message: format!("Request bodies are limited to {} MB", MAX_BODY_BYTES >> 20),
+        });
+    }
+    // curl waits for this before sending larger bodies
+    if expects_continue {
+        let _ = stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await;
+    }
+
+    let mut body = buffer[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let read = read_chunk(stream, &mut chunk).await?;
+        body.extend_from_slice(&chunk[..read]);
+    }
+    body.truncate(content_length);
+
+    Ok(HttpRequest { method, path, body })
+}
+
+/// A web page can make the browser send requests to localhost under its own domain name
+/// (DNS rebinding), so only local names and the bound address are accepted
+fn host_allowed(host: Option<&str>, bind: IpAddr) -> bool {
+    let Some(host) = host else {
+        return false;
+    };
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    if name.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    match name.parse::<IpAddr>() {
+        // Bound to every interface, clients may use any address of this machine
+        Ok(ip) => ip.is_loopback() || ip == bind || bind.is_unspecified(),
+        Err(_) => false,
+    }
+}
+
+/// Compares every byte so the time taken doesn't tell how much of the token was right
+fn token_matches(authorization: Option<&str>, token: &str) -> bool {
+    let Some(given) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    let given = given.trim().as_bytes();
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// 32 random bytes, printed at startup for the clients to use
+fn generate_token() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "Failed to generate an access token".to_string())?;
+    Ok(general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// Every response closes the connection, which keeps the server free of keep-alive handling
+async fn write_json(stream: &mut TcpStream, status: u16, body: &Value) -> std::io::Result<()> {
+    let body = body.to_string();
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+Connection: close\r\n\r\n",
+        status,
+        reason_phrase(status),
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.flush().await
+}
+
+async fn write_event(stream: &mut TcpStream, data: &str) -> std::io::Result<()> {
+    stream
+        .write_all(format!("data: {}\n\n", data).as_bytes())
+        .await?;
+    stream.flush().await
+}
+
+/// `ModelInfo` as an OpenAI model object
+fn model_object(model: &ModelInfo) -> Value {
+    json!({
+        "id": model.name,
+        "object": "model",
+        "created": 0,
+        "owned_by": model.provider,
+    })
+}
+
+/// Live models of every enabled provider, falling back to the ones the window cached
+async fn models_response(state: &ServerState) -> Value {
+    let mut models = Vec::new();
+    for config in state.providers.providers.iter().filter(|p| p.enabled) {
+        match config.provider().list_models().await {
+            Ok(listed) if !listed.is_empty() => models.extend(listed),
+            _ => models.extend(
+                state
+                    .models
+                    .iter()
+                    .filter(|m| m.provider == config.id)
+                    .cloned(),
+            ),
+        }
+    }
+    json!({
+        "object": "list",
+        "data": models.iter().map(model_object).collect::<Vec<_>>(),
+    })
+}
+
+/// Text and image URLs of an OpenAI message `content`, which is a string or a list of parts
+fn content_parts(content: &Value) -> Result<(String, Vec<String>), ApiError> {
+    match content {
+        Value::Null => Ok((String::new(), Vec::new())),
+        Value::String(text) => Ok((text.clone(), Vec::new())),
+        Value::Array(parts) => {
+            let mut texts = Vec::new();
+            let mut images = Vec::new();
+            for part in parts {
+                match part.get("type").and_then(|v| v.as_str()) {
+                    Some("text") => texts.push(
+                        part.get("text")
+                            .and_then(|v| v.as_str())
+                            .unwrap_or_default()
+                            .to_string(),
+                    ),
+                    Some("image_url") => {
+                        let url = part
+                            .pointer("/image_url/url")
+                            .or_else(|| part.get("image_url"))
+                            .and_then(|v| v.as_str())
+                            .ok_or_else(|| ApiError::bad_request("image_url part has no url"))?;
+                        images.push(url.to_string());
+                    }
+                    other => {
+                        return Err(ApiError::bad_request(format!(
+                            "Unsupported content part type {}",
+                            other.unwrap_or("(missing)")
+                        )))
+                    }
+                }
+            }
+            Ok((texts.join("\n"), images))
+        }
+        _ => Err(ApiError::bad_request("content must be a string or a list")),
+    }
+}
+
+/// Decodes a `data:image/...;base64,` URL; remote URLs are not fetched
+fn decode_data_url(url: &str) -> Result<ColorImage, ApiError> {
+    let data = url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+        .map(|(_, data)| data)
+        .ok_or_else(|| ApiError::bad_request("Only base64 data: URLs are supported for images"))?;
+    let bytes = general_purpose::STANDARD
+        .decode(data.trim())
+        .map_err(|e| ApiError::bad_request(format!("Invalid image data: {}", e)))?;
+    img_utils::load_color_image_from_bytes(&bytes).map_err(ApiError::bad_request)
+}
+
+/// An OpenAI `messages` array split the way `send_request` takes it
+struct TranslatedChat {
+    system: Vec<String>,
+    history: Vec<ChatMessage>,
+    prompt: String,
+    image: Option<ColorImage>,
+}
+
+fn translate_messages(messages: &[Value]) -> Result<TranslatedChat, ApiError> {
+    let mut system = Vec::new();
+    let mut turns: Vec<(String, String, Vec<String>)> = Vec::new();
+    for message in messages {
+        let role = message
+            .get("role")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| ApiError::bad_request("Every message needs a role"))?;
+        let (text, images) = content_parts(message.get("content").unwrap_or(&Value::Null))?;
+        match role {
+            "system" | "developer" => system.push(text),
+            "user" => turns.push(("user".to_string(), text, images)),
+            "assistant" => turns.push(("model".to_string(), text, images)),
+            other => {
+                return Err(ApiError::bad_request(format!(
+                    "Messages with role '{}' are not supported",
+                    other
+                )))
+            }
+        }
+    }
+
+    let Some((role, prompt, images)) = turns.pop() else {
+        return Err(ApiError::bad_request("messages contains no user message"));
+    };
+    if role != "user" {
+        return Err(ApiError::bad_request(
+            "The last message must come from the user",
+        ));
+    }
+    // send_request attaches a single image to the new prompt only; images of earlier turns
+    // are dropped and their text kept, so clients that resend the whole chat still work
+    if images.len() > 1 {
+        return Err(ApiError::bad_request(
+            "Only one image per message is supported",
+        ));
+    }
+    let image = images.first().map(|url| decode_data_url(url)).transpose()?;
+
+    let history = turns
+        .into_iter()
+        .map(|(role, text, _)| {
+            let mut message = ChatMessage::user(text);
+            message.role = role;
+            message
+        })
+        .collect();
+    Ok(TranslatedChat {
+        system,
+        history,
+        prompt,
+        image,
+    })
+}
+
+/// OpenAI `finish_reason` for a Gemini answer
+fn finish_reason(response: &ModelResponse) -> &'static str {
+    if response.blocked.is_some() {
+        return "content_filter";
+    }
+    match response.finish_reason.as_deref() {
+        Some("MAX_TOKENS") => "length",
+        Some("SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII") => {
+            "content_filter"
+        }
+        _ => "stop",
+    }
+}
+
+fn completion_id() -> String {
+    session::new_session_id().replace("session-", "chatcmpl-")
+}
+
+fn chunk_json(id: &str, created: u64, model: &str, delta: Value, finish: Option<&str>) -> String {
+    json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish }],
+    })
+    .to_string()
+}
+
+/// Handles `/v1/chat/completions`; returns the model used, for the request log
+async fn chat_completions(
+    stream: &mut TcpStream,
+    state: &ServerState,
+    body: &[u8],
+) -> Result<String, ApiError> {
+    let request: Value = serde_json::from_slice(body)
+        .map_err(|e| ApiError::bad_request(format!("Invalid JSON: {}", e)))?;
+    let messages = request
+        .get("messages")
+        .and_then(|v| v.as_array())
+        .ok_or_else(|| ApiError::bad_request("messages is required"))?;
+    let chat = translate_messages(messages)?;
+
+    let model = commands::resolve_model(
+        &state.models,
+        Some(
+            request
+                .get("model")
+                .and_then(|v| v.as_str())
+                .filter(|m| !m.is_empty())
+                .unwrap_or(&state.default_model),
+        ),
+    );
+    let provider = state
+        .providers
+        .provider_for_model(&model)
+        .map_err(ApiError::bad_request)?;
+    let mut settings =
+        GenerationSettings::default().for_model(state.models.iter().find(|m| m.name == model));
+    settings.include_thoughts = false;
+    let choices = request.get("n").and_then(|v| v.as_u64()).unwrap_or(1);
+    if !(1..=MAX_CANDIDATES as u64).contains(&choices) {
+        return Err(ApiError::bad_request(format!(
+            "n must be between 1 and {}",
+            MAX_CANDIDATES
+        )));
+    }
+    settings.candidate_count = choices as u32;
+
+    let shared = SharedContext {
+        system_instruction: std::iter::once(state.system.clone())
+            .chain(chat.system)
+            .filter(|s| !s.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        ..SharedContext::default()
+    };
+    let streaming = request.get("stream").and_then(|v| v.as_bool()) == Some(true);
+    let request = ChatRequest {
+        prompt: chat.prompt,
+        model: model.clone(),
+        image: chat.image,
+        history: chat.history,
+        settings,
+        shared,
+    };
+    let id = completion_id();
+    let created = storage::now_secs();
+
+    if !streaming {
+        let responses = provider.send(request).await.map_err(ApiError::upstream)?;
+        let choices: Vec<Value> = responses
+            .iter()
+            .enumerate()
+            .map(|(index, response)| {
+                json!({
+                    "index": index,
+                    "message": { "role": "assistant", "content": response.text },
+                    "finish_reason": finish_reason(response),
+                })
+            })
+            .collect();
+        let body = json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": created,
+            "model": model,
+            "choices": choices,
+        });
+        write_json(stream, 200, &body)
+            .await
+            .map_err(|e| ApiError::upstream(format!("Failed to send the response: {}", e)))?;
+        return Ok(model);
+    }
+
+    if choices > 1 {
+        return Err(ApiError::bad_request("n > 1 is not supported with stream"));
+    }
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+Connection: close\r\n\r\n",
+        )
+        .await
+        .map_err(|e| ApiError::upstream(e.to_string()))?;
+    let _ = write_event(
+        stream,
+        &chunk_json(
+            &id,
+            created,
+            &model,
+            json!({ "role": "assistant", "content": "" }),
+            None,
+        ),
+    )
+    .await;
+
+    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    let request = provider.stream(
+        request,
+        Box::new(move |text| {
+            let _ = sender.send(text.to_string());
+        }),
+    );
+    let forward = async {
+        let mut streamed = false;
+        while let Some(text) = receiver.recv().await {
+            streamed = true;
+            let chunk = chunk_json(&id, created, &model, json!({ "content": text }), None);
+            // A client that hung up just stops receiving; the request still finishes
+            if write_event(stream, &chunk).await.is_err() {
+                break;
+            }
+        }
+        streamed
+    };
+    let (result, streamed) = tokio::join!(request, forward);
+
+    // Headers are already out, so errors can only be reported as an event
+    let last = match result {
+        Ok(response) => {
+            if !streamed && response.blocked.is_some() {
+                let chunk = chunk_json(
+                    &id,
+                    created,
+                    &model,
+                    json!({ "content": response.text }),
+                    None,
+                );
+                let _ = write_event(stream, &chunk).await;
+            }
+            chunk_json(
+                &id,
+                created,
+                &model,
+                json!({}),
+                Some(finish_reason(&response)),
+            )
+        }
+        Err(e) => ApiError::upstream(e).to_json().to_string(),
+    };
+    let _ = write_event(stream, &last).await;
+    let _ = write_event(stream, "[DONE]").await;
+    Ok(model)
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<ServerState>) {
+    let started = Instant::now();
+    let request = match read_request(&mut stream, &state).await {
+        Ok(request) => request,
+        Err(e) => {
+            let _ = write_json(&mut stream, e.status, &e.to_json()).await;
+            return;
+        }
+    };
+
+    let path = request.path.trim_end_matches('/');
+    let result = match (request.method.as_str(), path) {
+        ("GET", "/v1/models" | "/models") => {
+            let body = models_response(&state).await;
+            write_json(&mut stream, 200, &body)
+                .await
+                .map(|_| String::new())
+                .map_err(|e| ApiError::upstream(e.to_string()))
+        }
+        ("POST", "/v1/chat/completions" | "/chat/completions") => {
+            chat_completions(&mut stream, &state, &request.body).await
+        }
+        (_, "/v1/models" | "/models" | "/v1/chat/completions" | "/chat/completions") => {
+            Err(ApiError {
+                status: 405,
+                kind: "invalid_request_error",
+                message: format!("{} is not allowed on {}", request.method, path),
+            })
+        }
+        _ => Err(ApiError {
+            status: 404,
+            kind: "invalid_request_error",
+            message: format!("Unknown endpoint {}", path),
+        }),
+    };
+
+    let status = match result {
+        Ok(ref model) => format!("200 {}", model),
+        Err(ref e) => {
+            let _ = write_json(&mut stream, e.status, &e.to_json()).await;
+            format!("{} {}", e.status, e.message)
+        }
+    };
+    eprintln!(
+        "{} {} -> {} ({:.1}s)",
+        request.method,
+        request.path,
+        status.trim_end(),
+        started.elapsed().as_secs_f32()
+    );
+}
+
+/// Serves the OpenAI-compatible endpoints until the process is stopped
+pub fn run(options: ServeOptions) -> Result<(), String> {
+    let providers = ProviderSettings::load_headless()?;
+    let models = load_cached_models().unwrap_or_else(fallback_models);
+    let token = match options.token.filter(|t| !t.trim().is_empty()) {
+        Some(token) => token,
+        None => generate_token()?,
+    };
+    let state = Arc::new(ServerState {
+        bind: options.addr.ip(),
+        token,
+        providers,
+        default_model: commands::resolve_model(&models, options.model.as_deref()),
+        models,
+        system: options.system,
+    });
+
+    let runtime =
+        Runtime::new().map_err(|e| format!("Failed to start the async runtime: {}", e))?;
+    runtime.block_on(async {
+        let listener = TcpListener::bind(options.addr)
+            .await
+            .map_err(|e| format!("Failed to listen on {}: {}", options.addr, e))?;
+        eprintln!(
+            "Serving an OpenAI-compatible API at http://{}/v1 (default model {})",
+            options.addr, state.default_model
+        );
+        eprintln!("Clients must send 'Authorization: Bearer {}'", state.token);
+        if !options.addr.ip().is_loopback() {
+            eprintln!(
+                "Warning: anyone who can reach this address and has the token can use your API key"
+            );
+        }
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(stream, Arc::clone(&state)));
+                }
+                // Usually one client resetting or too many open files; both pass
+                Err(e) => {
+                    eprintln!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,109 @@
+use crate::api_client::GenerationSettings;
+use crate::context::{ContextSettings, ConversationSummary};
+use crate::conversation::ConversationTree;
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SESSIONS_DIR: &str = "sessions";
+const TITLE_LENGTH: usize = 60;
+
+/// A saved conversation: the whole tree of turns plus the settings it was run with
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub title: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub model: String,
+    #[serde(default)]
+    pub settings: GenerationSettings,
+    #[serde(default)]
+    pub context: ContextSettings,
+    #[serde(default)]
+    pub summary: Option<ConversationSummary>,
+    #[serde(default)]
+    pub system_instruction: String,
+    pub conversation: ConversationTree,
+}
+
+impl Session {
+    /// First user prompt of the conversation, shortened to fit in the session picker
+    pub fn title_for(conversation: &ConversationTree) -> String {
+        let first_prompt = conversation
+            .all_messages()
+            .find(|m| m.role == "user")
+            .map(|m| m.content.trim().replace('\n', " "))
+            .unwrap_or_default();
+
+        if first_prompt.is_empty() {
+            return "Untitled".to_string();
+        }
+        if first_prompt.chars().count() > TITLE_LENGTH {
+            let short: String = first_prompt.chars().take(TITLE_LENGTH).collect();
+            return format!("{}…", short.trim_end());
+        }
+        first_prompt
+    }
+}
+
+/// What the session picker needs, without keeping every conversation in memory
+#[derive(Clone, Debug, Deserialize)]
+pub struct SessionSummary {
+    pub id: String,
+    pub title: String,
+    pub updated_at: u64,
+}
+
+pub fn new_session_id() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    format!("session-{}", millis)
+}
+
+fn session_file(id: &str) -> Result<std::path::PathBuf, String> {
+    if id.is_empty() || id.contains(['/', '\\', '.']) {
+        return Err(format!("Invalid session id '{}'", id));
+    }
+    Ok(storage::data_subdir(SESSIONS_DIR)?.join(format!("{}.json", id)))
+}
+
+pub fn save_session(session: &Session) -> Result<(), String> {
+    let path = session_file(&session.id)?;
+    let text = serde_json::to_string(session)
+        .map_err(|e| format!("Failed to serialize session: {}", e))?;
+    fs::write(&path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub fn load_session(id: &str) -> Result<Session, String> {
+    let path = session_file(id)?;
+    let text = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+pub fn delete_session(id: &str) -> Result<(), String> {
+    let path = session_file(id)?;
+    fs::remove_file(&path).map_err(|e| format!("Failed to delete {}: {}", path.display(), e))
+}
+
+/// Every saved session, most recently updated first
+pub fn list_sessions() -> Result<Vec<SessionSummary>, String> {
+    let dir = storage::data_subdir(SESSIONS_DIR)?;
+    let entries =
+        fs::read_dir(&dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+
+    let mut sessions: Vec<SessionSummary> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .filter_map(|text| serde_json::from_str(&text).ok())
+        .collect();
+
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.updated_at));
+    Ok(sessions)
+}
@@ -0,0 +1,73 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Returns the per-user directory where GeminiOxide keeps its data, creating it if needed.
+pub fn data_dir() -> Result<PathBuf, String> {
+    let dir = platform_data_dir()?;
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    Ok(dir)
+}
+
+#[cfg(target_os = "windows")]
+fn platform_data_dir() -> Result<PathBuf, String> {
+    std::env::var_os("APPDATA")
+        .map(|appdata| PathBuf::from(appdata).join("GeminiOxide"))
+        .ok_or_else(|| "APPDATA is not set".to_string())
+}
+
+#[cfg(target_os = "macos")]
+fn platform_data_dir() -> Result<PathBuf, String> {
+    std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join("Library/Application Support/GeminiOxide"))
+        .ok_or_else(|| "HOME is not set".to_string())
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn platform_data_dir() -> Result<PathBuf, String> {
+    if let Some(config_home) = std::env::var_os("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+        return Ok(PathBuf::from(config_home).join("gemini-oxide"));
+    }
+    std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".config/gemini-oxide"))
+        .ok_or_else(|| "HOME is not set".to_string())
+}
+
+/// Returns a subdirectory of the data directory, creating it if needed.
+pub fn data_subdir(name: &str) -> Result<PathBuf, String> {
+    let dir = data_dir()?.join(name);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    Ok(dir)
+}
+
+/// Reads a JSON file from the data directory. Returns `Ok(None)` if it doesn't exist yet.
+pub fn read_json<T: DeserializeOwned>(file_name: &str) -> Result<Option<T>, String> {
+    let path = data_dir()?.join(file_name);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let text = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&text)
+        .map(Some)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+/// Writes a value as pretty-printed JSON into the data directory.
+pub fn write_json<T: Serialize>(file_name: &str, value: &T) -> Result<(), String> {
+    let path = data_dir()?.join(file_name);
+    let text = serde_json::to_string_pretty(value)
+        .map_err(|e| format!("Failed to serialize {}: {}", file_name, e))?;
+    fs::write(&path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Seconds since the Unix epoch, as stored in saved files
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
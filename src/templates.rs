@@ -0,0 +1,177 @@
+use crate::storage;
+use rfd::FileDialog;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+const LIBRARY_FILE: &str = "templates.json";
+
+/// A reusable prompt; `{{name}}` placeholders are filled in when it is used
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PromptTemplate {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub body: String,
+}
+
+impl PromptTemplate {
+    /// Placeholder names in order of first appearance
+    pub fn variables(&self) -> Vec<String> {
+        let mut variables: Vec<String> = Vec::new();
+        let mut rest = self.body.as_str();
+        while let Some(start) = rest.find("{{") {
+            let after = &rest[start + 2..];
+            let Some(end) = after.find("}}") else {
+                break;
+            };
+            let name = after[..end].trim();
+            if !name.is_empty() && !variables.iter().any(|v| v == name) {
+                variables.push(name.to_string());
+            }
+            rest = &after[end + 2..];
+        }
+        variables
+    }
+
+    /// The body with every placeholder replaced; unknown ones are left as written
+    pub fn fill(&self, values: &BTreeMap<String, String>) -> String {
+        let mut filled = String::with_capacity(self.body.len());
+        let mut rest = self.body.as_str();
+        while let Some(start) = rest.find("{{") {
+            let after = &rest[start + 2..];
+            let Some(end) = after.find("}}") else {
+                break;
+            };
+            filled.push_str(&rest[..start]);
+            match values.get(after[..end].trim()) {
+                Some(value) => filled.push_str(value),
+                None => filled.push_str(&rest[start..start + 2 + end + 2]),
+            }
+            rest = &after[end + 2..];
+        }
+        filled.push_str(rest);
+        filled
+    }
+}
+
+/// All templates; this is also the JSON/TOML file format used for sharing
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TemplateLibrary {
+    #[serde(default)]
+    pub templates: Vec<PromptTemplate>,
+}
+
+impl Default for TemplateLibrary {
+    fn default() -> Self {
+        Self {
+            templates: vec![
+                PromptTemplate {
+                    name: "Review diff".to_string(),
+                    description: "Code review of a diff".to_string(),
+                    body: "Review this diff. Point out bugs, risky changes and missing tests, \
+most important first.\n\n```diff\n{{diff}}\n```"
+                        .to_string(),
+                },
+                PromptTemplate {
+                    name: "Translate".to_string(),
+                    description: "Translation into another language".to_string(),
+                    body: "Translate the following text to {{language}}. Keep the formatting.\n\n\
+{{text}}"
+                        .to_string(),
+                },
+            ],
+        }
+    }
+}
+
+impl TemplateLibrary {
+    pub fn load() -> Result<Self, String> {
+        Ok(storage::read_json(LIBRARY_FILE)?.unwrap_or_default())
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        storage::write_json(LIBRARY_FILE, self)
+    }
+
+    /// Adds the imported templates, replacing ones with the same name; returns how many
+    pub fn merge(&mut self, imported: TemplateLibrary) -> usize {
+        let count = imported.templates.len();
+        for template in imported.templates {
+            match self.templates.iter_mut().find(|t| t.name == template.name) {
+                Some(existing) => *existing = template,
+                None => self.templates.push(template),
+            }
+        }
+        count
+    }
+
+    /// Reads a `.json` or `.toml` file
+    pub fn read_file(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if is_toml(path) {
+            toml::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+        } else {
+            serde_json::from_str(&text)
+                .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+        }
+    }
+
+    /// Writes a `.json` or `.toml` file, depending on the extension
+    pub fn write_file(&self, path: &Path) -> Result<(), String> {
+        let text = if is_toml(path) {
+            toml::to_string_pretty(self).map_err(|e| e.to_string())?
+        } else {
+            serde_json::to_string_pretty(self).map_err(|e| e.to_string())?
+        };
+        fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    /// Asks for a file and merges its templates in; returns how many were imported
+    pub fn import_dialog(&mut self) -> Result<usize, String> {
+        let Some(path) = FileDialog::new()
+            .add_filter("templates", &["json", "toml"])
+            .pick_file()
+        else {
+            return Ok(0);
+        };
+        let imported = Self::read_file(&path)?;
+        Ok(self.merge(imported))
+    }
+
+    pub fn export_dialog(&self) -> Result<(), String> {
+        let Some(path) = FileDialog::new()
+            .add_filter("JSON", &["json"])
+            .add_filter("TOML", &["toml"])
+            .set_file_name("prompt-templates.json")
+            .save_file()
+        else {
+            return Ok(());
+        };
+        self.write_file(&path)
+    }
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("toml"))
+}
+
+/// Values being entered for a template's placeholders before it is inserted
+pub struct TemplateForm {
+    pub template: PromptTemplate,
+    pub values: BTreeMap<String, String>,
+}
+
+impl TemplateForm {
+    pub fn new(template: PromptTemplate) -> Self {
+        let values = template
+            .variables()
+            .into_iter()
+            .map(|name| (name, String::new()))
+            .collect();
+        Self { template, values }
+    }
+}
@@ -0,0 +1,225 @@
+use std::io::IsTerminal;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const ITALIC: &str = "\x1b[3m";
+const UNDERLINE: &str = "\x1b[4m";
+const CYAN: &str = "\x1b[36m";
+const YELLOW: &str = "\x1b[33m";
+
+/// Colors are used only on a terminal, and never when `NO_COLOR` is set
+pub fn color_enabled() -> bool {
+    std::io::stdout().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none()
+        && enable_escape_codes()
+}
+
+/// Windows consoles show escape codes literally unless virtual terminal processing is on
+#[cfg(windows)]
+fn enable_escape_codes() -> bool {
+    use windows_sys::Win32::System::Console::{
+        GetConsoleMode, GetStdHandle, SetConsoleMode, ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+        STD_OUTPUT_HANDLE,
+    };
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut mode = 0;
+        GetConsoleMode(handle, &mut mode) != 0
+            && (mode & ENABLE_VIRTUAL_TERMINAL_PROCESSING != 0
+                || SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0)
+    }
+}
+
+#[cfg(not(windows))]
+fn enable_escape_codes() -> bool {
+    true
+}
+
+/// `text` without control characters other than tabs and newlines, so model output can't
+/// send escape sequences to the terminal
+pub fn strip_controls(text: &str) -> String {
+    text.chars()
+        .filter(|&c| matches!(c, '\t' | '\n') || !c.is_control())
+        .collect()
+}
+
+/// `text` in yellow when colors are enabled, e.g. for notices between answers
+pub fn notice(text: &str, color: bool) -> String {
+    if color {
+        format!("{}{}{}", YELLOW, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Renders streamed markdown to ANSI one complete line at a time
+pub struct MarkdownPrinter {
+    color: bool,
+    in_code_block: bool,
+    pending: String,
+}
+
+impl MarkdownPrinter {
+    pub fn new(color: bool) -> Self {
+        Self {
+            color,
+            in_code_block: false,
+            pending: String::new(),
+        }
+    }
+
+    /// Adds streamed text and returns the lines it completed, rendered
+    pub fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+        let mut out = String::new();
+        while let Some(end) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=end).collect();
+            out.push_str(&self.render_line(line.trim_end_matches(['\r', '\n'])));
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Renders whatever is left after the last newline
+    pub fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        self.in_code_block = false;
+        if rest.is_empty() {
+            String::new()
+        } else {
+            format!("{}\n", self.render_line(&rest))
+        }
+    }
+
+    fn render_line(&mut self, line: &str) -> String {
+        let line = &strip_controls(line);
+        if !self.color {
+            return line.to_string();
+        }
+
+        let trimmed = line.trim_start();
+        if let Some(language) = trimmed.strip_prefix("```") {
+            self.in_code_block = !self.in_code_block;
+            return format!("{}{}{}", DIM, language.trim(), RESET);
+        }
+        if self.in_code_block {
+            return format!("  {}{}{}", CYAN, line, RESET);
+        }
+
+        let heading_level = trimmed.chars().take_while(|&c| c == '#').count();
+        if (1..=6).contains(&heading_level) && trimmed[heading_level..].starts_with(' ') {
+            let base = if heading_level == 1 {
+                format!("{}{}", BOLD, UNDERLINE)
+            } else {
+                BOLD.to_string()
+            };
+            return format!(
+                "{}{}{}",
+                base,
+                render_inline(trimmed[heading_level..].trim(), &base),
+                RESET
+            );
+        }
+
+        if ["---", "***", "___"].contains(&trimmed.trim_end()) {
+            return format!("{}{}{}", DIM, "─".repeat(40), RESET);
+        }
+
+        if let Some(quote) = trimmed.strip_prefix('>') {
+            return format!(
+                "{}│{} {}",
+                DIM,
+                RESET,
+                render_inline(quote.trim_start(), "")
+            );
+        }
+
+        let indent = &line[..line.len() - trimmed.len()];
+        for bullet in ["- ", "* ", "+ "] {
+            if let Some(item) = trimmed.strip_prefix(bullet) {
+                return format!("{}• {}", indent, render_inline(item, ""));
+            }
+        }
+
+        render_inline(line, "")
+    }
+}
+
+/// Bold, italics, inline code and links; `base` is the style to return to after each span
+fn render_inline(text: &str, base: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    let mut previous: Option<char> = None;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '`' {
+            if let Some(end) = rest[1..].find('`') {
+                out.push_str(&format!("{}{}{}{}", CYAN, &rest[1..1 + end], RESET, base));
+                rest = &rest[end + 2..];
+                previous = Some('`');
+                continue;
+            }
+        }
+
+        if rest.starts_with("**") || rest.starts_with("__") {
+            let marker = &rest[..2];
+            if let Some(end) = rest[2..].find(marker).filter(|&end| end > 0) {
+                let style = format!("{}{}", base, BOLD);
+                out.push_str(&format!(
+                    "{}{}{}{}",
+                    BOLD,
+                    render_inline(&rest[2..2 + end], &style),
+                    RESET,
+                    base
+                ));
+                rest = &rest[end + 4..];
+                previous = Some('*');
+                continue;
+            }
+        }
+
+        // `_` only counts at a word boundary, so snake_case names stay intact
+        let opens_italic = (c == '*' || (c == '_' && !previous.is_some_and(char::is_alphanumeric)))
+            && rest[1..].starts_with(|next: char| !next.is_whitespace());
+        if opens_italic {
+            if let Some(end) = rest[1..].find(c).filter(|&end| end > 0) {
+                let style = format!("{}{}", base, ITALIC);
+                out.push_str(&format!(
+                    "{}{}{}{}",
+                    ITALIC,
+                    render_inline(&rest[1..1 + end], &style),
+                    RESET,
+                    base
+                ));
+                rest = &rest[end + 2..];
+                previous = Some(c);
+                continue;
+            }
+        }
+
+        if c == '[' {
+            let link = rest[1..]
+                .split_once("](")
+                .filter(|(label, _)| !label.contains(']'))
+                .and_then(|(label, after)| {
+                    let (url, after) = after.split_once(')')?;
+                    (!url.contains(char::is_whitespace)).then_some((label, url, after))
+                });
+            if let Some((label, url, after)) = link {
+                out.push_str(&format!(
+                    "{}{}{}{} {}({}){}{}",
+                    UNDERLINE, label, RESET, base, DIM, url, RESET, base
+                ));
+                rest = after;
+                previous = Some(')');
+                continue;
+            }
+        }
+
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+        previous = Some(c);
+    }
+    out
+}
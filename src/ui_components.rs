@@ -1,26 +1,103 @@
-use crate::api_client::spawn_async_request;
+use crate::api_client::{
+    fallback_models, load_cached_models, save_cached_models, spawn_create_context_cache,
+    spawn_delete_context_cache, GenerationSettings, ModelInfo, ModelResponse, ThinkingControl,
+    BLOCK_THRESHOLDS, DEFAULT_MODEL, DEFAULT_THRESHOLD, GEMINI_PROVIDER, HARM_CATEGORIES,
+    MAX_CANDIDATES, THINKING_LEVELS,
+};
+use crate::commands::{self, Command, ImageSource, COMMANDS};
+use crate::compare::{Comparison, MAX_COMPARE_MODELS, MIN_COMPARE_MODELS};
+use crate::context::{
+    self, estimate_text_tokens, estimate_tokens, ContextSettings, ContextStrategy,
+    ConversationSummary,
+};
+use crate::conversation::{ChatMessage, ConversationTree, MAX_TOKENS_FINISH_REASON};
+use crate::diagnostics::{self, RequestRecord};
+use crate::documents::{self, ContextCache, SharedContext, DEFAULT_CACHE_TTL_MINUTES};
+use crate::export::{self, ExportFormat, ExportedConversation};
 use crate::img_utils;
+use crate::img_utils::GeneratedImage;
+use crate::keystore::KeyStorage;
+use crate::prompt_editor::{EditorPreferences, SendShortcut};
+use crate::providers::{
+    self, ChatRequest, ModelListing, ProviderConfig, ProviderKind, ProviderSettings,
+};
+use crate::search::{self, DateFilter, SearchIndex, SearchQuery};
+use crate::session::{self, Session, SessionSummary};
+use crate::storage;
+use crate::templates::{PromptTemplate, TemplateForm, TemplateLibrary};
 use copypasta::{ClipboardContext, ClipboardProvider};
 use eframe::egui;
 use egui::{ColorImage, Spinner, TextureHandle};
 use egui_commonmark::{CommonMarkCache, CommonMarkViewer};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
-#[derive(Clone, Debug)]
-pub struct ChatMessage {
-    pub role: String,
-    pub content: String,
+const FALLBACK_MODEL: &str = "gemini-2.5-flash";
+const UNKNOWN_LIMIT_MAX_BUDGET: u64 = 1_048_576;
+const CONTINUE_PROMPT: &str = "Continue exactly where your previous answer was cut off. \
+Do not repeat anything you already wrote.";
+
+/// Per-message actions triggered from the history window; all take node ids
+enum HistoryAction {
+    Continue(usize),
+    StartEdit(usize),
+    SubmitEdit,
+    CancelEdit,
+    /// Model message to regenerate, optionally with the model to switch to first
+    Regenerate(usize, Option<String>),
+    SelectSibling(usize, isize),
+    TogglePin(usize),
+}
+
+/// Where the answer to the running request goes in the conversation tree
+enum PendingTurn {
+    /// A new user turn (plus its answer) under this node, or as a new root
+    Prompt(Option<usize>),
+    /// A new answer to this existing user turn
+    Regenerate(usize),
+    /// A continuation joined onto this truncated model turn
+    Continue(usize),
+}
+
+/// A new key for one provider, checked with a models request before it is saved
+struct KeyRotation {
+    /// Index into the provider list
+    provider: usize,
+    new_key: String,
+    check: Option<JoinHandle<Result<usize, String>>>,
+    /// Number of models the new key can use, or why the check failed
+    result: Option<Result<usize, String>>,
 }
 
 pub struct UIState {
     pub prompt: String,
     pub last_prompt: String,
     pub llm_response: String,
-    pub chat_history: Vec<ChatMessage>,
+    pub llm_thoughts: Option<String>,
+    pub llm_finish_reason: Option<String>,
+    pub llm_images: Vec<GeneratedImage>,
+    /// Textures for generated images, keyed by `GeneratedImage::id`
+    pub image_textures: HashMap<u64, TextureHandle>,
+    pending_turn: Option<PendingTurn>,
+    /// User message (node id) being edited in the history window, with the draft text
+    pub editing_message: Option<(usize, String)>,
+    /// Model the running request was sent to
+    pub request_model: String,
+    pub chat_history: ConversationTree,
+    pub session_id: String,
+    pub session_created_at: u64,
+    pub saved_sessions: Vec<SessionSummary>,
+    /// "Delete Session..." was clicked and waits for confirmation
+    confirm_delete_session: bool,
     pub show_history_window: bool,
     pub is_loading: bool,
-    pub client_thread: Option<JoinHandle<Result<String, ()>>>,
+    pub client_thread: Option<JoinHandle<Result<Vec<ModelResponse>, String>>>,
+    /// Editor text of the request in flight, put back if the request fails
+    sent_prompt: Option<String>,
+    /// Alternative answers waiting for the user to pick one for `chat_history`
+    pub pending_candidates: Vec<ModelResponse>,
     pub commonmark_cache: CommonMarkCache,
     pub captured_img: Option<ColorImage>,
     pub captured_img_texture: Option<TextureHandle>,
@@ -28,6 +105,50 @@ pub struct UIState {
     pub error_message: Option<String>,
     pub first_frame: bool,
     pub ai_model: String,
+    pub available_models: Vec<ModelInfo>,
+    pub models_thread: Option<JoinHandle<Result<ModelListing, String>>>,
+    pub provider_settings: ProviderSettings,
+    pub show_providers_window: bool,
+    /// Unlocks the encrypted key file; kept in memory only
+    key_passphrase: String,
+    key_rotation: Option<KeyRotation>,
+    pub generation_settings: GenerationSettings,
+    pub context_settings: ContextSettings,
+    pub conversation_summary: Option<ConversationSummary>,
+    /// Running summarization: the last node it covers, and the request
+    pub summary_thread: Option<(usize, JoinHandle<Result<String, String>>)>,
+    pub show_settings_window: bool,
+    /// System instruction and documents; attachments are not saved with the session
+    pub shared_context: SharedContext,
+    pub context_cache: Option<ContextCache>,
+    pub cache_thread: Option<JoinHandle<Result<ContextCache, String>>>,
+    /// Creates that were still running when their cache was deleted; whatever they
+    /// create is deleted as soon as they finish
+    abandoned_caches: Vec<JoinHandle<Result<ContextCache, String>>>,
+    pub cache_ttl_minutes: u64,
+    pub show_documents_window: bool,
+    pub editor_preferences: EditorPreferences,
+    pub template_library: TemplateLibrary,
+    /// Template whose placeholders are being filled in
+    pub template_form: Option<TemplateForm>,
+    pub selected_template: Option<usize>,
+    pub show_templates_window: bool,
+    /// Result or usage hint of the last slash command, shown under the prompt
+    pub command_status: Option<String>,
+    /// Index over all saved sessions; built in the background when History first opens
+    pub search_index: Option<SearchIndex>,
+    pub search_thread: Option<JoinHandle<Result<SearchIndex, String>>>,
+    pub search_query: SearchQuery,
+    /// Message to scroll to the next time the History window is drawn
+    pub jump_to_node: Option<usize>,
+    /// Models checked in the Compare window
+    pub compare_models: Vec<String>,
+    pub comparison: Option<Comparison>,
+    pub show_compare_window: bool,
+    pub show_diagnostics_window: bool,
+    /// Request shown in the diagnostics window
+    pub selected_request: Option<u64>,
+    pub resend_thread: Option<JoinHandle<Result<(), String>>>,
 }
 
 impl Default for UIState {
@@ -36,298 +157,1606 @@ impl Default for UIState {
             prompt: String::new(),
             last_prompt: String::new(),
             llm_response: String::new(),
-            chat_history: Vec::new(),
+            llm_thoughts: None,
+            llm_finish_reason: None,
+            llm_images: Vec::new(),
+            image_textures: HashMap::new(),
+            pending_turn: None,
+            editing_message: None,
+            request_model: String::new(),
+            chat_history: ConversationTree::default(),
+            session_id: session::new_session_id(),
+            session_created_at: storage::now_secs(),
+            saved_sessions: Vec::new(),
+            confirm_delete_session: false,
             show_history_window: false,
             is_loading: false,
             client_thread: None,
+            sent_prompt: None,
+            pending_candidates: Vec::new(),
             commonmark_cache: CommonMarkCache::default(),
             captured_img: None,
             captured_img_texture: None,
             show_image_buttons: false,
             error_message: None,
             first_frame: true,
-            ai_model: "gemini-3.1-pro-preview".to_string(),
+            ai_model: DEFAULT_MODEL.to_string(),
+            available_models: fallback_models(),
+            models_thread: None,
+            provider_settings: ProviderSettings::default(),
+            show_providers_window: false,
+            key_passphrase: String::new(),
+            key_rotation: None,
+            generation_settings: GenerationSettings::default(),
+            context_settings: ContextSettings::default(),
+            conversation_summary: None,
+            summary_thread: None,
+            show_settings_window: false,
+            shared_context: SharedContext::default(),
+            context_cache: None,
+            cache_thread: None,
+            abandoned_caches: Vec::new(),
+            cache_ttl_minutes: DEFAULT_CACHE_TTL_MINUTES,
+            show_documents_window: false,
+            editor_preferences: EditorPreferences::default(),
+            template_library: TemplateLibrary::default(),
+            template_form: None,
+            selected_template: None,
+            show_templates_window: false,
+            command_status: None,
+            search_index: None,
+            search_thread: None,
+            search_query: SearchQuery::default(),
+            jump_to_node: None,
+            compare_models: Vec::new(),
+            comparison: None,
+            show_compare_window: false,
+            show_diagnostics_window: false,
+            selected_request: None,
+            resend_thread: None,
         }
     }
 }
 
 impl UIState {
-    pub fn update_llm_response(&mut self, response: String) {
-        self.llm_response = response.clone();
-        self.chat_history.push(ChatMessage {
-            role: "user".to_string(),
-            content: self.last_prompt.clone(),
-        });
-        self.chat_history.push(ChatMessage {
-            role: "model".to_string(),
-            content: response,
-        });
+    /// Handles the candidates returned by a finished request
+    pub fn handle_responses(&mut self, responses: Vec<ModelResponse>) {
+        let continuing = matches!(self.pending_turn, Some(PendingTurn::Continue(_)));
+        if !continuing && responses.len() > 1 {
+            self.pending_candidates = responses;
+            return;
+        }
+
+        if let Some(response) = responses.into_iter().next() {
+            self.update_llm_response(response);
+        }
     }
 
-    pub fn start_async_request(&mut self, prompt: String) {
-        if !self.is_loading && !prompt.trim().is_empty() {
-            self.is_loading = true;
+    /// Commits one of the pending candidates as the model turn
+    pub fn choose_candidate(&mut self, idx: usize) {
+        if idx < self.pending_candidates.len() {
+            let chosen = self.pending_candidates.swap_remove(idx);
+            self.pending_candidates.clear();
+            self.update_llm_response(chosen);
+        }
+    }
 
-            // Capture last 10 messages for context
-            let history_len = self.chat_history.len();
-            let start_idx = if history_len > 10 {
-                history_len - 10
-            } else {
-                0
-            };
-            let history = self.chat_history[start_idx..].to_vec();
+    pub fn update_llm_response(&mut self, response: ModelResponse) {
+        let pending_turn = self.pending_turn.take();
+        if let Some(PendingTurn::Continue(node)) = pending_turn {
+            self.append_continuation(node, response);
+            return;
+        }
 
-            self.last_prompt = prompt.clone();
-            let sent_model = self.ai_model.clone();
-            self.client_thread = Some(spawn_async_request(
-                prompt,
-                sent_model,
-                self.captured_img.clone(),
-                history,
-            ));
-            self.prompt.clear();
-            self.llm_response.clear();
+        self.llm_response = response.text.clone();
+        self.llm_thoughts = response.thoughts.clone();
+        self.llm_finish_reason = response.finish_reason.clone();
+        self.llm_images = response.images.clone();
+
+        // A blocked turn has no content worth sending back as context
+        if let Some(reason) = response.blocked {
+            self.set_error(reason);
+            return;
         }
+
+        let user_node = match pending_turn {
+            Some(PendingTurn::Regenerate(user_node)) => user_node,
+            Some(PendingTurn::Prompt(parent)) => self
+                .chat_history
+                .add_child(parent, ChatMessage::user(self.last_prompt.clone())),
+            _ => self.chat_history.add_child(
+                self.chat_history.active_leaf(),
+                ChatMessage::user(self.last_prompt.clone()),
+            ),
+        };
+        self.chat_history.add_child(
+            Some(user_node),
+            ChatMessage {
+                role: "model".to_string(),
+                content: response.text,
+                thoughts: response.thoughts,
+                finish_reason: response.finish_reason,
+                images: response.images,
+                model: Some(self.request_model.clone()),
+                pinned: false,
+                created_at: storage::now_secs(),
+                latency_ms: response.latency_ms,
+            },
+        );
+        self.save_session();
+        self.summarize_dropped_messages();
     }
 
-    pub fn get_image_texture(&mut self, ctx: &egui::Context) -> Option<&TextureHandle> {
-        if let Some(ref color_img) = self.captured_img {
-            if self.captured_img_texture.is_none() {
-                let texture = ctx.load_texture(
-                    "captured_image",
-                    color_img.clone(),
-                    egui::TextureOptions::default(),
-                );
-                self.captured_img_texture = Some(texture);
+    /// A request that failed adds no turn; the prompt goes back into the editor to retry
+    fn request_failed(&mut self, error: String) {
+        self.pending_turn = None;
+        if let Some(prompt) = self.sent_prompt.take() {
+            if self.prompt.trim().is_empty() {
+                self.prompt = prompt;
             }
-            self.captured_img_texture.as_ref()
-        } else {
-            None
         }
+        self.sync_latest_response();
+        self.set_error(error);
     }
 
-    pub fn set_error(&mut self, error: String) {
-        self.error_message = Some(error);
-    }
+    /// Joins a continuation onto the truncated model message it was requested for
+    fn append_continuation(&mut self, node: usize, response: ModelResponse) {
+        if let Some(reason) = response.blocked {
+            self.set_error(reason);
+            return;
+        }
+        let Some(msg) = self.chat_history.get_mut(node) else {
+            return;
+        };
 
-    pub fn clear_error(&mut self) {
-        self.error_message = None;
+        msg.content.push_str(&response.text);
+        if let Some(thoughts) = response.thoughts {
+            let joined = match msg.thoughts.take() {
+                Some(previous) => format!("{}\n\n{}", previous, thoughts),
+                None => thoughts,
+            };
+            msg.thoughts = Some(joined);
+        }
+        msg.finish_reason = response.finish_reason;
+        msg.images.extend(response.images);
+
+        self.sync_latest_response();
+        self.save_session();
     }
 
-    pub fn render_prompt_section(&mut self, app_ui: &mut egui::Ui) -> bool {
-        app_ui.heading("Enter a prompt:");
-        app_ui.add_space(3.0);
+    /// Shows the answer at the end of the active branch in the response section
+    fn sync_latest_response(&mut self) {
+        let leaf = self.chat_history.active_leaf();
+        let latest = leaf.and_then(|node| self.chat_history.get(node));
+        match latest {
+            Some(msg) if msg.role == "model" => {
+                self.llm_response = msg.content.clone();
+                self.llm_thoughts = msg.thoughts.clone();
+                self.llm_finish_reason = msg.finish_reason.clone();
+                self.llm_images = msg.images.clone();
+                let prompt = leaf
+                    .and_then(|node| self.chat_history.parent(node))
+                    .and_then(|node| self.chat_history.get(node));
+                self.last_prompt = prompt.map(|m| m.content.clone()).unwrap_or_default();
+            }
+            _ => {
+                self.llm_response.clear();
+                self.llm_thoughts = None;
+                self.llm_finish_reason = None;
+                self.llm_images.clear();
+            }
+        }
+    }
 
-        let mut should_generate = false;
+    /// The messages on the way to `end` that the context strategy sends along,
+    /// led by the running summary when it stands in for dropped ones
+    fn context_history(&self, end: Option<usize>) -> Vec<ChatMessage> {
+        let path = self.chat_history.path_to(end);
+        let messages: Vec<&ChatMessage> = path
+            .iter()
+            .filter_map(|&node| self.chat_history.get(node))
+            .collect();
+        let included = self
+            .context_settings
+            .select(&messages, self.selected_model());
 
-        app_ui.with_layout(
-            egui::Layout::top_down_justified(egui::Align::Center),
-            |ui| {
-                let response = ui.text_edit_singleline(&mut self.prompt);
-                if self.first_frame {
-                    response.request_focus();
-                    self.first_frame = false;
-                }
-                if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-                    should_generate = true;
-                }
-            },
-        );
-        app_ui.add_space(3.0);
+        let summary = self
+            .active_summary(&path, &included)
+            .map(|summary| summary.to_message());
+        summary
+            .into_iter()
+            .chain(
+                messages
+                    .into_iter()
+                    .zip(included)
+                    .filter(|(_, keep)| *keep)
+                    .map(|(msg, _)| msg.clone()),
+            )
+            .collect()
+    }
 
-        should_generate
+    /// The running summary, if summarizing is on and it covers messages of `path`
+    /// that `included` leaves out
+    fn active_summary(&self, path: &[usize], included: &[bool]) -> Option<&ConversationSummary> {
+        if !self.context_settings.summarize_dropped {
+            return None;
+        }
+        let summary = self.conversation_summary.as_ref()?;
+        let pos = path.iter().position(|&node| node == summary.through)?;
+        (!included[pos]).then_some(summary)
     }
 
-    pub fn render_action_buttons(
-        &mut self,
-        app_ui: &mut egui::Ui,
-        ctx: &egui::Context,
-    ) -> (bool, bool) {
-        let mut should_generate = false;
-        let mut img_context = false;
+    /// Starts folding the messages the next request would drop into the running summary
+    fn summarize_dropped_messages(&mut self) {
+        if !self.context_settings.summarize_dropped || self.summary_thread.is_some() {
+            return;
+        }
 
-        app_ui.horizontal(|ui| {
-            if ui
-                .add_enabled(!self.is_loading, egui::Button::new("Generate"))
-                .clicked()
-            {
-                should_generate = true;
-                self.clear_error();
-                self.show_image_buttons = false;
-            }
+        let path = self.chat_history.active_path();
+        let messages: Vec<&ChatMessage> = path
+            .iter()
+            .filter_map(|&node| self.chat_history.get(node))
+            .collect();
+        let included = self
+            .context_settings
+            .select(&messages, self.selected_model());
+        let Some(last_dropped) = context::last_dropped(&included) else {
+            return;
+        };
 
-            let button_text = if self.show_image_buttons {
-                "Hide Image Options"
-            } else {
-                "Add Image"
-            };
+        // Only the messages after the part the current summary already covers
+        let previous = self.conversation_summary.as_ref().and_then(|summary| {
+            path.iter()
+                .position(|&node| node == summary.through)
+                .map(|pos| (pos, summary.text.as_str()))
+        });
+        let start = previous.map_or(0, |(pos, _)| pos + 1);
+        if start > last_dropped {
+            return;
+        }
+        let new_messages: Vec<ChatMessage> = messages[start..=last_dropped]
+            .iter()
+            .zip(&included[start..=last_dropped])
+            .filter(|(_, &keep)| !keep)
+            .map(|(msg, _)| (*msg).clone())
+            .collect();
 
-            if ui
-                .add_enabled(!self.is_loading, egui::Button::new(button_text))
-                .clicked()
-            {
-                self.show_image_buttons = !self.show_image_buttons;
-                self.clear_error();
+        let prompt = context::summary_prompt(previous.map(|(_, text)| text), &new_messages);
+        let model = self.context_settings.summary_model.clone();
+        let settings = GenerationSettings {
+            include_thoughts: false,
+            candidate_count: 1,
+            ..GenerationSettings::default()
+        }
+        .for_model(self.available_models.iter().find(|m| m.name == model));
+        let provider = match self.provider_settings.provider_for_model(&model) {
+            Ok(provider) => provider,
+            Err(e) => {
+                self.set_error(format!("Failed to summarize earlier messages: {}", e));
+                return;
             }
+        };
+        self.summary_thread = Some((
+            path[last_dropped],
+            providers::spawn_summary_request(provider, prompt, model, settings),
+        ));
+    }
 
-            if ui.button("History").clicked() {
-                self.show_history_window = !self.show_history_window;
+    pub fn poll_summary(&mut self, ctx: &egui::Context) {
+        if let Some((through, handle)) = self.summary_thread.take() {
+            if !handle.is_finished() {
+                self.summary_thread = Some((through, handle));
+                ctx.request_repaint_after(Duration::from_millis(200));
+                return;
             }
 
-            if let Some(ref _color_image) = self.captured_img {
-                if ui
-                    .add_enabled(!self.is_loading, egui::Button::new("Clear Image"))
-                    .clicked()
-                {
-                    self.captured_img = None;
-                    self.captured_img_texture = None; // Clear texture cache
-                    self.clear_error();
+            match handle.join() {
+                Ok(Ok(text)) => {
+                    self.conversation_summary = Some(ConversationSummary { text, through });
+                    self.save_session();
                 }
+                Ok(Err(e)) => {
+                    self.set_error(format!("Failed to summarize earlier messages: {}", e))
+                }
+                Err(_) => self.set_error("Summary request panicked".to_string()),
             }
+            ctx.request_repaint();
+        }
+    }
 
-            egui::ComboBox::new("ai_model_selector", "")
-                .selected_text(&self.ai_model)
-                .show_ui(ui, |ui| {
-                    ui.selectable_value(
-                        &mut self.ai_model,
-                        "gemini-3.1-pro-preview".to_string(),
-                        "gemini-3.1-pro-preview",
-                    );
-                    ui.selectable_value(
-                        &mut self.ai_model,
-                        "gemini-2.5-pro".to_string(),
-                        "gemini-2.5-pro",
-                    );
-                    ui.selectable_value(
-                        &mut self.ai_model,
-                        "gemini-2.5-flash".to_string(),
-                        "gemini-2.5-flash",
-                    );
-
-                    ui.selectable_value(
-                        &mut self.ai_model,
-                        "gemini-2.5-flash-lite".to_string(),
-                        "gemini-2.5-flash-lite",
-                    );
-                });
-        });
+    /// Runs the prompt as a slash command, or sends it to the model
+    pub fn submit_prompt(&mut self, ctx: &egui::Context) {
+        let prompt = self.prompt.clone();
+        let Some(command) = commands::parse(&prompt) else {
+            self.command_status = None;
+            self.start_async_request(commands::unescape(&prompt).to_string());
+            return;
+        };
 
-        if self.show_image_buttons {
-            egui::Window::new("Image Options")
-                .collapsible(false)
-                .resizable(false)
-                .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
-                .show(ctx, |ui| {
-                    ui.horizontal(|ui| {
-                        if ui
-                            .add_enabled(!self.is_loading, egui::Button::new("Pick Image"))
-                            .clicked()
-                        {
-                            match img_utils::pick_image_file() {
-                                Ok(color_image) => {
-                                    self.captured_img = Some(color_image);
-                                    self.captured_img_texture = None; // Clear texture cache to force recreation
-                                    img_context = true;
-                                    self.show_image_buttons = false;
-                                    self.clear_error();
-                                }
-                                Err(e) => {
-                                    self.set_error(format!("Image pick failed: {}", e));
-                                }
-                            }
-                        }
+        self.editor_preferences.history.push(&prompt);
+        self.save_editor_preferences();
+        match command {
+            Ok(command) => {
+                self.prompt.clear();
+                self.command_status = None;
+                self.run_command(command, ctx);
+            }
+            // Keep the prompt so the command can be fixed
+            Err(e) => self.command_status = Some(e),
+        }
+    }
 
-                        if ui
-                            .add_enabled(!self.is_loading, egui::Button::new("Paste Image"))
-                            .clicked()
-                        {
-                            match img_utils::image_from_clipboard(ctx) {
-                                Ok(color_image) => {
-                                    self.captured_img = Some(color_image);
-                                    self.captured_img_texture = None; // Clear texture cache to force recreation
-                                    self.show_image_buttons = false;
-                                    self.clear_error();
-                                }
-                                Err(e) => {
-                                    self.set_error(format!("Failed to paste image: {}", e));
-                                }
-                            }
-                        }
+    fn run_command(&mut self, command: Command, ctx: &egui::Context) {
+        match command {
+            Command::Model(query) => match commands::find_model(&self.available_models, &query) {
+                Some(model) => {
+                    self.ai_model = model.name.clone();
+                    self.command_status = Some(format!("Switched to {}", model.label()));
+                }
+                None => self.command_status = Some(format!("No model matches '{}'", query)),
+            },
+            Command::Clear => {
+                if self.is_loading {
+                    self.command_status = Some("Wait for the running request first".to_string());
+                } else {
+                    self.new_session();
+                    self.command_status = Some("Started a new conversation".to_string());
+                }
+            }
+            Command::Image(source) => {
+                let image = match source {
+                    ImageSource::Paste => img_utils::image_from_clipboard(ctx).map(Some),
+                    ImageSource::File => img_utils::pick_image_file().map(Some),
+                    ImageSource::Clear => Ok(None),
+                };
+                match image {
+                    Ok(image) => {
+                        self.captured_img = image;
+                        self.captured_img_texture = None;
+                        self.show_image_buttons = false;
+                        self.clear_error();
+                    }
+                    Err(e) => self.set_error(format!("Failed to load image: {}", e)),
+                }
+            }
+            Command::System(text) if text.is_empty() => self.show_documents_window = true,
+            Command::System(text) => {
+                if self.context_cache.is_some() || self.cache_thread.is_some() {
+                    self.command_status = Some(
+                        "Delete the context cache before changing the system instruction"
+                            .to_string(),
+                    );
+                } else {
+                    self.shared_context.system_instruction = text;
+                    self.save_session();
+                    self.command_status = Some("System instruction set".to_string());
+                }
+            }
+            Command::Export(format) => self.export_conversation(format),
+            Command::Template(query) => {
+                match commands::find_template(&self.template_library.templates, &query) {
+                    Some(template) => self.use_template(template.clone()),
+                    None => self.command_status = Some(format!("No template matches '{}'", query)),
+                }
+            }
+            Command::Help => {
+                let help: Vec<String> = COMMANDS
+                    .iter()
+                    .map(|c| format!("{}  {}", c.usage, c.description))
+                    .collect();
+                self.command_status = Some(help.join("\n"));
+            }
+        }
+    }
 
-                        if ui.button("Cancel").clicked() {
-                            self.show_image_buttons = false;
-                            self.clear_error();
-                        }
-                    });
-                });
+    /// Writes the active branch of the conversation to a file chosen by the user
+    pub fn export_conversation(&mut self, format: ExportFormat) {
+        let messages: Vec<&ChatMessage> = self
+            .chat_history
+            .active_path()
+            .into_iter()
+            .filter_map(|node| self.chat_history.get(node))
+            .collect();
+        if messages.is_empty() {
+            self.command_status = Some("Nothing to export yet".to_string());
+            return;
         }
 
-        app_ui.add_space(3.0);
+        let title = Session::title_for(&self.chat_history);
+        let exported = ExportedConversation {
+            title: &title,
+            model: &self.ai_model,
+            system_instruction: &self.shared_context.system_instruction,
+            messages,
+        };
+        match export::export_dialog(format, &exported) {
+            Ok(true) => self.command_status = Some(format!("Exported as {}", format.label())),
+            Ok(false) => {}
+            Err(e) => self.set_error(format!("Export failed: {}", e)),
+        }
+    }
 
-        (should_generate, img_context)
+    /// Restores a JSON export as a new session
+    pub fn import_conversation(&mut self) {
+        if self.is_loading {
+            return;
+        }
+        match export::import_dialog() {
+            Ok(Some(imported)) => {
+                self.new_session();
+                self.chat_history = imported.conversation;
+                self.shared_context.system_instruction = imported.system_instruction;
+                if let Some(model) = imported.model {
+                    self.ai_model = model;
+                    self.ensure_model_available();
+                }
+                self.sync_latest_response();
+                self.save_session();
+                self.refresh_saved_sessions();
+            }
+            Ok(None) => {}
+            Err(e) => self.set_error(format!("Import failed: {}", e)),
+        }
     }
 
-    pub fn render_response_section(&mut self, app_ui: &mut egui::Ui, ctx: &egui::Context) {
-        app_ui.heading("Response:");
-        app_ui.separator();
+    pub fn start_async_request(&mut self, prompt: String) {
+        if self.is_loading {
+            return;
+        }
+        // Moving on without picking keeps the first candidate
+        self.choose_candidate(0);
+        let parent = self.chat_history.active_leaf();
+        if self.send_prompt(prompt.clone(), PendingTurn::Prompt(parent)) {
+            self.sent_prompt = Some(std::mem::take(&mut self.prompt));
+            self.editor_preferences.history.push(&prompt);
+            self.save_editor_preferences();
+        }
+    }
 
-        // Check right click BEFORE rendering the scroll area, 
-        // so that the Copy event is processed by the labels below it in the SAME frame.
-        if app_ui.input(|i| i.pointer.secondary_clicked()) {
-            app_ui.ctx().input_mut(|i| i.events.push(egui::Event::Copy));
+    /// Sends `prompt` with the conversation leading up to `target`; returns false if nothing was sent
+    fn send_prompt(&mut self, prompt: String, target: PendingTurn) -> bool {
+        if self.is_loading || prompt.trim().is_empty() {
+            return false;
         }
+        let provider = match self.provider_settings.provider_for_model(&self.ai_model) {
+            Ok(provider) => provider,
+            Err(e) => {
+                self.set_error(e);
+                return false;
+            }
+        };
 
-        egui::ScrollArea::vertical().show(app_ui, |ui| {
-            // Enable selectable labels for the response area
-            ui.style_mut().interaction.selectable_labels = true;
+        let context_end = match target {
+            PendingTurn::Prompt(parent) => parent,
+            PendingTurn::Regenerate(user_node) => self.chat_history.parent(user_node),
+            PendingTurn::Continue(node) => Some(node),
+        };
 
-            if !self.llm_response.is_empty() {
-                ui.label(format!("Prompt: {}", self.last_prompt));
-                ui.separator();
+        self.is_loading = true;
 
-                CommonMarkViewer::new()
-                    .max_image_width(Some(ui.available_width() as usize))
-                    .show(ui, &mut self.commonmark_cache, &self.llm_response);
-            } else {
-                ui.label("No response yet...");
-            }
+        let history = self.context_history(context_end);
 
-            if let Some(texture) = self.get_image_texture(ctx) {
-                ui.separator();
-                ui.label("Image:");
-                ui.add(
-                    egui::Image::from_texture(texture)
-                        .max_width(ui.available_width())
-                        .maintain_aspect_ratio(true),
-                );
-            }
+        self.last_prompt = prompt.clone();
+        self.request_model = self.ai_model.clone();
+        self.pending_turn = Some(target);
+        self.client_thread = Some(providers::spawn_request(
+            provider,
+            ChatRequest {
+                prompt,
+                model: self.request_model.clone(),
+                image: self.captured_img.clone(),
+                history,
+                settings: self.generation_settings.for_model(self.selected_model()),
+                shared: self.request_context(),
+            },
+        ));
+        self.llm_response.clear();
+        self.llm_thoughts = None;
+        self.llm_finish_reason = None;
+        self.llm_images.clear();
+        true
+    }
 
-            ui.add_space(10.0);
+    /// Sends an edited version of the user message `node` as a new sibling branch
+    pub fn edit_and_resend(&mut self, node: usize, prompt: String) {
+        // Moving on without picking keeps the first candidate
+        self.choose_candidate(0);
+        if self.chat_history.get(node).is_none_or(|m| m.role != "user") {
+            return;
+        }
 
-            // Fetch the copied text that might have been generated by labels responding to Event::Copy
-            let mut copied_text = None;
-            ui.ctx().output_mut(|o| {
-                // Iter over commands to find CopyText
-                for cmd in &o.commands {
-                    if let egui::OutputCommand::CopyText(text) = cmd {
-                        copied_text = Some(text.clone());
-                    }
-                }
-            });
+        let parent = self.chat_history.parent(node);
+        self.send_prompt(prompt, PendingTurn::Prompt(parent));
+    }
 
-            if let Some(text) = copied_text {
-                if !text.is_empty() {
-                    if let Ok(mut ctx) = ClipboardContext::new() {
-                        let _ = ctx.set_contents(text);
-                    }
+    /// Re-runs the prompt that produced the model message `node` as a new sibling branch
+    pub fn regenerate(&mut self, node: usize) {
+        self.choose_candidate(0);
+        let Some(user_node) = self.chat_history.parent(node) else {
+            return;
+        };
+        let Some(prompt) = self
+            .chat_history
+            .get(user_node)
+            .filter(|m| m.role == "user")
+            .map(|m| m.content.clone())
+        else {
+            return;
+        };
+
+        self.send_prompt(prompt, PendingTurn::Regenerate(user_node));
+    }
+
+    fn apply_history_action(&mut self, action: HistoryAction) {
+        match action {
+            HistoryAction::Continue(node) => self.continue_response(node),
+            HistoryAction::StartEdit(node) => {
+                if let Some(msg) = self.chat_history.get(node) {
+                    self.editing_message = Some((node, msg.content.clone()));
                 }
             }
-        });
+            HistoryAction::SubmitEdit => {
+                if let Some((node, text)) = self.editing_message.take() {
+                    self.edit_and_resend(node, text);
+                }
+            }
+            HistoryAction::CancelEdit => self.editing_message = None,
+            HistoryAction::Regenerate(node, model) => {
+                if let Some(model) = model {
+                    self.ai_model = model;
+                }
+                self.regenerate(node);
+            }
+            HistoryAction::TogglePin(node) => {
+                if let Some(msg) = self.chat_history.get_mut(node) {
+                    msg.pinned = !msg.pinned;
+                }
+                self.save_session();
+            }
+            HistoryAction::SelectSibling(node, offset) => {
+                self.choose_candidate(0);
+                self.chat_history.select_sibling(node, offset);
+                self.sync_latest_response();
+                self.save_session();
+            }
+        }
     }
 
-    pub fn render_error_section(&mut self, ctx: &egui::Context) {
-        egui::TopBottomPanel::bottom("error_message").show(ctx, |ui| {
-            ui.separator();
+    /// Asks the model to pick up a truncated answer where it stopped
+    pub fn continue_response(&mut self, node: usize) {
+        if self.is_loading
+            || !self
+                .chat_history
+                .get(node)
+                .is_some_and(|m| m.was_truncated())
+        {
+            return;
+        }
+        let provider = match self.provider_settings.provider_for_model(&self.ai_model) {
+            Ok(provider) => provider,
+            Err(e) => {
+                self.set_error(e);
+                return;
+            }
+        };
+
+        self.is_loading = true;
+        self.pending_turn = Some(PendingTurn::Continue(node));
+        self.request_model = self.ai_model.clone();
+        let history = self.context_history(Some(node));
+        let mut settings = self.generation_settings.for_model(self.selected_model());
+        settings.candidate_count = 1;
+        self.client_thread = Some(providers::spawn_request(
+            provider,
+            ChatRequest {
+                prompt: CONTINUE_PROMPT.to_string(),
+                model: self.ai_model.clone(),
+                image: None,
+                history,
+                settings,
+                shared: self.request_context(),
+            },
+        ));
+    }
+
+    /// Sends the prompt in the editor to every model checked in the Compare window
+    pub fn start_comparison(&mut self) {
+        let prompt = commands::unescape(&self.prompt).to_string();
+        if self.comparison.as_ref().is_some_and(Comparison::is_running)
+            || prompt.trim().is_empty()
+            || self.compare_models.len() < MIN_COMPARE_MODELS
+        {
+            return;
+        }
+
+        // Moving on without picking keeps the first candidate
+        self.choose_candidate(0);
+        let parent = self.chat_history.active_leaf();
+        let history = self.context_history(parent);
+        let mut requests = Vec::new();
+        for model in &self.compare_models {
+            let provider = match self.provider_settings.provider_for_model(model) {
+                Ok(provider) => provider,
+                Err(e) => {
+                    self.set_error(e);
+                    return;
+                }
+            };
+            let mut settings = self
+                .generation_settings
+                .for_model(self.available_models.iter().find(|m| &m.name == model));
+            settings.candidate_count = 1;
+            requests.push((
+                provider,
+                ChatRequest {
+                    prompt: prompt.clone(),
+                    model: model.clone(),
+                    image: self.captured_img.clone(),
+                    history: history.clone(),
+                    settings,
+                    shared: self.context_for(model),
+                },
+            ));
+        }
+
+        self.comparison = Some(Comparison::start(prompt, parent, requests));
+        self.editor_preferences.history.push(&self.prompt);
+        self.save_editor_preferences();
+        self.prompt.clear();
+        self.clear_error();
+    }
+
+    pub fn poll_comparison(&mut self, ctx: &egui::Context) {
+        if let Some(ref mut comparison) = self.comparison {
+            if comparison.poll() {
+                ctx.request_repaint();
+            }
+            if comparison.is_running() {
+                // Also keeps the elapsed time of running columns ticking
+                ctx.request_repaint_after(Duration::from_millis(100));
+            }
+        }
+    }
+
+    /// Adds the compared prompt and the answer of column `idx` to the conversation
+    pub fn promote_comparison(&mut self, idx: usize) {
+        if self.is_loading {
+            return;
+        }
+        let Some(ref mut comparison) = self.comparison else {
+            return;
+        };
+        if comparison.promoted.is_some() {
+            return;
+        }
+        let Some(column) = comparison.columns.get(idx) else {
+            return;
+        };
+        let Some(Ok(ref response)) = column.result else {
+            return;
+        };
+        let response = response.clone();
+        comparison.promoted = Some(idx);
+        let prompt = comparison.prompt.clone();
+        let parent = comparison.parent;
+        let model = column.model.clone();
+
+        self.choose_candidate(0);
+        self.last_prompt = prompt;
+        self.request_model = model;
+        self.pending_turn = Some(PendingTurn::Prompt(parent));
+        self.first_frame = true;
+        self.update_llm_response(response);
+    }
+
+    /// Writes the whole conversation tree and its settings to the sessions folder
+    pub fn save_session(&mut self) {
+        if self.chat_history.is_empty() {
+            return;
+        }
+
+        let session = Session {
+            id: self.session_id.clone(),
+            title: Session::title_for(&self.chat_history),
+            created_at: self.session_created_at,
+            updated_at: storage::now_secs(),
+            model: self.ai_model.clone(),
+            settings: self.generation_settings.clone(),
+            context: self.context_settings.clone(),
+            summary: self.conversation_summary.clone(),
+            system_instruction: self.shared_context.system_instruction.clone(),
+            conversation: self.chat_history.clone(),
+        };
+        if let Err(e) = session::save_session(&session) {
+            self.set_error(format!("Failed to save session: {}", e));
+            return;
+        }
+        if let Some(ref mut index) = self.search_index {
+            index.update_session(&session);
+        }
+        // Updated in place; listing reads every session file, images included
+        self.saved_sessions.retain(|s| s.id != session.id);
+        self.saved_sessions.insert(
+            0,
+            SessionSummary {
+                id: session.id,
+                title: session.title,
+                updated_at: session.updated_at,
+            },
+        );
+    }
+
+    pub fn refresh_saved_sessions(&mut self) {
+        match session::list_sessions() {
+            Ok(sessions) => self.saved_sessions = sessions,
+            Err(e) => self.set_error(format!("Failed to list sessions: {}", e)),
+        }
+        if self.search_index.is_none() && self.search_thread.is_none() {
+            self.search_thread = Some(search::spawn_build_index());
+        }
+    }
+
+    pub fn poll_search_index(&mut self, ctx: &egui::Context) {
+        if let Some(handle) = self.search_thread.take() {
+            if !handle.is_finished() {
+                self.search_thread = Some(handle);
+                ctx.request_repaint_after(Duration::from_millis(200));
+                return;
+            }
+
+            match handle.join() {
+                Ok(Ok(index)) => self.search_index = Some(index),
+                Ok(Err(e)) => self.set_error(format!("Failed to index sessions: {}", e)),
+                Err(_) => self.set_error("Session indexing panicked".to_string()),
+            }
+            ctx.request_repaint();
+        }
+    }
+
+    /// Opens the session containing `node` and scrolls the History window to it
+    fn jump_to_message(&mut self, session_id: &str, node: usize) {
+        if session_id != self.session_id {
+            self.open_session(session_id);
+            if session_id != self.session_id {
+                return;
+            }
+        }
+        if self.chat_history.get(node).is_none() {
+            return;
+        }
+        self.choose_candidate(0);
+        self.chat_history.activate(node);
+        self.sync_latest_response();
+        self.jump_to_node = Some(node);
+        self.search_query.text.clear();
+    }
+
+    /// Starts an empty conversation; the current one stays in the sessions folder
+    pub fn new_session(&mut self) {
+        if self.is_loading {
+            return;
+        }
+        self.choose_candidate(0);
+        self.confirm_delete_session = false;
+        self.chat_history.clear();
+        self.conversation_summary = None;
+        self.summary_thread = None;
+        self.delete_context_cache();
+        self.shared_context = SharedContext::default();
+        self.image_textures.clear();
+        self.editing_message = None;
+        self.session_id = session::new_session_id();
+        self.session_created_at = storage::now_secs();
+        self.sync_latest_response();
+        self.last_prompt.clear();
+    }
+
+    pub fn open_session(&mut self, id: &str) {
+        if self.is_loading {
+            return;
+        }
+        self.choose_candidate(0);
+        self.confirm_delete_session = false;
+        match session::load_session(id) {
+            Ok(session) => {
+                self.chat_history = session.conversation;
+                self.image_textures.clear();
+                self.editing_message = None;
+                self.session_id = session.id;
+                self.session_created_at = session.created_at;
+                self.ai_model = session.model;
+                self.generation_settings = session.settings;
+                self.context_settings = session.context;
+                self.conversation_summary = session.summary;
+                self.summary_thread = None;
+                self.delete_context_cache();
+                self.shared_context = SharedContext {
+                    system_instruction: session.system_instruction,
+                    ..SharedContext::default()
+                };
+                self.ensure_model_available();
+                self.sync_latest_response();
+            }
+            Err(e) => self.set_error(format!("Failed to open session: {}", e)),
+        }
+    }
+
+    pub fn delete_session(&mut self, id: &str) {
+        if let Err(e) = session::delete_session(id) {
+            self.set_error(format!("Failed to delete session: {}", e));
+        }
+        if let Some(ref mut index) = self.search_index {
+            index.remove_session(id);
+        }
+        if id == self.session_id {
+            self.new_session();
+        }
+        self.refresh_saved_sessions();
+    }
+
+    /// Instruction and documents for a request to `request_model`, by reference when
+    /// a live cache for that model holds them
+    fn request_context(&self) -> SharedContext {
+        self.context_for(&self.request_model)
+    }
+
+    /// Instruction and documents for a request to `model`
+    fn context_for(&self, model: &str) -> SharedContext {
+        match self.context_cache {
+            Some(ref cache) if cache.model == model && !cache.is_expired() => {
+                SharedContext::via_cache(cache)
+            }
+            _ => self.shared_context.clone(),
+        }
+    }
+
+    pub fn create_context_cache(&mut self) {
+        if self.cache_thread.is_some() || self.shared_context.is_empty() {
+            return;
+        }
+        self.delete_context_cache();
+        self.cache_thread = Some(spawn_create_context_cache(
+            self.ai_model.clone(),
+            self.shared_context.clone(),
+            Duration::from_secs(self.cache_ttl_minutes * 60),
+        ));
+    }
+
+    /// Removes the cache from the server in the background; it would expire on its own anyway
+    pub fn delete_context_cache(&mut self) {
+        if let Some(handle) = self.cache_thread.take() {
+            self.abandoned_caches.push(handle);
+        }
+        if let Some(cache) = self.context_cache.take() {
+            spawn_delete_context_cache(cache.name);
+        }
+    }
+
+    pub fn poll_context_cache(&mut self, ctx: &egui::Context) {
+        if self
+            .context_cache
+            .as_ref()
+            .is_some_and(|cache| cache.is_expired())
+        {
+            self.context_cache = None;
+        }
+
+        let (finished, running) = std::mem::take(&mut self.abandoned_caches)
+            .into_iter()
+            .partition(|handle| handle.is_finished());
+        self.abandoned_caches = running;
+        for handle in finished {
+            if let Ok(Ok(cache)) = handle.join() {
+                spawn_delete_context_cache(cache.name);
+            }
+        }
+        if !self.abandoned_caches.is_empty() {
+            ctx.request_repaint_after(Duration::from_millis(200));
+        }
+
+        if let Some(handle) = self.cache_thread.take() {
+            if !handle.is_finished() {
+                self.cache_thread = Some(handle);
+                ctx.request_repaint_after(Duration::from_millis(200));
+                return;
+            }
+
+            match handle.join() {
+                Ok(Ok(cache)) => self.context_cache = Some(cache),
+                Ok(Err(e)) => self.set_error(format!("Failed to create context cache: {}", e)),
+                Err(_) => self.set_error("Context cache request panicked".to_string()),
+            }
+            ctx.request_repaint();
+        }
+    }
+
+    /// Deletes the context cache before the app closes, waiting for the requests to finish,
+    /// including caches whose create is still running
+    pub fn shutdown(&mut self) {
+        if let Some(cache) = self.context_cache.take() {
+            let _ = spawn_delete_context_cache(cache.name).join();
+        }
+        let running = self.cache_thread.take();
+        for handle in std::mem::take(&mut self.abandoned_caches)
+            .into_iter()
+            .chain(running)
+        {
+            if let Ok(Ok(cache)) = handle.join() {
+                let _ = spawn_delete_context_cache(cache.name).join();
+            }
+        }
+    }
+
+    pub fn load_editor_preferences(&mut self) {
+        match EditorPreferences::load() {
+            Ok(preferences) => self.editor_preferences = preferences,
+            Err(e) => self.set_error(format!("Failed to load editor preferences: {}", e)),
+        }
+    }
+
+    fn save_editor_preferences(&mut self) {
+        if let Err(e) = self.editor_preferences.save() {
+            self.set_error(format!("Failed to save editor preferences: {}", e));
+        }
+    }
+
+    pub fn load_template_library(&mut self) {
+        match TemplateLibrary::load() {
+            Ok(library) => self.template_library = library,
+            Err(e) => self.set_error(format!("Failed to load prompt templates: {}", e)),
+        }
+    }
+
+    pub fn load_provider_settings(&mut self) {
+        match ProviderSettings::load() {
+            Ok(settings) => self.provider_settings = settings,
+            Err(e) => self.set_error(format!("Failed to load providers: {}", e)),
+        }
+        let errors = self.provider_settings.load_keys(None);
+        if !errors.is_empty() {
+            self.set_error(format!("{} (Settings → Providers...)", errors.join("; ")));
+        }
+        self.provider_settings.apply();
+    }
+
+    /// Stores the keys, writes providers.json and applies the result
+    fn save_provider_settings(&mut self) -> Result<(), String> {
+        let passphrase = Some(self.key_passphrase.as_str()).filter(|p| !p.is_empty());
+        self.provider_settings.save(passphrase)?;
+        self.provider_settings.apply();
+        self.refresh_models();
+        Ok(())
+    }
+
+    fn save_template_library(&mut self) {
+        if let Err(e) = self.template_library.save() {
+            self.set_error(format!("Failed to save prompt templates: {}", e));
+        }
+    }
+
+    /// Inserts `template` into the prompt, asking for its placeholders first if it has any
+    pub fn use_template(&mut self, template: PromptTemplate) {
+        if template.variables().is_empty() {
+            self.insert_into_prompt(&template.body);
+        } else {
+            self.template_form = Some(TemplateForm::new(template));
+        }
+    }
+
+    /// Appends `text` to the prompt as a new paragraph
+    fn insert_into_prompt(&mut self, text: &str) {
+        if !self.prompt.trim().is_empty() {
+            self.prompt.push_str("\n\n");
+        }
+        self.prompt.push_str(text);
+    }
+
+    /// Loads the cached model list, or fetches it if nothing has been cached yet
+    pub fn load_model_list(&mut self) {
+        match load_cached_models() {
+            Some(models) => {
+                self.available_models = models;
+                self.ensure_model_available();
+            }
+            None => self.refresh_models(),
+        }
+    }
+
+    pub fn refresh_models(&mut self) {
+        if self.models_thread.is_none() {
+            self.models_thread = Some(providers::spawn_list_models(self.provider_settings.clone()));
+        }
+    }
+
+    pub fn poll_model_list(&mut self, ctx: &egui::Context) {
+        if let Some(handle) = self.models_thread.take() {
+            if !handle.is_finished() {
+                self.models_thread = Some(handle);
+                ctx.request_repaint_after(Duration::from_millis(200));
+                return;
+            }
+
+            match handle.join() {
+                Ok(Ok(listing)) => {
+                    let ModelListing { mut models, errors } = listing;
+                    // Providers that couldn't be reached keep the models known from before
+                    for (provider, error) in &errors {
+                        models.extend(
+                            self.available_models
+                                .iter()
+                                .filter(|m| &m.provider == provider)
+                                .cloned(),
+                        );
+                        self.set_error(format!(
+                            "Failed to refresh models of {}: {}",
+                            self.provider_settings.name_of(provider),
+                            error
+                        ));
+                    }
+                    if models.is_empty() {
+                        if errors.is_empty() {
+                            self.set_error(
+                                "No models supporting generateContent were returned".to_string(),
+                            );
+                        }
+                    } else {
+                        if let Err(e) = save_cached_models(&models) {
+                            self.set_error(format!("Failed to cache model list: {}", e));
+                        }
+                        self.available_models = models;
+                        self.ensure_model_available();
+                    }
+                }
+                Ok(Err(e)) => self.set_error(format!("Failed to refresh models: {}", e)),
+                Err(_) => self.set_error("Model list request panicked".to_string()),
+            }
+            ctx.request_repaint();
+        }
+    }
+
+    pub fn selected_model(&self) -> Option<&ModelInfo> {
+        self.available_models
+            .iter()
+            .find(|m| m.name == self.ai_model)
+    }
+
+    /// Documents and context caches only work with Gemini models
+    fn uses_gemini(&self) -> bool {
+        self.selected_model()
+            .is_none_or(|m| m.provider == GEMINI_PROVIDER)
+    }
+
+    /// Switches to a model the key can actually use if the selected one isn't listed
+    fn ensure_model_available(&mut self) {
+        if self
+            .available_models
+            .iter()
+            .any(|m| m.name == self.ai_model)
+        {
+            return;
+        }
+
+        let fallback = self
+            .available_models
+            .iter()
+            .find(|m| m.name == FALLBACK_MODEL)
+            .or_else(|| self.available_models.first());
+        if let Some(model) = fallback {
+            self.ai_model = model.name.clone();
+        }
+    }
+
+    pub fn get_image_texture(&mut self, ctx: &egui::Context) -> Option<&TextureHandle> {
+        if let Some(ref color_img) = self.captured_img {
+            if self.captured_img_texture.is_none() {
+                let texture = ctx.load_texture(
+                    "captured_image",
+                    color_img.clone(),
+                    egui::TextureOptions::default(),
+                );
+                self.captured_img_texture = Some(texture);
+            }
+            self.captured_img_texture.as_ref()
+        } else {
+            None
+        }
+    }
+
+    pub fn set_error(&mut self, error: String) {
+        self.error_message = Some(diagnostics::redact(&error));
+    }
+
+    pub fn clear_error(&mut self) {
+        self.error_message = None;
+    }
+
+    pub fn render_prompt_section(&mut self, app_ui: &mut egui::Ui) -> bool {
+        let mut chosen_template = None;
+        app_ui.horizontal(|ui| {
+            ui.heading("Enter a prompt:");
+            ui.menu_button("Templates", |ui| {
+                for template in &self.template_library.templates {
+                    let response = ui.button(&template.name);
+                    let response = if template.description.is_empty() {
+                        response
+                    } else {
+                        response.on_hover_text(&template.description)
+                    };
+                    if response.clicked() {
+                        chosen_template = Some(template.clone());
+                        ui.close();
+                    }
+                }
+                if self.template_library.templates.is_empty() {
+                    ui.weak("No templates yet");
+                }
+                ui.separator();
+                if ui.button("Manage templates...").clicked() {
+                    self.show_templates_window = true;
+                    ui.close();
+                }
+            });
+        });
+        if let Some(template) = chosen_template {
+            self.use_template(template);
+        }
+        app_ui.add_space(3.0);
+
+        let mut should_generate = false;
+        let shortcut = self.editor_preferences.send_shortcut;
+        let editor_id = egui::Id::new("prompt_editor");
+
+        let completions = commands::complete(
+            &self.prompt,
+            &self.available_models,
+            &self.template_library.templates,
+        );
+
+        // Shortcuts are consumed before the editor sees them, so they don't insert newlines
+        if app_ui.memory(|m| m.has_focus(editor_id)) {
+            let (send, older, newer, complete) = app_ui.input_mut(|i| {
+                (
+                    shortcut.consume(i),
+                    i.consume_key(egui::Modifiers::COMMAND, egui::Key::ArrowUp),
+                    i.consume_key(egui::Modifiers::COMMAND, egui::Key::ArrowDown),
+                    !completions.is_empty() && i.consume_key(egui::Modifiers::NONE, egui::Key::Tab),
+                )
+            });
+            should_generate = send;
+            let recalled = if older {
+                self.editor_preferences.history.previous(&self.prompt)
+            } else if newer {
+                self.editor_preferences.history.next()
+            } else if complete {
+                completions.first().map(|c| c.text.clone())
+            } else {
+                None
+            };
+            if let Some(text) = recalled {
+                self.prompt = text;
+                move_cursor_to_end(app_ui.ctx(), editor_id, &self.prompt);
+            }
+        }
+
+        let width = app_ui.available_width();
+        egui::Resize::default()
+            .id_salt("prompt_editor_resize")
+            .resizable([false, true])
+            .min_width(width)
+            .max_width(width)
+            .default_height(80.0)
+            .min_height(40.0)
+            .show(app_ui, |ui| {
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    let response = ui.add_sized(
+                        ui.available_size(),
+                        egui::TextEdit::multiline(&mut self.prompt)
+                            .id(editor_id)
+                            .return_key(shortcut.newline_key())
+                            .hint_text(format!("{} to send", shortcut.label())),
+                    );
+                    if self.first_frame {
+                        response.request_focus();
+                        self.first_frame = false;
+                    }
+                    if response.changed() {
+                        self.command_status = None;
+                    }
+                });
+            });
+
+        if !completions.is_empty() {
+            let mut chosen = None;
+            egui::Frame::group(app_ui.style()).show(app_ui, |ui| {
+                for completion in completions.iter().take(8) {
+                    ui.horizontal(|ui| {
+                        if ui
+                            .selectable_label(
+                                false,
+                                egui::RichText::new(&completion.label).monospace(),
+                            )
+                            .clicked()
+                        {
+                            chosen = Some(completion.text.clone());
+                        }
+                        ui.weak(&completion.hint);
+                    });
+                }
+                ui.weak("Tab completes the first entry");
+            });
+            if let Some(text) = chosen {
+                self.prompt = text;
+                move_cursor_to_end(app_ui.ctx(), editor_id, &self.prompt);
+                app_ui.memory_mut(|m| m.request_focus(editor_id));
+            }
+        }
+
+        app_ui.horizontal(|ui| {
+            ui.weak(format!(
+                "{} characters, ~{} tokens",
+                self.prompt.chars().count(),
+                estimate_text_tokens(&self.prompt)
+            ));
+            ui.weak(format!(
+                "{} sends, Ctrl+Up/Down recalls earlier prompts, / starts a command",
+                shortcut.label()
+            ));
+        });
+        if let Some(ref status) = self.command_status {
+            app_ui.label(status);
+        }
+        app_ui.add_space(3.0);
+
+        should_generate
+    }
+
+    pub fn render_action_buttons(
+        &mut self,
+        app_ui: &mut egui::Ui,
+        ctx: &egui::Context,
+    ) -> (bool, bool) {
+        let mut should_generate = false;
+        let mut img_context = false;
+
+        app_ui.horizontal(|ui| {
+            if ui
+                .add_enabled(!self.is_loading, egui::Button::new("Generate"))
+                .clicked()
+            {
+                should_generate = true;
+                self.clear_error();
+                self.show_image_buttons = false;
+            }
+
+            let button_text = if self.show_image_buttons {
+                "Hide Image Options"
+            } else {
+                "Add Image"
+            };
+
+            if ui
+                .add_enabled(!self.is_loading, egui::Button::new(button_text))
+                .clicked()
+            {
+                self.show_image_buttons = !self.show_image_buttons;
+                self.clear_error();
+            }
+
+            if ui.button("History").clicked() {
+                self.show_history_window = !self.show_history_window;
+                if self.show_history_window {
+                    self.refresh_saved_sessions();
+                }
+            }
+
+            if ui.button("Settings").clicked() {
+                self.show_settings_window = !self.show_settings_window;
+            }
+
+            if ui
+                .button("Compare")
+                .on_hover_text("Send the prompt to several models side by side")
+                .clicked()
+            {
+                self.show_compare_window = !self.show_compare_window;
+                if self.compare_models.is_empty() {
+                    self.compare_models.push(self.ai_model.clone());
+                }
+            }
+
+            let documents_label = match self.shared_context.attachments.len() {
+                0 => "Documents".to_string(),
+                n => format!("Documents ({})", n),
+            };
+            if ui.button(documents_label).clicked() {
+                self.show_documents_window = !self.show_documents_window;
+            }
+
+            if let Some(ref _color_image) = self.captured_img {
+                if ui
+                    .add_enabled(!self.is_loading, egui::Button::new("Clear Image"))
+                    .clicked()
+                {
+                    self.captured_img = None;
+                    self.captured_img_texture = None; // Clear texture cache
+                    self.clear_error();
+                }
+            }
+
+            let selected_text = self
+                .selected_model()
+                .map(|m| m.display_name.clone())
+                .unwrap_or_else(|| self.ai_model.clone());
+
+            egui::ComboBox::new("ai_model_selector", "")
+                .selected_text(selected_text)
+                .show_ui(ui, |ui| {
+                    // Models grouped under their provider, in the order providers are configured
+                    let groups: Vec<&ProviderConfig> = self
+                        .provider_settings
+                        .providers
+                        .iter()
+                        .filter(|p| self.available_models.iter().any(|m| m.provider == p.id))
+                        .collect();
+                    for provider in &groups {
+                        if groups.len() > 1 {
+                            ui.label(egui::RichText::new(&provider.name).strong());
+                        }
+                        for model in self
+                            .available_models
+                            .iter()
+                            .filter(|m| m.provider == provider.id)
+                        {
+                            ui.selectable_value(
+                                &mut self.ai_model,
+                                model.name.clone(),
+                                model.label(),
+                            )
+                            .on_hover_text(&model.name);
+                        }
+                    }
+                });
+
+            let refreshing = self.models_thread.is_some();
+            if ui
+                .add_enabled(!refreshing, egui::Button::new("🔄"))
+                .on_hover_text("Refresh model list")
+                .clicked()
+            {
+                self.refresh_models();
+            }
+        });
+
+        if self.show_image_buttons {
+            egui::Window::new("Image Options")
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        if ui
+                            .add_enabled(!self.is_loading, egui::Button::new("Pick Image"))
+                            .clicked()
+                        {
+                            match img_utils::pick_image_file() {
+                                Ok(color_image) => {
+                                    self.captured_img = Some(color_image);
+                                    self.captured_img_texture = None; // Clear texture cache to force recreation
+                                    img_context = true;
+                                    self.show_image_buttons = false;
+                                    self.clear_error();
+                                }
+                                Err(e) => {
+                                    self.set_error(format!("Image pick failed: {}", e));
+                                }
+                            }
+                        }
+
+                        if ui
+                            .add_enabled(!self.is_loading, egui::Button::new("Paste Image"))
+                            .clicked()
+                        {
+                            match img_utils::image_from_clipboard(ctx) {
+                                Ok(color_image) => {
+                                    self.captured_img = Some(color_image);
+                                    self.captured_img_texture = None; // Clear texture cache to force recreation
+                                    self.show_image_buttons = false;
+                                    self.clear_error();
+                                }
+                                Err(e) => {
+                                    self.set_error(format!("Failed to paste image: {}", e));
+                                }
+                            }
+                        }
+
+                        if ui.button("Cancel").clicked() {
+                            self.show_image_buttons = false;
+                            self.clear_error();
+                        }
+                    });
+                });
+        }
+
+        app_ui.add_space(3.0);
+
+        (should_generate, img_context)
+    }
+
+    pub fn render_response_section(&mut self, app_ui: &mut egui::Ui, ctx: &egui::Context) {
+        app_ui.heading("Response:");
+        app_ui.separator();
+
+        // Check right click BEFORE rendering the scroll area,
+        // so that the Copy event is processed by the labels below it in the SAME frame.
+        if app_ui.input(|i| i.pointer.secondary_clicked()) {
+            app_ui.ctx().input_mut(|i| i.events.push(egui::Event::Copy));
+        }
+
+        let mut continue_clicked = false;
+        let mut regenerate_clicked = false;
+        let mut chosen_candidate = None;
+        let mut image_error = None;
+
+        egui::ScrollArea::vertical().show(app_ui, |ui| {
+            // Enable selectable labels for the response area
+            ui.style_mut().interaction.selectable_labels = true;
+
+            if !self.pending_candidates.is_empty() {
+                ui.label(format!("Prompt: {}", self.last_prompt));
+                ui.separator();
+                chosen_candidate = self.render_candidates(ui);
+            } else if !self.llm_response.is_empty() {
+                ui.label(format!("Prompt: {}", self.last_prompt));
+                ui.separator();
+
+                if let Some(ref thoughts) = self.llm_thoughts {
+                    render_thoughts(ui, &mut self.commonmark_cache, thoughts, "response");
+                }
+
+                CommonMarkViewer::new()
+                    .max_image_width(Some(ui.available_width() as usize))
+                    .show(ui, &mut self.commonmark_cache, &self.llm_response);
+
+                if let Err(e) =
+                    render_generated_images(ui, &mut self.image_textures, &self.llm_images)
+                {
+                    image_error = Some(e);
+                }
+
+                let latest = self
+                    .chat_history
+                    .active_leaf()
+                    .and_then(|node| self.chat_history.get(node))
+                    .filter(|m| m.role == "model" && m.content == self.llm_response);
+                let latest_is_model = latest.is_some();
+                if let Some(ms) = latest.and_then(|m| m.latency_ms) {
+                    ui.weak(format!("Answered in {:.1} s", ms as f64 / 1000.0));
+                }
+                if latest_is_model
+                    && ui
+                        .add_enabled(!self.is_loading, egui::Button::new("🔄 Regenerate"))
+                        .on_hover_text("Ask again with the model currently selected")
+                        .clicked()
+                {
+                    regenerate_clicked = true;
+                }
+
+                if self.llm_finish_reason.as_deref() == Some(MAX_TOKENS_FINISH_REASON) {
+                    ui.horizontal(|ui| {
+                        ui.colored_label(
+                            egui::Color32::YELLOW,
+                            "⚠ The response was cut off (MAX_TOKENS)",
+                        );
+                        if ui
+                            .add_enabled(!self.is_loading, egui::Button::new("Continue"))
+                            .clicked()
+                        {
+                            continue_clicked = true;
+                        }
+                    });
+                }
+            } else {
+                ui.label("No response yet...");
+            }
+
+            if let Some(texture) = self.get_image_texture(ctx) {
+                ui.separator();
+                ui.label("Image:");
+                ui.add(
+                    egui::Image::from_texture(texture)
+                        .max_width(ui.available_width())
+                        .maintain_aspect_ratio(true),
+                );
+            }
+
+            ui.add_space(10.0);
+
+            // Fetch the copied text that might have been generated by labels responding to Event::Copy
+            let mut copied_text = None;
+            ui.ctx().output_mut(|o| {
+                // Iter over commands to find CopyText
+                for cmd in &o.commands {
+                    if let egui::OutputCommand::CopyText(text) = cmd {
+                        copied_text = Some(text.clone());
+                    }
+                }
+            });
+
+            if let Some(text) = copied_text {
+                if !text.is_empty() {
+                    if let Ok(mut ctx) = ClipboardContext::new() {
+                        let _ = ctx.set_contents(text);
+                    }
+                }
+            }
+        });
+
+        if let Some(e) = image_error {
+            self.set_error(e);
+        }
+
+        if let Some(idx) = chosen_candidate {
+            self.choose_candidate(idx);
+        }
+
+        if let Some(leaf) = self.chat_history.active_leaf() {
+            if regenerate_clicked {
+                self.regenerate(leaf);
+            }
+            if continue_clicked {
+                self.continue_response(leaf);
+            }
+        }
+    }
+
+    /// Shows the pending candidates side by side; returns the one the user picked
+    fn render_candidates(&mut self, ui: &mut egui::Ui) -> Option<usize> {
+        let mut chosen = None;
+        let mut image_error = None;
+        ui.label("Several answers were returned. Pick the one to keep in the conversation:");
+        ui.add_space(3.0);
+
+        ui.columns(self.pending_candidates.len(), |columns| {
+            for (i, (column, candidate)) in
+                columns.iter_mut().zip(&self.pending_candidates).enumerate()
+            {
+                column.horizontal(|ui| {
+                    ui.strong(format!("Candidate {}", i + 1));
+                    if ui.button("Use this").clicked() {
+                        chosen = Some(i);
+                    }
+                });
+                if let Some(ref reason) = candidate.finish_reason {
+                    column.weak(format!("finish reason: {}", reason));
+                }
+                column.separator();
+
+                if let Some(ref thoughts) = candidate.thoughts {
+                    render_thoughts(
+                        column,
+                        &mut self.commonmark_cache,
+                        thoughts,
+                        ("candidate", i),
+                    );
+                }
+                CommonMarkViewer::new().show(column, &mut self.commonmark_cache, &candidate.text);
+                if let Err(e) =
+                    render_generated_images(column, &mut self.image_textures, &candidate.images)
+                {
+                    image_error = Some(e);
+                }
+            }
+        });
+
+        if let Some(e) = image_error {
+            self.set_error(e);
+        }
+        chosen
+    }
+
+    pub fn render_error_section(&mut self, ctx: &egui::Context) {
+        egui::TopBottomPanel::bottom("error_message").show(ctx, |ui| {
+            ui.separator();
             ui.horizontal(|ui| {
                 if let Some(ref error) = self.error_message {
                     ui.colored_label(egui::Color32::RED, "⚠ Error:");
@@ -335,56 +1764,1547 @@ impl UIState {
                 } else {
                     ui.label(" ");
                 }
-            });
+            });
+        });
+    }
+
+    pub fn render_history_window(&mut self, ctx: &egui::Context) {
+        if self.show_history_window {
+            let should_close = ctx.show_viewport_immediate(
+                egui::ViewportId::from_hash_of("history_viewport"),
+                egui::ViewportBuilder::default()
+                    .with_title("Chat History")
+                    .with_inner_size([400.0, 600.0]),
+                |ctx, class| {
+                    assert!(
+                        class == egui::ViewportClass::Immediate,
+                        "This egui backend doesn't support multiple viewports"
+                    );
+
+                    let mut close_requested = false;
+                    if ctx.input(|i| i.viewport().close_requested()) {
+                        close_requested = true;
+                    }
+
+                    egui::CentralPanel::default().show(ctx, |ui| {
+                        ui.heading("Chat History");
+                        let mut session_to_open = None;
+                        ui.horizontal(|ui| {
+                            let current_title = if self.chat_history.is_empty() {
+                                "New session".to_string()
+                            } else {
+                                Session::title_for(&self.chat_history)
+                            };
+                            egui::ComboBox::new("session_picker", "")
+                                .selected_text(current_title)
+                                .width(200.0)
+                                .show_ui(ui, |ui| {
+                                    for saved in &self.saved_sessions {
+                                        let selected = saved.id == self.session_id;
+                                        if ui.selectable_label(selected, &saved.title).clicked()
+                                            && !selected
+                                        {
+                                            session_to_open = Some(saved.id.clone());
+                                        }
+                                    }
+                                });
+                            if ui
+                                .add_enabled(!self.is_loading, egui::Button::new("New Session"))
+                                .clicked()
+                            {
+                                self.new_session();
+                            }
+                            ui.menu_button("Export", |ui| {
+                                for format in ExportFormat::ALL {
+                                    if ui.button(format.label()).clicked() {
+                                        self.export_conversation(format);
+                                        ui.close();
+                                    }
+                                }
+                            });
+                            if ui
+                                .add_enabled(!self.is_loading, egui::Button::new("Import..."))
+                                .on_hover_text("Open a JSON export as a new session")
+                                .clicked()
+                            {
+                                self.import_conversation();
+                            }
+                        });
+                        if let Some(id) = session_to_open {
+                            self.open_session(&id);
+                        }
+                        ui.horizontal(|ui| {
+                            if ui
+                                .add_enabled(!self.is_loading, egui::Button::new("Clear History"))
+                                .on_hover_text("Start a new session; this one stays saved")
+                                .clicked()
+                            {
+                                self.new_session();
+                            }
+                            if self.confirm_delete_session {
+                                ui.label("Delete this session for good?");
+                                if ui.button("Delete").clicked() {
+                                    self.confirm_delete_session = false;
+                                    let id = self.session_id.clone();
+                                    self.delete_session(&id);
+                                }
+                                if ui.button("Cancel").clicked() {
+                                    self.confirm_delete_session = false;
+                                }
+                            } else if ui
+                                .add_enabled(
+                                    !self.is_loading,
+                                    egui::Button::new("Delete Session..."),
+                                )
+                                .on_hover_text("Remove this session from disk")
+                                .clicked()
+                            {
+                                self.confirm_delete_session = true;
+                            }
+                        });
+                        ui.separator();
+
+                        if self.render_search(ui) {
+                            return;
+                        }
+
+                        let path = self.chat_history.active_path();
+                        let path_messages: Vec<&ChatMessage> = path
+                            .iter()
+                            .filter_map(|&node| self.chat_history.get(node))
+                            .collect();
+                        let included = self
+                            .context_settings
+                            .select(&path_messages, self.selected_model());
+                        let summary_message = self
+                            .active_summary(&path, &included)
+                            .map(|summary| summary.to_message());
+                        // Index on the path of the last message the summary stands in for
+                        let summarized_through = self
+                            .conversation_summary
+                            .as_ref()
+                            .filter(|_| summary_message.is_some())
+                            .and_then(|summary| {
+                                path.iter().position(|&node| node == summary.through)
+                            });
+                        let sent_tokens: u64 = path_messages
+                            .iter()
+                            .zip(&included)
+                            .filter(|(_, &keep)| keep)
+                            .map(|(msg, _)| estimate_tokens(msg))
+                            .chain(summary_message.as_ref().map(estimate_tokens))
+                            .sum();
+                        ui.weak(format!(
+                            "Next prompt sends {} of {} messages{} (~{} tokens)",
+                            included.iter().filter(|&&keep| keep).count(),
+                            path_messages.len(),
+                            if summary_message.is_some() {
+                                " plus the summary"
+                            } else {
+                                ""
+                            },
+                            sent_tokens
+                        ));
+
+                        if self.context_settings.summarize_dropped {
+                            if self.summary_thread.is_some() {
+                                ui.horizontal(|ui| {
+                                    ui.spinner();
+                                    ui.weak("Summarizing earlier messages...");
+                                });
+                            }
+                            let mut save_summary = false;
+                            let mut discard_summary = false;
+                            if let Some(summary) = self.conversation_summary.as_mut() {
+                                egui::CollapsingHeader::new("Summary of earlier messages")
+                                    .id_salt("conversation_summary")
+                                    .show(ui, |ui| {
+                                        let response = ui.add(
+                                            egui::TextEdit::multiline(&mut summary.text)
+                                                .desired_rows(4)
+                                                .desired_width(f32::INFINITY),
+                                        );
+                                        save_summary = response.lost_focus();
+                                        discard_summary = ui
+                                            .small_button("Discard summary")
+                                            .on_hover_text(
+                                                "The next answer starts a new summary from scratch",
+                                            )
+                                            .clicked();
+                                    });
+                            }
+                            if discard_summary {
+                                self.conversation_summary = None;
+                            }
+                            if save_summary || discard_summary {
+                                self.save_session();
+                            }
+                        }
+                        ui.separator();
+
+                        egui::ScrollArea::vertical().show(ui, |ui| {
+                            let mut action = None;
+                            let mut image_error = None;
+                            let leaf = path.last().copied();
+                            for (path_pos, (&i, &in_context)) in
+                                path.iter().zip(&included).enumerate()
+                            {
+                                let Some(msg) = self.chat_history.get(i) else {
+                                    continue;
+                                };
+                                if let Some(ref thoughts) = msg.thoughts {
+                                    render_thoughts(ui, &mut self.commonmark_cache, thoughts, i);
+                                }
+                                ui.horizontal_wrapped(|ui| {
+                                    let role = match (&msg.model, msg.latency_ms) {
+                                        (Some(model), Some(ms)) => format!(
+                                            "{} ({}, {:.1} s):",
+                                            msg.role,
+                                            model,
+                                            ms as f64 / 1000.0
+                                        ),
+                                        (Some(model), None) => {
+                                            format!("{} ({}):", msg.role, model)
+                                        }
+                                        _ => format!("{}:", msg.role),
+                                    };
+                                    ui.label(egui::RichText::new(role).strong());
+                                    if !matches!(self.editing_message, Some((idx, _)) if idx == i) {
+                                        if in_context {
+                                            ui.label(&msg.content);
+                                        } else {
+                                            ui.weak(&msg.content);
+                                        }
+                                    }
+                                });
+                                if self.jump_to_node == Some(i) {
+                                    ui.scroll_to_cursor(Some(egui::Align::Center));
+                                }
+
+                                match self.editing_message {
+                                    Some((idx, ref mut text)) if idx == i => {
+                                        ui.add(
+                                            egui::TextEdit::multiline(text)
+                                                .desired_width(f32::INFINITY),
+                                        );
+                                        ui.horizontal(|ui| {
+                                            if ui
+                                                .add_enabled(
+                                                    !self.is_loading,
+                                                    egui::Button::new("Send"),
+                                                )
+                                                .clicked()
+                                            {
+                                                action = Some(HistoryAction::SubmitEdit);
+                                            }
+                                            if ui.button("Cancel").clicked() {
+                                                action = Some(HistoryAction::CancelEdit);
+                                            }
+                                        });
+                                    }
+                                    _ => {}
+                                }
+
+                                if let Err(e) = render_generated_images(
+                                    ui,
+                                    &mut self.image_textures,
+                                    &msg.images,
+                                ) {
+                                    image_error = Some(e);
+                                }
+
+                                ui.horizontal(|ui| {
+                                    let (position, count) = self.chat_history.sibling_position(i);
+                                    if count > 1 {
+                                        ui.add_enabled_ui(!self.is_loading, |ui| {
+                                            if ui.small_button("<").clicked() {
+                                                action = Some(HistoryAction::SelectSibling(i, -1));
+                                            }
+                                            ui.label(format!("{}/{}", position, count));
+                                            if ui.small_button(">").clicked() {
+                                                action = Some(HistoryAction::SelectSibling(i, 1));
+                                            }
+                                        });
+                                    }
+                                    if !in_context {
+                                        let summarized = summarized_through
+                                            .is_some_and(|through| path_pos <= through);
+                                        ui.weak(if summarized { "summarized" } else { "not sent" });
+                                    }
+                                    if let Some(ref reason) = msg.finish_reason {
+                                        ui.weak(format!("finish reason: {}", reason));
+                                    }
+                                    let pin_label =
+                                        if msg.pinned { "📌 Unpin" } else { "📌 Pin" };
+                                    if ui
+                                        .small_button(pin_label)
+                                        .on_hover_text("Pinned messages are always sent")
+                                        .clicked()
+                                    {
+                                        action = Some(HistoryAction::TogglePin(i));
+                                    }
+                                    ui.add_enabled_ui(!self.is_loading, |ui| {
+                                        if msg.role == "user" {
+                                            if ui.small_button("✏ Edit").clicked() {
+                                                action = Some(HistoryAction::StartEdit(i));
+                                            }
+                                            return;
+                                        }
+
+                                        if msg.was_truncated()
+                                            && Some(i) == leaf
+                                            && ui.small_button("Continue").clicked()
+                                        {
+                                            action = Some(HistoryAction::Continue(i));
+                                        }
+                                        if ui.small_button("🔄 Regenerate").clicked() {
+                                            action = Some(HistoryAction::Regenerate(i, None));
+                                        }
+                                        ui.menu_button("with…", |ui| {
+                                            for model in &self.available_models {
+                                                if ui.button(model.label()).clicked() {
+                                                    action = Some(HistoryAction::Regenerate(
+                                                        i,
+                                                        Some(model.name.clone()),
+                                                    ));
+                                                }
+                                            }
+                                        });
+                                    });
+                                });
+                                ui.separator();
+                            }
+                            self.jump_to_node = None;
+
+                            if let Some(action) = action {
+                                self.apply_history_action(action);
+                            }
+                            if let Some(e) = image_error {
+                                self.set_error(e);
+                            }
+                        });
+                    });
+
+                    close_requested
+                },
+            );
+
+            if should_close {
+                self.show_history_window = false;
+            }
+        }
+    }
+
+    pub fn render_settings_window(&mut self, ctx: &egui::Context) {
+        if !self.show_settings_window {
+            return;
+        }
+
+        let mut open = true;
+        let mut shortcut_changed = false;
+        let mut open_providers = false;
+        let mut open_diagnostics = false;
+        egui::Window::new("Settings")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let settings = &mut self.generation_settings;
+                let available_models = &self.available_models;
+                let selected_model = available_models.iter().find(|m| m.name == self.ai_model);
+
+                ui.strong("Thinking");
+                ui.horizontal(|ui| {
+                    if ui
+                        .radio(
+                            settings.thinking == ThinkingControl::ModelDefault,
+                            "Model default",
+                        )
+                        .clicked()
+                    {
+                        settings.thinking = ThinkingControl::ModelDefault;
+                    }
+                    if ui
+                        .radio(
+                            matches!(settings.thinking, ThinkingControl::Budget(_)),
+                            "Budget",
+                        )
+                        .on_hover_text("Token budget, used by Gemini 2.5 models")
+                        .clicked()
+                        && !matches!(settings.thinking, ThinkingControl::Budget(_))
+                    {
+                        settings.thinking = ThinkingControl::Budget(-1);
+                    }
+                    if ui
+                        .radio(
+                            matches!(settings.thinking, ThinkingControl::Level(_)),
+                            "Level",
+                        )
+                        .on_hover_text("Thinking level, used by Gemini 3 models")
+                        .clicked()
+                        && !matches!(settings.thinking, ThinkingControl::Level(_))
+                    {
+                        settings.thinking = ThinkingControl::Level("high".to_string());
+                    }
+                });
+
+                match &mut settings.thinking {
+                    ThinkingControl::ModelDefault => {}
+                    ThinkingControl::Budget(budget) => {
+                        ui.horizontal(|ui| {
+                            ui.label("Budget (tokens):");
+                            ui.add(egui::DragValue::new(budget).range(-1..=32768));
+                            ui.weak("-1 = dynamic, 0 = off");
+                        });
+                    }
+                    ThinkingControl::Level(level) => {
+                        egui::ComboBox::new("thinking_level", "Level")
+                            .selected_text(level.as_str())
+                            .show_ui(ui, |ui| {
+                                for option in THINKING_LEVELS {
+                                    ui.selectable_value(level, option.to_string(), option);
+                                }
+                            });
+                    }
+                }
+
+                ui.checkbox(&mut settings.include_thoughts, "Show thought summaries");
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.strong("Candidates");
+                    ui.add(
+                        egui::DragValue::new(&mut settings.candidate_count)
+                            .range(1..=MAX_CANDIDATES),
+                    )
+                    .on_hover_text("Number of alternative answers to request");
+                });
+
+                ui.separator();
+                ui.strong("Safety");
+                egui::Grid::new("safety_settings_grid")
+                    .num_columns(2)
+                    .show(ui, |ui| {
+                        for (category, label) in HARM_CATEGORIES {
+                            let threshold = settings
+                                .safety_thresholds
+                                .entry(category.to_string())
+                                .or_insert_with(|| DEFAULT_THRESHOLD.to_string());
+                            let selected_label = BLOCK_THRESHOLDS
+                                .iter()
+                                .find(|(value, _)| value == threshold)
+                                .map(|(_, label)| *label)
+                                .unwrap_or(threshold.as_str())
+                                .to_string();
+
+                            ui.label(label);
+                            egui::ComboBox::new(("safety_threshold", category), "")
+                                .selected_text(selected_label)
+                                .show_ui(ui, |ui| {
+                                    for (value, option_label) in BLOCK_THRESHOLDS {
+                                        ui.selectable_value(
+                                            threshold,
+                                            value.to_string(),
+                                            option_label,
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+                        }
+                    });
+
+                ui.separator();
+                ui.strong("Prompt editor");
+                let mut shortcut = self.editor_preferences.send_shortcut;
+                ui.horizontal(|ui| {
+                    ui.label("Send with:");
+                    for option in SendShortcut::ALL {
+                        ui.radio_value(&mut shortcut, option, option.label());
+                    }
+                });
+                if shortcut != self.editor_preferences.send_shortcut {
+                    self.editor_preferences.send_shortcut = shortcut;
+                    shortcut_changed = true;
+                }
+
+                ui.separator();
+                ui.strong("Context");
+                let context = &mut self.context_settings;
+                ui.horizontal(|ui| {
+                    ui.radio_value(
+                        &mut context.strategy,
+                        ContextStrategy::FullHistory,
+                        "Full history",
+                    );
+                    ui.radio_value(
+                        &mut context.strategy,
+                        ContextStrategy::TokenBudget,
+                        "Token budget",
+                    );
+                    ui.radio_value(
+                        &mut context.strategy,
+                        ContextStrategy::SlidingWindow,
+                        "Sliding window",
+                    );
+                });
+                if context.strategy != ContextStrategy::FullHistory {
+                    ui.checkbox(
+                        &mut context.summarize_dropped,
+                        "Summarize messages that no longer fit",
+                    );
+                    if context.summarize_dropped {
+                        ui.horizontal(|ui| {
+                            ui.label("Summary model:");
+                            egui::ComboBox::from_id_salt("summary_model")
+                                .selected_text(&context.summary_model)
+                                .show_ui(ui, |ui| {
+                                    for model in available_models {
+                                        ui.selectable_value(
+                                            &mut context.summary_model,
+                                            model.name.clone(),
+                                            model.label(),
+                                        );
+                                    }
+                                });
+                        });
+                    }
+                }
+                match context.strategy {
+                    ContextStrategy::FullHistory => {}
+                    ContextStrategy::SlidingWindow => {
+                        ui.horizontal(|ui| {
+                            ui.label("Messages:");
+                            ui.add(egui::DragValue::new(&mut context.window).range(1..=500));
+                            ui.weak("pinned messages are always sent");
+                        });
+                    }
+                    ContextStrategy::TokenBudget => {
+                        if let Some(model) = selected_model {
+                            let unlimited = context.budget_for(Some(model)).is_none();
+                            let mut budget = context
+                                .budget_for(Some(model))
+                                .unwrap_or(UNKNOWN_LIMIT_MAX_BUDGET);
+                            // Servers without a reported limit get a generous upper bound
+                            let max_budget = match model.input_token_limit {
+                                0 => UNKNOWN_LIMIT_MAX_BUDGET,
+                                limit => limit.max(1_000),
+                            };
+                            ui.horizontal(|ui| {
+                                ui.label(format!("Budget for {}:", model.display_name));
+                                if ui
+                                    .add(
+                                        egui::DragValue::new(&mut budget)
+                                            .range(1_000..=max_budget)
+                                            .speed(1_000),
+                                    )
+                                    .changed()
+                                {
+                                    context.model_budgets.insert(model.name.clone(), budget);
+                                }
+                                if ui.small_button("Reset").clicked() {
+                                    context.model_budgets.remove(&model.name);
+                                }
+                            });
+                            if unlimited {
+                                ui.weak("The limit is unknown, so the whole history is sent");
+                            }
+                        }
+                    }
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui
+                        .button("Providers...")
+                        .on_hover_text(
+                            "Gemini key and endpoint, local and OpenAI-compatible servers",
+                        )
+                        .clicked()
+                    {
+                        open_providers = true;
+                    }
+                    if ui
+                        .button("Request log...")
+                        .on_hover_text("What was sent for recent requests, and how long it took")
+                        .clicked()
+                    {
+                        open_diagnostics = true;
+                    }
+                });
+            });
+
+        if open_providers {
+            self.show_providers_window = true;
+        }
+        if open_diagnostics {
+            self.show_diagnostics_window = true;
+        }
+        if shortcut_changed {
+            self.save_editor_preferences();
+        }
+        if !open {
+            self.show_settings_window = false;
+        }
+    }
+
+    /// Search box and filters; returns true while results are shown instead of the conversation
+    fn render_search(&mut self, ui: &mut egui::Ui) -> bool {
+        let models: Vec<String> = self
+            .search_index
+            .as_ref()
+            .map(|index| index.models().into_iter().map(String::from).collect())
+            .unwrap_or_default();
+        let query = &mut self.search_query;
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut query.text)
+                    .hint_text("Search all sessions")
+                    .desired_width(180.0),
+            );
+            egui::ComboBox::from_id_salt("search_role")
+                .selected_text(query.role.as_deref().unwrap_or("Any role"))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut query.role, None, "Any role");
+                    for role in ["user", "model"] {
+                        ui.selectable_value(&mut query.role, Some(role.to_string()), role);
+                    }
+                });
+            egui::ComboBox::from_id_salt("search_model")
+                .selected_text(query.model.as_deref().unwrap_or("Any model"))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut query.model, None, "Any model");
+                    for model in models {
+                        ui.selectable_value(&mut query.model, Some(model.clone()), model);
+                    }
+                });
+            egui::ComboBox::from_id_salt("search_date")
+                .selected_text(query.date.label())
+                .show_ui(ui, |ui| {
+                    for date in DateFilter::ALL {
+                        ui.selectable_value(&mut query.date, date, date.label());
+                    }
+                });
+        });
+
+        if self.search_query.text.trim().is_empty() {
+            return false;
+        }
+        let Some(ref index) = self.search_index else {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label("Indexing saved sessions...");
+            });
+            return true;
+        };
+
+        let hits = index.search(&self.search_query);
+        if hits.is_empty() {
+            ui.weak("No matching messages");
+            return true;
+        }
+
+        let highlight = ui.visuals().selection.bg_fill;
+        let text_color = ui.visuals().text_color();
+        let mut jump = None;
+        ui.weak(format!("{} matching messages", hits.len()));
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for hit in &hits {
+                ui.horizontal_wrapped(|ui| {
+                    ui.strong(&hit.session_title);
+                    let author = match hit.model {
+                        Some(ref model) => format!("{} ({})", hit.role, model),
+                        None => hit.role.clone(),
+                    };
+                    ui.weak(format!(
+                        "{}, {}",
+                        author,
+                        search::format_date(hit.timestamp)
+                    ));
+                });
+
+                let mut job = egui::text::LayoutJob::default();
+                let mut last = 0;
+                for range in &hit.highlights {
+                    let plain = egui::TextFormat {
+                        color: text_color,
+                        ..Default::default()
+                    };
+                    job.append(&hit.snippet[last..range.start], 0.0, plain);
+                    let marked = egui::TextFormat {
+                        color: text_color,
+                        background: highlight,
+                        ..Default::default()
+                    };
+                    job.append(&hit.snippet[range.clone()], 0.0, marked);
+                    last = range.end;
+                }
+                job.append(
+                    &hit.snippet[last..],
+                    0.0,
+                    egui::TextFormat {
+                        color: text_color,
+                        ..Default::default()
+                    },
+                );
+                job.wrap.max_width = ui.available_width();
+
+                if ui
+                    .add(egui::Label::new(job).sense(egui::Sense::click()))
+                    .on_hover_text("Open this message")
+                    .clicked()
+                {
+                    jump = Some((hit.session_id.clone(), hit.node));
+                }
+                ui.separator();
+            }
         });
+
+        if let Some((session_id, node)) = jump {
+            self.jump_to_message(&session_id, node);
+        }
+        true
     }
 
-    pub fn render_history_window(&mut self, ctx: &egui::Context) {
-        if self.show_history_window {
-            let should_close = ctx.show_viewport_immediate(
-                egui::ViewportId::from_hash_of("history_viewport"),
-                egui::ViewportBuilder::default()
-                    .with_title("Chat History")
-                    .with_inner_size([400.0, 600.0]),
-                |ctx, class| {
-                    assert!(
-                        class == egui::ViewportClass::Immediate,
-                        "This egui backend doesn't support multiple viewports"
-                    );
+    /// Form for the placeholders of the template being inserted
+    pub fn render_template_form(&mut self, ctx: &egui::Context) {
+        let Some(ref mut form) = self.template_form else {
+            return;
+        };
 
-                    let mut close_requested = false;
-                    if ctx.input(|i| i.viewport().close_requested()) {
-                        close_requested = true;
+        let mut open = true;
+        let mut insert = false;
+        let mut cancel = false;
+        egui::Window::new(format!("Template: {}", form.template.name))
+            .id(egui::Id::new("template_form"))
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                for name in form.template.variables() {
+                    ui.label(&name);
+                    if let Some(value) = form.values.get_mut(&name) {
+                        ui.add(
+                            egui::TextEdit::multiline(value)
+                                .desired_rows(2)
+                                .desired_width(f32::INFINITY),
+                        );
+                    }
+                }
+                ui.horizontal(|ui| {
+                    insert = ui.button("Insert").clicked();
+                    cancel = ui.button("Cancel").clicked();
+                });
+            });
+
+        if insert {
+            let text = form.template.fill(&form.values);
+            self.template_form = None;
+            self.insert_into_prompt(&text);
+        } else if cancel || !open {
+            self.template_form = None;
+        }
+    }
+
+    /// Create, edit, import and export prompt templates
+    pub fn render_templates_window(&mut self, ctx: &egui::Context) {
+        if !self.show_templates_window {
+            return;
+        }
+
+        let mut open = true;
+        let mut changed = false;
+        let mut to_use = None;
+        let mut delete = false;
+        egui::Window::new("Prompt Templates")
+            .open(&mut open)
+            .default_width(560.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("New").clicked() {
+                        self.template_library.templates.push(PromptTemplate {
+                            name: format!("Template {}", self.template_library.templates.len() + 1),
+                            ..PromptTemplate::default()
+                        });
+                        self.selected_template = Some(self.template_library.templates.len() - 1);
+                        changed = true;
                     }
+                    if ui.button("Import...").clicked() {
+                        match self.template_library.import_dialog() {
+                            Ok(0) => {}
+                            Ok(_) => changed = true,
+                            Err(e) => self.set_error(e),
+                        }
+                    }
+                    if ui
+                        .button("Export...")
+                        .on_hover_text("Save as .json or .toml to share the templates")
+                        .clicked()
+                    {
+                        if let Err(e) = self.template_library.export_dialog() {
+                            self.set_error(e);
+                        }
+                    }
+                });
+                ui.separator();
 
-                    egui::CentralPanel::default().show(ctx, |ui| {
-                        ui.heading("Chat History");
-                        if ui.button("Clear History").clicked() {
-                            self.chat_history.clear();
+                ui.horizontal_top(|ui| {
+                    ui.vertical(|ui| {
+                        ui.set_width(160.0);
+                        for (i, template) in self.template_library.templates.iter().enumerate() {
+                            if ui
+                                .selectable_label(self.selected_template == Some(i), &template.name)
+                                .clicked()
+                            {
+                                self.selected_template = Some(i);
+                            }
+                        }
+                    });
+                    ui.separator();
+
+                    let Some(i) = self
+                        .selected_template
+                        .filter(|&i| i < self.template_library.templates.len())
+                    else {
+                        ui.weak("Select a template to edit it");
+                        return;
+                    };
+                    ui.vertical(|ui| {
+                        let template = &mut self.template_library.templates[i];
+                        ui.label("Name:");
+                        changed |= ui.text_edit_singleline(&mut template.name).lost_focus();
+                        ui.label("Description:");
+                        changed |= ui
+                            .text_edit_singleline(&mut template.description)
+                            .lost_focus();
+                        ui.label("Prompt (use {{name}} for placeholders):");
+                        changed |= ui
+                            .add(
+                                egui::TextEdit::multiline(&mut template.body)
+                                    .desired_rows(8)
+                                    .desired_width(f32::INFINITY),
+                            )
+                            .lost_focus();
+                        let variables = template.variables();
+                        if !variables.is_empty() {
+                            ui.weak(format!("Placeholders: {}", variables.join(", ")));
+                        }
+                        ui.horizontal(|ui| {
+                            if ui.button("Use").clicked() {
+                                to_use = Some(template.clone());
+                            }
+                            delete = ui.button("Delete").clicked();
+                        });
+                    });
+                });
+            });
+
+        if let Some(i) = self.selected_template.filter(|_| delete) {
+            self.template_library.templates.remove(i);
+            self.selected_template = None;
+            changed = true;
+        }
+        if changed {
+            self.save_template_library();
+        }
+        if let Some(template) = to_use {
+            self.use_template(template);
+        }
+        if !open {
+            self.show_templates_window = false;
+        }
+    }
+
+    pub fn render_compare_window(&mut self, ctx: &egui::Context) {
+        if !self.show_compare_window {
+            return;
+        }
+
+        let mut open = true;
+        let mut start = false;
+        let mut promote = None;
+        egui::Window::new("Compare Models")
+            .open(&mut open)
+            .default_width(900.0)
+            .show(ctx, |ui| {
+                ui.horizontal_wrapped(|ui| {
+                    ui.label("Models:");
+                    let full = self.compare_models.len() >= MAX_COMPARE_MODELS;
+                    for model in &self.available_models {
+                        let mut checked = self.compare_models.contains(&model.name);
+                        let response = ui
+                            .add_enabled(
+                                checked || !full,
+                                egui::Checkbox::new(&mut checked, &model.display_name),
+                            )
+                            .on_hover_text(&model.name);
+                        if response.changed() {
+                            if checked {
+                                self.compare_models.push(model.name.clone());
+                            } else {
+                                self.compare_models.retain(|m| m != &model.name);
+                            }
+                        }
+                    }
+                });
+
+                let running = self.comparison.as_ref().is_some_and(Comparison::is_running);
+                ui.horizontal(|ui| {
+                    let ready = !running
+                        && self.compare_models.len() >= MIN_COMPARE_MODELS
+                        && !self.prompt.trim().is_empty();
+                    if ui
+                        .add_enabled(ready, egui::Button::new("Compare"))
+                        .on_disabled_hover_text(format!(
+                            "Check {} to {} models and write a prompt",
+                            MIN_COMPARE_MODELS, MAX_COMPARE_MODELS
+                        ))
+                        .clicked()
+                    {
+                        start = true;
+                    }
+                    ui.weak("Uses the prompt, the conversation and the attached image");
+                });
+
+                let Some(ref comparison) = self.comparison else {
+                    return;
+                };
+                ui.separator();
+                ui.label(egui::RichText::new(&comparison.prompt).italics());
+                ui.add_space(3.0);
+
+                ui.columns(comparison.columns.len(), |columns| {
+                    for (i, (ui, column)) in columns.iter_mut().zip(&comparison.columns).enumerate()
+                    {
+                        let name = self
+                            .available_models
+                            .iter()
+                            .find(|m| m.name == column.model)
+                            .map_or(column.model.as_str(), |m| m.display_name.as_str());
+                        ui.horizontal(|ui| {
+                            ui.strong(name);
+                            let usable = comparison.promoted.is_none()
+                                && !self.is_loading
+                                && matches!(column.result, Some(Ok(ref r)) if r.blocked.is_none());
+                            if comparison.promoted == Some(i) {
+                                ui.weak("✔ added to the conversation");
+                            } else if ui
+                                .add_enabled(usable, egui::Button::new("Use this"))
+                                .on_hover_text("Add this answer to the conversation")
+                                .clicked()
+                            {
+                                promote = Some(i);
+                            }
+                        });
+
+                        let mut stats = format!("{:.1} s", column.elapsed().as_secs_f32());
+                        if let Some(usage) = column.usage() {
+                            stats.push_str(&format!(
+                                " · {} in / {} out",
+                                usage.prompt, usage.output
+                            ));
+                            if usage.thoughts > 0 {
+                                stats.push_str(&format!(" / {} thinking", usage.thoughts));
+                            }
                         }
+                        ui.horizontal(|ui| {
+                            if column.is_running() {
+                                ui.add(Spinner::default());
+                            }
+                            ui.weak(stats);
+                        });
                         ui.separator();
 
-                        egui::ScrollArea::vertical().show(ui, |ui| {
-                            for msg in &self.chat_history {
-                                ui.horizontal_wrapped(|ui| {
-                                    ui.label(
-                                        egui::RichText::new(format!("{}:", msg.role)).strong(),
+                        egui::ScrollArea::vertical()
+                            .id_salt(("compare", i))
+                            .max_height(480.0)
+                            .show(ui, |ui| match column.result {
+                                Some(Ok(ref response)) => {
+                                    CommonMarkViewer::new().show(
+                                        ui,
+                                        &mut self.commonmark_cache,
+                                        &response.text,
+                                    );
+                                }
+                                Some(Err(ref e)) => {
+                                    ui.colored_label(egui::Color32::RED, e);
+                                }
+                                None => {}
+                            });
+                    }
+                });
+            });
+
+        if start {
+            self.start_comparison();
+        }
+        if let Some(i) = promote {
+            self.promote_comparison(i);
+        }
+        if !open {
+            self.show_compare_window = false;
+        }
+    }
+
+    pub fn render_diagnostics_window(&mut self, ctx: &egui::Context) {
+        if !self.show_diagnostics_window {
+            return;
+        }
+
+        if let Some(handle) = self.resend_thread.take() {
+            if handle.is_finished() {
+                match handle.join() {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => self.set_error(format!("Resend failed: {}", e)),
+                    Err(_) => self.set_error("Resend panicked".to_string()),
+                }
+            } else {
+                self.resend_thread = Some(handle);
+            }
+        }
+
+        let mut open = true;
+        let mut resend = None;
+        let mut copy = None;
+        let mut clear = false;
+        // Drawn under the log's lock so the request and response bodies aren't copied every frame
+        diagnostics::with_records(|records| {
+            // Requests in flight update their record from another thread
+            if records.iter().any(|r| r.total.is_none()) || self.resend_thread.is_some() {
+                ctx.request_repaint_after(Duration::from_millis(250));
+            }
+            egui::Window::new("Request Log")
+                .open(&mut open)
+                .default_width(820.0)
+                .default_height(560.0)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        if ui.button("Clear").clicked() {
+                            clear = true;
+                        }
+                        ui.weak(format!(
+                            "{} recent requests; keys are redacted",
+                            records.len()
+                        ));
+                    });
+                    ui.separator();
+
+                    ui.horizontal_top(|ui| {
+                        ui.vertical(|ui| {
+                            ui.set_width(240.0);
+                            egui::ScrollArea::vertical()
+                                .id_salt("request_list")
+                                .show(ui, |ui| {
+                                    for record in records.iter().rev() {
+                                        if ui
+                                            .selectable_label(
+                                                self.selected_request == Some(record.id),
+                                                request_summary(record),
+                                            )
+                                            .clicked()
+                                        {
+                                            self.selected_request = Some(record.id);
+                                        }
+                                    }
+                                });
+                        });
+                        ui.separator();
+
+                        let Some(record) =
+                            records.iter().find(|r| Some(r.id) == self.selected_request)
+                        else {
+                            ui.weak("Select a request to see its details");
+                            return;
+                        };
+                        ui.vertical(|ui| {
+                            egui::Grid::new("request_details")
+                                .num_columns(2)
+                                .show(ui, |ui| {
+                                    ui.label("Endpoint:");
+                                    ui.label(format!("{} {}", record.method(), record.endpoint()));
+                                    ui.end_row();
+                                    ui.label("Model:");
+                                    ui.label(record.model.as_deref().unwrap_or("-"));
+                                    ui.end_row();
+                                    ui.label("Sent:");
+                                    let age = storage::now_secs().saturating_sub(record.created_at);
+                                    ui.label(if age < 60 {
+                                        format!("{} s ago", age)
+                                    } else {
+                                        format!("{} min ago", age / 60)
+                                    });
+                                    ui.end_row();
+                                    ui.label("Status:");
+                                    ui.label(match (record.status, &record.error) {
+                                        (_, Some(e)) => e.clone(),
+                                        (Some(status), None) => status.to_string(),
+                                        (None, None) => "waiting...".to_string(),
+                                    });
+                                    ui.end_row();
+                                    ui.label("First byte:");
+                                    ui.label(format_duration(record.time_to_first_byte));
+                                    ui.end_row();
+                                    ui.label("Total:");
+                                    ui.label(format_duration(record.total));
+                                    ui.end_row();
+                                    ui.label("Request body:");
+                                    ui.label(diagnostics::size_label(record.body_bytes));
+                                    ui.end_row();
+                                    if !record.image_bytes.is_empty() {
+                                        ui.label("Images:");
+                                        let sizes: Vec<String> = record
+                                            .image_bytes
+                                            .iter()
+                                            .map(|&bytes| diagnostics::size_label(bytes))
+                                            .collect();
+                                        ui.label(sizes.join(", "));
+                                        ui.end_row();
+                                    }
+                                    if let Some(original) = record.resend_of {
+                                        ui.label("Resend of:");
+                                        ui.label(format!("#{}", original));
+                                        ui.end_row();
+                                    }
+                                });
+
+                            egui::CollapsingHeader::new("Headers")
+                                .id_salt(("request_headers", record.id))
+                                .show(ui, |ui| {
+                                    for (name, value) in record.headers() {
+                                        ui.monospace(format!("{}: {}", name, value));
+                                    }
+                                });
+
+                            ui.horizontal(|ui| {
+                                if ui
+                                    .add_enabled(
+                                        self.resend_thread.is_none() && record.can_resend(),
+                                        egui::Button::new("Resend"),
+                                    )
+                                    .on_hover_text(
+                                        "Send the same request again; the result is logged",
+                                    )
+                                    .on_disabled_hover_text(if record.can_resend() {
+                                        "A resend is already running"
+                                    } else {
+                                        "The request body was dropped to keep the log small"
+                                    })
+                                    .clicked()
+                                {
+                                    resend = Some(record.clone());
+                                }
+                                if ui.button("Copy as curl").clicked() {
+                                    copy = Some(record.curl_command());
+                                }
+                                if ui.button("Copy response").clicked() {
+                                    copy = Some(record.response.clone());
+                                }
+                            });
+                            ui.separator();
+
+                            ui.label("Response:");
+                            egui::ScrollArea::vertical()
+                                .id_salt(("request_response", record.id))
+                                .show(ui, |ui| {
+                                    ui.add(
+                                        egui::TextEdit::multiline(&mut record.response.as_str())
+                                            .code_editor()
+                                            .desired_width(f32::INFINITY),
                                     );
-                                    ui.label(&msg.content);
                                 });
-                                ui.separator();
+                        });
+                    });
+                });
+        });
+
+        if clear {
+            diagnostics::clear();
+            self.selected_request = None;
+        }
+        if let Some(record) = resend {
+            self.resend_thread = Some(diagnostics::spawn_resend(&record));
+        }
+        if let Some(text) = copy {
+            if let Ok(mut clipboard) = ClipboardContext::new() {
+                let _ = clipboard.set_contents(text);
+            }
+        }
+        if !open {
+            self.show_diagnostics_window = false;
+        }
+    }
+
+    pub fn render_providers_window(&mut self, ctx: &egui::Context) {
+        if !self.show_providers_window {
+            return;
+        }
+
+        let mut open = true;
+        let mut remove = None;
+        let mut rotate = None;
+        let mut forget = None;
+        let mut unlock = false;
+        let mut save = false;
+        egui::Window::new("Providers")
+            .open(&mut open)
+            .default_width(520.0)
+            .show(ctx, |ui| {
+                ui.weak(
+                    "Models of providers other than Gemini appear as id/model, e.g. ollama/llama3.2",
+                );
+                ui.separator();
+
+                for (i, provider) in self.provider_settings.providers.iter_mut().enumerate() {
+                    let is_gemini = provider.kind == ProviderKind::Gemini;
+                    ui.horizontal(|ui| {
+                        ui.add_enabled(!is_gemini, egui::Checkbox::new(&mut provider.enabled, ""))
+                            .on_hover_text("Enabled");
+                        ui.add(egui::TextEdit::singleline(&mut provider.name).desired_width(140.0));
+                        if is_gemini {
+                            ui.weak("Gemini API");
+                        } else {
+                            ui.weak("OpenAI-compatible");
+                            if ui.small_button("Remove").clicked() {
+                                remove = Some(i);
                             }
+                        }
+                    });
+                    egui::Grid::new(("provider", i))
+                        .num_columns(2)
+                        .show(ui, |ui| {
+                            ui.label("Id:");
+                            ui.add_enabled(
+                                !is_gemini,
+                                egui::TextEdit::singleline(&mut provider.id).desired_width(140.0),
+                            );
+                            ui.end_row();
+                            ui.label("Base URL:");
+                            ui.add(
+                                egui::TextEdit::singleline(&mut provider.base_url)
+                                    .desired_width(320.0),
+                            );
+                            ui.end_row();
+                            ui.label("API key:");
+                            let hint = if is_gemini {
+                                "empty uses GEMINI_API_KEY"
+                            } else {
+                                "optional"
+                            };
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::TextEdit::singleline(&mut provider.api_key)
+                                        .password(true)
+                                        .hint_text(hint)
+                                        .desired_width(220.0),
+                                );
+                                if ui
+                                    .small_button("Rotate...")
+                                    .on_hover_text("Check a new key, then replace this one")
+                                    .clicked()
+                                {
+                                    rotate = Some(i);
+                                }
+                                if ui
+                                    .small_button("Forget")
+                                    .on_hover_text("Remove the stored key")
+                                    .clicked()
+                                {
+                                    forget = Some(i);
+                                }
+                            });
+                            ui.end_row();
+                            ui.label("Stored in:");
+                            egui::ComboBox::from_id_salt(("key_storage", i))
+                                .selected_text(provider.key_storage.label())
+                                .show_ui(ui, |ui| {
+                                    for storage in KeyStorage::ALL {
+                                        ui.selectable_value(
+                                            &mut provider.key_storage,
+                                            storage,
+                                            storage.label(),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
                         });
+                    ui.separator();
+                }
+
+                let uses_file = self
+                    .provider_settings
+                    .providers
+                    .iter()
+                    .any(|p| p.key_storage == KeyStorage::EncryptedFile);
+                if uses_file {
+                    ui.horizontal(|ui| {
+                        ui.label("Passphrase:");
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.key_passphrase)
+                                .password(true)
+                                .hint_text("for the encrypted key file")
+                                .desired_width(200.0),
+                        );
+                        if ui
+                            .add_enabled(!self.key_passphrase.is_empty(), egui::Button::new("Unlock"))
+                            .clicked()
+                        {
+                            unlock = true;
+                        }
                     });
+                    ui.separator();
+                }
 
-                    close_requested
-                },
-            );
+                ui.horizontal(|ui| {
+                    if ui.button("Add provider").clicked() {
+                        let n = self.provider_settings.providers.len();
+                        self.provider_settings
+                            .providers
+                            .push(ProviderConfig::openai_compatible(
+                                &format!("local{}", n),
+                                "Local server",
+                                "http://localhost:8000/v1",
+                            ));
+                    }
+                    if ui.button("Save and refresh models").clicked() {
+                        save = true;
+                    }
+                });
+            });
 
-            if should_close {
-                self.show_history_window = false;
+        if let Some(i) = remove {
+            self.provider_settings.providers.remove(i);
+        }
+        if let Some(i) = rotate {
+            self.key_rotation = Some(KeyRotation {
+                provider: i,
+                new_key: String::new(),
+                check: None,
+                result: None,
+            });
+        }
+        if let Some(i) = forget {
+            match self.provider_settings.forget_key(i) {
+                Ok(()) => self.provider_settings.apply(),
+                Err(e) => self.set_error(format!("Failed to remove the key: {}", e)),
+            }
+        }
+        if unlock {
+            let errors = self
+                .provider_settings
+                .load_keys(Some(self.key_passphrase.as_str()));
+            if errors.is_empty() {
+                self.clear_error();
+                self.provider_settings.apply();
+                self.refresh_models();
+            } else {
+                self.set_error(errors.join("; "));
+            }
+        }
+        if save {
+            match self.save_provider_settings() {
+                Ok(()) => self.clear_error(),
+                Err(e) => self.set_error(format!("Failed to save providers: {}", e)),
+            }
+        }
+        if !open {
+            self.show_providers_window = false;
+            self.key_rotation = None;
+        }
+        self.render_key_rotation(ctx);
+    }
+
+    fn render_key_rotation(&mut self, ctx: &egui::Context) {
+        let Some(rotation) = self.key_rotation.as_mut() else {
+            return;
+        };
+        let Some(provider) = self.provider_settings.providers.get(rotation.provider) else {
+            self.key_rotation = None;
+            return;
+        };
+
+        if let Some(handle) = rotation.check.take() {
+            if handle.is_finished() {
+                rotation.result = Some(
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err("Key check panicked".to_string())),
+                );
+            } else {
+                rotation.check = Some(handle);
+                ctx.request_repaint_after(Duration::from_millis(100));
+            }
+        }
+
+        let mut open = true;
+        let mut check = false;
+        egui::Window::new(format!("Rotate {} key", provider.name))
+            .open(&mut open)
+            .collapsible(false)
+            .default_width(360.0)
+            .show(ctx, |ui| {
+                ui.label("The new key is used to list models first and only saved if that works.");
+                ui.add(
+                    egui::TextEdit::singleline(&mut rotation.new_key)
+                        .password(true)
+                        .hint_text("new key")
+                        .desired_width(f32::INFINITY),
+                );
+                ui.horizontal(|ui| {
+                    let checking = rotation.check.is_some();
+                    if ui
+                        .add_enabled(
+                            !checking && !rotation.new_key.trim().is_empty(),
+                            egui::Button::new("Check and save"),
+                        )
+                        .clicked()
+                    {
+                        check = true;
+                    }
+                    if checking {
+                        ui.spinner();
+                    }
+                });
+                match &rotation.result {
+                    Some(Ok(count)) => {
+                        ui.label(format!("✔ Key works ({} models) and was saved", count));
+                    }
+                    Some(Err(e)) => {
+                        ui.colored_label(egui::Color32::RED, e);
+                    }
+                    None => {}
+                }
+            });
+
+        if check {
+            let mut config = provider.clone();
+            config.api_key = rotation.new_key.trim().to_string();
+            rotation.result = None;
+            rotation.check = Some(providers::spawn_check_key(config));
+        }
+        let verified = matches!(rotation.result, Some(Ok(_))) && !rotation.new_key.is_empty();
+        if verified {
+            let index = rotation.provider;
+            let new_key = std::mem::take(&mut rotation.new_key).trim().to_string();
+            let passphrase = Some(self.key_passphrase.as_str()).filter(|p| !p.is_empty());
+            match self.provider_settings.save_key(index, &new_key, passphrase) {
+                Ok(()) => {
+                    self.provider_settings.apply();
+                    self.refresh_models();
+                }
+                Err(e) => {
+                    rotation.result = Some(Err(format!("The key works but was not saved: {}", e)))
+                }
             }
         }
+        if !open {
+            self.key_rotation = None;
+        }
+    }
+
+    pub fn render_documents_window(&mut self, ctx: &egui::Context) {
+        if !self.show_documents_window {
+            return;
+        }
+
+        let mut open = true;
+        egui::Window::new("Documents")
+            .open(&mut open)
+            .default_width(420.0)
+            .show(ctx, |ui| {
+                // A cache holds a snapshot, so edits would silently not apply
+                let locked = self.context_cache.is_some() || self.cache_thread.is_some();
+
+                ui.strong("System instruction");
+                let instruction = ui.add_enabled(
+                    !locked,
+                    egui::TextEdit::multiline(&mut self.shared_context.system_instruction)
+                        .desired_rows(4)
+                        .desired_width(f32::INFINITY),
+                );
+                if instruction.lost_focus() {
+                    self.save_session();
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.strong("Attachments");
+                    if ui
+                        .add_enabled(!locked, egui::Button::new("Add files..."))
+                        .clicked()
+                    {
+                        match documents::pick_attachments() {
+                            Ok(attachments) => self.shared_context.attachments.extend(attachments),
+                            Err(e) => self.set_error(e),
+                        }
+                    }
+                });
+                if self.shared_context.attachments.is_empty() {
+                    ui.weak("No documents attached");
+                }
+                let mut to_remove = None;
+                for (i, attachment) in self.shared_context.attachments.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(&attachment.name);
+                        ui.weak(attachment.size_label());
+                        if ui
+                            .add_enabled(!locked, egui::Button::new("✖").small())
+                            .clicked()
+                        {
+                            to_remove = Some(i);
+                        }
+                    });
+                }
+                if let Some(i) = to_remove {
+                    self.shared_context.attachments.remove(i);
+                }
+
+                ui.separator();
+                ui.strong("Context cache");
+                if self.cache_thread.is_some() {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("Creating cache...");
+                    });
+                } else if let Some(ref cache) = self.context_cache {
+                    ui.label(format!("{} for {}", cache.name, cache.model));
+                    if let Some(tokens) = cache.token_count {
+                        ui.weak(format!("{} cached tokens", tokens));
+                    }
+                    ui.label(format!("Expires in {}", cache.ttl_label()));
+                    if cache.model != self.ai_model {
+                        ui.weak(format!(
+                            "Only used with {}; other models get the documents inline",
+                            cache.model
+                        ));
+                    }
+                    if ui.button("Delete cache").clicked() {
+                        self.delete_context_cache();
+                    }
+                    ctx.request_repaint_after(Duration::from_secs(1));
+                } else {
+                    ui.horizontal(|ui| {
+                        ui.label("Keep for");
+                        ui.add(egui::DragValue::new(&mut self.cache_ttl_minutes).range(1..=1440));
+                        ui.label("minutes");
+                    });
+                    if ui
+                        .add_enabled(
+                            !self.shared_context.is_empty() && self.uses_gemini(),
+                            egui::Button::new(format!("Create cache for {}", self.ai_model)),
+                        )
+                        .on_hover_text(
+                            "Stores the instruction and documents on the server so later \
+prompts don't resend them",
+                        )
+                        .on_disabled_hover_text("Only Gemini models support context caching")
+                        .clicked()
+                    {
+                        self.create_context_cache();
+                    }
+                    ui.weak("Caches need a minimum number of tokens and disable search tools");
+                }
+            });
+
+        if !open {
+            self.show_documents_window = false;
+        }
     }
 
     pub fn render_loading_indicator(&mut self, ctx: &egui::Context) {
@@ -403,12 +3323,17 @@ impl UIState {
 
             if let Some(handle) = self.client_thread.take() {
                 if handle.is_finished() {
-                    if let Ok(res) = handle.join() {
-                        self.is_loading = false;
-                        self.first_frame = true;
-                        self.update_llm_response(res.unwrap());
-                        ctx.request_repaint();
+                    self.is_loading = false;
+                    self.first_frame = true;
+                    match handle.join() {
+                        Ok(Ok(responses)) => {
+                            self.sent_prompt = None;
+                            self.handle_responses(responses);
+                        }
+                        Ok(Err(e)) => self.request_failed(e),
+                        Err(_) => self.request_failed("Request panicked".to_string()),
                     }
+                    ctx.request_repaint();
                 } else {
                     self.client_thread = Some(handle);
                 }
@@ -417,6 +3342,102 @@ impl UIState {
     }
 }
 
+/// One line per request in the request log, e.g. "#12 200 gemini-2.5-flash 1.3 s"
+fn request_summary(record: &RequestRecord) -> String {
+    let status = match (record.status, &record.error) {
+        (_, Some(_)) => "failed".to_string(),
+        (Some(status), None) => status.to_string(),
+        (None, None) => "...".to_string(),
+    };
+    let target = record.model.clone().unwrap_or_else(|| {
+        record
+            .endpoint()
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    });
+    format!(
+        "#{} {} {} {}",
+        record.id,
+        status,
+        target,
+        format_duration(record.total)
+    )
+}
+
+fn format_duration(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) if duration.as_millis() < 1000 => format!("{} ms", duration.as_millis()),
+        Some(duration) => format!("{:.1} s", duration.as_secs_f32()),
+        None => "-".to_string(),
+    }
+}
+
+/// Puts the text cursor of the prompt editor after the last character
+fn move_cursor_to_end(ctx: &egui::Context, editor_id: egui::Id, text: &str) {
+    if let Some(mut state) = egui::TextEdit::load_state(ctx, editor_id) {
+        let end = egui::text::CCursor::new(text.chars().count());
+        state
+            .cursor
+            .set_char_range(Some(egui::text::CCursorRange::one(end)));
+        state.store(ctx, editor_id);
+    }
+}
+
+/// Collapsible "Reasoning" section shown above a model answer
+fn render_thoughts(
+    ui: &mut egui::Ui,
+    cache: &mut CommonMarkCache,
+    thoughts: &str,
+    id_salt: impl std::hash::Hash,
+) {
+    egui::CollapsingHeader::new("Reasoning")
+        .id_salt(("reasoning", id_salt))
+        .default_open(false)
+        .show(ui, |ui| {
+            CommonMarkViewer::new().show(ui, cache, thoughts);
+        });
+}
+
+/// Shows generated images inline with Save and Copy actions underneath each one
+fn render_generated_images(
+    ui: &mut egui::Ui,
+    textures: &mut HashMap<u64, TextureHandle>,
+    images: &[GeneratedImage],
+) -> Result<(), String> {
+    let mut result = Ok(());
+
+    for generated in images {
+        let texture = textures.entry(generated.id).or_insert_with(|| {
+            ui.ctx().load_texture(
+                format!("generated_image_{}", generated.id),
+                generated.image.clone(),
+                egui::TextureOptions::default(),
+            )
+        });
+
+        ui.add(
+            egui::Image::from_texture(&*texture)
+                .max_width(ui.available_width())
+                .maintain_aspect_ratio(true),
+        );
+        ui.horizontal(|ui| {
+            if ui.button("Save…").clicked() {
+                result = generated.save_to_file();
+            }
+            if ui.button("Copy").clicked() {
+                result = generated.copy_to_clipboard();
+            }
+        });
+    }
+
+    result
+}
+
 pub fn create_viewport_with_icon(
     title: &str,
     icon_bytes: &[u8],